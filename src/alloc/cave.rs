@@ -0,0 +1,183 @@
+use super::{ExecutableAllocator, ExecutableBlock};
+use crate::error::{Error, Result};
+use crate::{arch, util};
+use lazy_static::lazy_static;
+use std::collections::BTreeMap;
+use std::ops::Range;
+use std::sync::Mutex;
+use std::{cmp, mem, slice};
+
+/// The granularity of caves; pointer sized, since relays contain slots which
+/// are written atomically.
+const CHUNK_SIZE: usize = mem::size_of::<usize>();
+
+lazy_static! {
+  /// The original contents of each allocated cave, by address.
+  ///
+  /// The caves are shared by every allocator, so a cave is never handed out
+  /// twice within the process.
+  static ref CAVES: Mutex<BTreeMap<usize, Vec<u8>>> = Mutex::new(BTreeMap::new());
+}
+
+/// An allocator of code caves, i.e padding between the functions of the
+/// origin's executable region (e.g `int3` instructions).
+///
+/// This requires no additional memory to be mapped, but caves are scarce and
+/// small, so it's mostly suitable for relays. The padding is verified before
+/// use, and restored once a cave is released.
+///
+/// Only padding which is never executed (i.e traps) is used, since alignment
+/// padding within a function (e.g `nop` instructions) may be executed. On
+/// Linux, padding within the bounds of an exported function's symbol is never
+/// used either.
+///
+/// The first chunk of each run of padding is never used, in case it's part of
+/// a preceding instruction. Runs of zeroes are never used either, since they
+/// may be data embedded in the code. Allocated caves are tracked for the whole
+/// process, so separate allocators never hand out the same cave.
+pub struct CodeCaveAllocator(());
+
+impl CodeCaveAllocator {
+  /// Creates a new code cave allocator.
+  pub fn new() -> Self {
+    CodeCaveAllocator(())
+  }
+
+  /// Returns the usable runs of padding within a region, which may contain
+  /// allocated caves.
+  fn padding(region: &[u8]) -> Vec<Range<usize>> {
+    let mut runs: Vec<Range<usize>> = Vec::new();
+
+    for (index, chunk) in region.chunks_exact(CHUNK_SIZE).enumerate() {
+      let address = region.as_ptr() as usize + index * CHUNK_SIZE;
+      if !arch::is_cave_padding(chunk) {
+        continue;
+      }
+
+      match runs.last_mut() {
+        Some(run) if run.end == address => run.end += CHUNK_SIZE,
+        _ => runs.push(address..address + CHUNK_SIZE),
+      }
+    }
+
+    runs
+      .into_iter()
+      .map(|run| run.start + CHUNK_SIZE..run.end)
+      .filter(|run| run.start < run.end)
+      .collect()
+  }
+
+  /// Returns whether a run of padding is within a symbol, rather than between
+  /// symbols.
+  #[cfg(target_os = "linux")]
+  fn is_within_symbol(run: &Range<usize>) -> bool {
+    unsafe {
+      arch::bounds::is_within_symbol(run.start as *const ())
+        || arch::bounds::is_within_symbol((run.end - 1) as *const ())
+    }
+  }
+
+  /// Returns whether a run of padding is within a symbol, rather than between
+  /// symbols.
+  #[cfg(not(target_os = "linux"))]
+  fn is_within_symbol(_run: &Range<usize>) -> bool {
+    false
+  }
+
+  /// Splits a run of padding around the allocated caves.
+  fn available(caves: &BTreeMap<usize, Vec<u8>>, run: &Range<usize>) -> Vec<Range<usize>> {
+    let mut start = run.start;
+    let mut available = Vec::new();
+
+    // A cave preceding the run may still extend into it
+    let first = caves
+      .range(..run.start)
+      .next_back()
+      .map_or(run.start, |(&cave, _)| cave);
+
+    for (&cave, code) in caves.range(first..run.end) {
+      if cave > start {
+        available.push(start..cave);
+      }
+      start = cmp::max(start, cave + code.len());
+    }
+
+    if start < run.end {
+      available.push(start..run.end);
+    }
+    available
+  }
+}
+
+impl Default for CodeCaveAllocator {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+unsafe impl ExecutableAllocator for CodeCaveAllocator {
+  fn allocate_near(
+    &self,
+    origin: *const (),
+    size: usize,
+    max_distance: usize,
+  ) -> Result<ExecutableBlock> {
+    let region = region::query(origin as *const u8)?;
+    if !region
+      .protection()
+      .contains(region::Protection::READ_EXECUTE)
+    {
+      Err(Error::OutOfMemory)?;
+    }
+
+    let size = (size + CHUNK_SIZE - 1) & !(CHUNK_SIZE - 1);
+    let distance = |address: usize| {
+      (address as isize)
+        .wrapping_sub(origin as isize)
+        .unsigned_abs()
+    };
+
+    // The region is scanned without locking, since allocated caves are only
+    // excluded once locked
+    let code = unsafe { slice::from_raw_parts(region.as_ptr::<u8>(), region.len()) };
+    let runs = Self::padding(code)
+      .into_iter()
+      .filter(|run| run.end - run.start >= size && !Self::is_within_symbol(run))
+      .collect::<Vec<_>>();
+
+    // Use the closest run of padding able to contain the allocation
+    let mut caves = util::lock(&CAVES);
+    let address = runs
+      .iter()
+      .flat_map(|run| Self::available(&caves, run))
+      .filter(|run| run.end - run.start >= size)
+      .map(|run| run.start)
+      .filter(|&address| {
+        distance(address) < max_distance && distance(address + size) < max_distance
+      })
+      .min_by_key(|&address| distance(address))
+      .ok_or(Error::OutOfMemory)?;
+
+    let original = unsafe { slice::from_raw_parts(address as *const u8, size) };
+    caves.insert(address, original.to_vec());
+    Ok(ExecutableBlock::new(address as *const u8, size))
+  }
+
+  unsafe fn release(&self, block: ExecutableBlock) {
+    let mut caves = util::lock(&CAVES);
+    let original = caves
+      .get(&(block.as_ptr() as usize))
+      .expect("releasing unknown cave");
+
+    // The cave is only reused once its padding has been restored
+    if let Err(error) = util::write_code(block.as_ptr(), original) {
+      log::debug!(
+        "failed to restore code cave at {:?}: {}",
+        block.as_ptr(),
+        error
+      );
+    }
+
+    caves.remove(&(block.as_ptr() as usize));
+  }
+}
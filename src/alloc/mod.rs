@@ -1,60 +1,212 @@
-use crate::error::Result;
-use std::ops::{Deref, DerefMut};
+use crate::error::{Error, Result};
+use crate::util;
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::slice;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 
+pub use self::cave::CodeCaveAllocator;
+
+mod cave;
+mod pool;
 mod proximity;
 mod search;
 
-/// A thread-safe memory pool for allocating chunks close to addresses.
-pub struct ThreadAllocator(Arc<Mutex<proximity::ProximityAllocator>>);
-
-// TODO: Decrease use of mutexes
-impl ThreadAllocator {
-  /// Creates a new proximity memory allocator.
-  pub fn new(max_distance: usize) -> Self {
-    ThreadAllocator(Arc::new(Mutex::new(proximity::ProximityAllocator {
-      max_distance,
-      pools: Vec::new(),
-    })))
+/// An allocator of executable memory, used for trampolines and relays.
+///
+/// The default implementation is the
+/// [ProximityAllocator](./struct.ProximityAllocator.html). A custom allocator
+/// can be installed globally using [set_allocator](./fn.set_allocator.html), or
+/// for a specific detour (e.g [RawDetour::with_allocator](./struct.RawDetour.
+/// html#method.with_allocator)).
+///
+/// # Safety
+///
+/// The allocated memory must be executable, and remain valid until released.
+pub unsafe trait ExecutableAllocator: Send + Sync {
+  /// Allocates at least `size` bytes of executable memory, located within
+  /// `max_distance` bytes of `origin`.
+  fn allocate_near(
+    &self,
+    origin: *const (),
+    size: usize,
+    max_distance: usize,
+  ) -> Result<ExecutableBlock>;
+
+  /// Releases a block previously allocated by this allocator.
+  ///
+  /// The block is no longer executed, nor referenced, once released.
+  unsafe fn release(&self, block: ExecutableBlock);
+}
+
+/// A block of executable memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExecutableBlock {
+  address: *const u8,
+  size: usize,
+  alias: Option<*mut u8>,
+}
+
+impl ExecutableBlock {
+  /// Constructs a block of `size` bytes at `address`.
+  ///
+  /// Code is written to the block by temporarily making its pages writable,
+  /// whilst retaining their current protection.
+  pub fn new(address: *const u8, size: usize) -> Self {
+    ExecutableBlock {
+      address,
+      size,
+      alias: None,
+    }
+  }
+
+  /// Constructs a block of `size` bytes at `address`, whose code is written
+  /// through a separate, writable view of the same memory.
+  pub fn with_alias(address: *const u8, size: usize, alias: *mut u8) -> Self {
+    ExecutableBlock {
+      address,
+      size,
+      alias: Some(alias),
+    }
+  }
+
+  /// Returns the executable address of the block.
+  pub fn as_ptr(&self) -> *const u8 {
+    self.address
+  }
+
+  /// Returns the size of the block.
+  pub fn len(&self) -> usize {
+    self.size
   }
 
-  /// Allocates read-, write- & executable memory close to `origin`.
-  pub fn allocate(&self, origin: *const (), size: usize) -> Result<ExecutableMemory> {
-    let mut allocator = self.0.lock().unwrap();
-    allocator
-      .allocate(origin, size)
-      .map(|data| ExecutableMemory {
-        allocator: self.0.clone(),
-        data,
-      })
+  /// Returns the writable view of the block, if any.
+  pub fn alias(&self) -> Option<*mut u8> {
+    self.alias
   }
 }
 
-/// A handle for allocated proximity memory.
+unsafe impl Send for ExecutableBlock {}
+unsafe impl Sync for ExecutableBlock {}
+
+/// The default allocator, packing allocations into pools of memory close to
+/// their origin.
+///
+/// On Linux, each pool is mapped twice; once executable and once writable, so
+/// no memory is ever both writable and executable.
+///
+/// Optionally, if no pool can be mapped close to the origin, code caves in the
+/// origin's executable region are used instead (see
+/// [CodeCaveAllocator](./struct.CodeCaveAllocator.html)).
+pub struct ProximityAllocator {
+  pools: proximity::PoolAllocator,
+  allocations: Mutex<HashMap<usize, proximity::Allocation>>,
+  caves: CodeCaveAllocator,
+  use_caves: AtomicBool,
+}
+
+impl ProximityAllocator {
+  /// Creates a new proximity memory allocator, whose pools are at least
+  /// `granularity` bytes.
+  pub fn new(granularity: usize) -> Self {
+    ProximityAllocator {
+      pools: proximity::PoolAllocator::new(granularity),
+      allocations: Mutex::new(HashMap::new()),
+      caves: CodeCaveAllocator::new(),
+      use_caves: AtomicBool::new(false),
+    }
+  }
+
+  /// Sets the minimum size of subsequently allocated pools.
+  pub fn set_granularity(&self, granularity: usize) {
+    self.pools.set_granularity(granularity);
+  }
+
+  /// Sets whether code caves are used, if no pool can be mapped close to the
+  /// origin.
+  pub fn set_code_caves(&self, enabled: bool) {
+    self.use_caves.store(enabled, Ordering::SeqCst);
+  }
+}
+
+unsafe impl ExecutableAllocator for ProximityAllocator {
+  fn allocate_near(
+    &self,
+    origin: *const (),
+    size: usize,
+    max_distance: usize,
+  ) -> Result<ExecutableBlock> {
+    let allocation = match self.pools.allocate(origin, size, max_distance) {
+      Ok(allocation) => allocation,
+      Err(Error::OutOfMemory) if self.use_caves.load(Ordering::SeqCst) => {
+        return self.caves.allocate_near(origin, size, max_distance);
+      },
+      Err(error) => return Err(error),
+    };
+
+    let block = match allocation.alias() {
+      Some(alias) => ExecutableBlock::with_alias(allocation.as_ptr(), allocation.len(), alias),
+      None => ExecutableBlock::new(allocation.as_ptr(), allocation.len()),
+    };
+
+    util::lock(&self.allocations).insert(block.as_ptr() as usize, allocation);
+    Ok(block)
+  }
+
+  unsafe fn release(&self, block: ExecutableBlock) {
+    let allocation = match util::lock(&self.allocations).remove(&(block.as_ptr() as usize)) {
+      Some(allocation) => allocation,
+      None => return self.caves.release(block),
+    };
+
+    // Release the associated memory map (if unique)
+    self.pools.release(allocation);
+  }
+}
+
+/// A handle for allocated executable memory.
 pub struct ExecutableMemory {
-  allocator: Arc<Mutex<proximity::ProximityAllocator>>,
-  data: proximity::Allocation,
+  allocator: Arc<dyn ExecutableAllocator>,
+  block: ExecutableBlock,
 }
 
 impl ExecutableMemory {
-  pub fn modify(&mut self, write: impl FnOnce(&mut [u8])) -> Result<()> {
-    let _handle = unsafe {
-      region::protect_with_handle(
-        self.data.deref().as_ptr(),
-        self.data.deref().len(),
-        region::Protection::READ_WRITE,
-      )
-    }?;
-
-    write(self.data.deref_mut());
-    Ok(())
+  /// Allocates executable memory close to `origin`.
+  pub fn allocate(
+    allocator: Arc<dyn ExecutableAllocator>,
+    origin: *const (),
+    size: usize,
+    max_distance: usize,
+  ) -> Result<Self> {
+    let block = allocator.allocate_near(origin, size, max_distance)?;
+    assert!(block.len() >= size);
+
+    Ok(ExecutableMemory { allocator, block })
+  }
+
+  /// Writes code at an offset within the allocation.
+  ///
+  /// The memory remains executable, since other allocations in the same pages
+  /// may be executed concurrently.
+  pub fn write(&self, offset: usize, code: &[u8]) -> Result<()> {
+    assert!(offset + code.len() <= self.block.len());
+    unsafe {
+      match self.block.alias() {
+        // Dual mapped memory is written through its writable view
+        Some(alias) => {
+          util::copy_code(alias.add(offset), code);
+          Ok(())
+        },
+        None => util::write_code(self.block.as_ptr().add(offset), code),
+      }
+    }
   }
 }
 
 impl Drop for ExecutableMemory {
   fn drop(&mut self) {
-    // Release the associated memory map (if unique)
-    self.allocator.lock().unwrap().release(&self.data);
+    unsafe { self.allocator.release(self.block) };
   }
 }
 
@@ -62,6 +214,6 @@ impl Deref for ExecutableMemory {
   type Target = [u8];
 
   fn deref(&self) -> &Self::Target {
-    self.data.deref()
+    unsafe { slice::from_raw_parts(self.block.as_ptr(), self.block.len()) }
   }
 }
@@ -0,0 +1,207 @@
+//! A thread-safe pool, allocating chunks of a memory region.
+//!
+//! This replaces the `slice-pool` crate, which is unsuitable for pools shared
+//! by several allocations (e.g a detour's relay and trampoline):
+//!
+//! - Releasing a chunk between two free chunks only merges one of them. Any
+//!   chunk later allocated next to the remaining pair stays marked as free, so
+//!   it's handed out twice, and its release panics ("releasing chunk").
+//! - It does not count the active allocations, which is required to unmap a
+//!   pool once it's empty (its `len` is the size of the memory region).
+use crate::util;
+use std::ops::{Deref, DerefMut};
+use std::slice;
+use std::sync::{Arc, Mutex};
+
+/// A contiguous range of a pool's memory.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Chunk {
+  offset: usize,
+  size: usize,
+  free: bool,
+}
+
+/// The chunks of a pool, ordered by their offset.
+type ChunkChain = Mutex<Vec<Chunk>>;
+
+/// A thread-safe interface for allocating chunks of a memory region.
+pub struct SlicePool {
+  chain: Arc<ChunkChain>,
+  memory: Arc<dyn AsRef<[u8]> + Send + Sync>,
+}
+
+impl SlicePool {
+  /// Constructs a new pool, owning the memory region.
+  pub fn new<S: AsRef<[u8]> + Send + Sync + 'static>(memory: S) -> Self {
+    let size = memory.as_ref().len();
+
+    SlicePool {
+      chain: Arc::new(Mutex::new(vec![Chunk {
+        offset: 0,
+        size,
+        free: true,
+      }])),
+      memory: Arc::new(memory),
+    }
+  }
+
+  /// Allocates a slice from the pool, using the smallest eligible chunk.
+  pub fn alloc(&self, size: usize) -> Option<SliceBox> {
+    let mut chunks = util::lock(&self.chain);
+    let (index, chunk) = chunks
+      .iter()
+      .cloned()
+      .enumerate()
+      .filter(|(_, chunk)| chunk.free && chunk.size >= size)
+      .min_by_key(|(_, chunk)| chunk.size)?;
+
+    if chunk.size > size {
+      // The surplus memory remains available as a separate chunk
+      chunks.insert(
+        index + 1,
+        Chunk {
+          offset: chunk.offset + size,
+          size: chunk.size - size,
+          free: true,
+        },
+      );
+    }
+
+    chunks[index] = Chunk {
+      offset: chunk.offset,
+      size,
+      free: false,
+    };
+
+    Some(SliceBox {
+      chain: self.chain.clone(),
+      _memory: self.memory.clone(),
+      data: unsafe { self.as_ptr().add(chunk.offset) as *mut u8 },
+      offset: chunk.offset,
+      size,
+    })
+  }
+
+  /// Returns the number of active allocations.
+  pub fn allocations(&self) -> usize {
+    let chunks = util::lock(&self.chain);
+    chunks.iter().filter(|chunk| !chunk.free).count()
+  }
+
+  /// Returns a pointer to the pool's memory.
+  pub fn as_ptr(&self) -> *const u8 {
+    (*self.memory).as_ref().as_ptr()
+  }
+
+  /// Returns the size of the pool's memory.
+  pub fn len(&self) -> usize {
+    (*self.memory).as_ref().len()
+  }
+}
+
+/// An allocation in a `SlicePool`, returned to the pool once dropped.
+pub struct SliceBox {
+  chain: Arc<ChunkChain>,
+  _memory: Arc<dyn AsRef<[u8]> + Send + Sync>,
+  data: *mut u8,
+  offset: usize,
+  size: usize,
+}
+
+impl Deref for SliceBox {
+  type Target = [u8];
+
+  fn deref(&self) -> &Self::Target {
+    unsafe { slice::from_raw_parts(self.data, self.size) }
+  }
+}
+
+impl DerefMut for SliceBox {
+  fn deref_mut(&mut self) -> &mut Self::Target {
+    unsafe { slice::from_raw_parts_mut(self.data, self.size) }
+  }
+}
+
+impl Drop for SliceBox {
+  /// Releases the chunk, merging it with any adjacent free chunks.
+  fn drop(&mut self) {
+    let mut chunks = util::lock(&self.chain);
+    let index = chunks
+      .binary_search_by_key(&self.offset, |chunk| chunk.offset)
+      .expect("releasing chunk");
+    chunks[index].free = true;
+
+    if index + 1 < chunks.len() && chunks[index + 1].free {
+      let size = chunks.remove(index + 1).size;
+      chunks[index].size += size;
+    }
+
+    if index > 0 && chunks[index - 1].free {
+      let size = chunks.remove(index).size;
+      chunks[index - 1].size += size;
+    }
+  }
+}
+
+unsafe impl Send for SliceBox {}
+unsafe impl Sync for SliceBox {}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn reuses_released_chunks() {
+    let pool = SlicePool::new(vec![0u8; 64]);
+
+    let first = pool.alloc(16).unwrap();
+    let second = pool.alloc(24).unwrap();
+    mem_eq(&first, 0, &pool);
+    mem_eq(&second, 16, &pool);
+
+    // Releasing both leaves a single free chunk
+    assert_eq!(pool.allocations(), 2);
+    drop(first);
+    drop(second);
+    assert_eq!(pool.allocations(), 0);
+    assert_eq!(pool.chain.lock().unwrap().len(), 1);
+
+    // Allocations next to a free chunk must not overlap
+    let first = pool.alloc(16).unwrap();
+    let second = pool.alloc(24).unwrap();
+    let third = pool.alloc(24).unwrap();
+    mem_eq(&first, 0, &pool);
+    mem_eq(&second, 16, &pool);
+    mem_eq(&third, 40, &pool);
+    assert!(pool.alloc(1).is_none());
+  }
+
+  #[test]
+  fn merges_chunks_on_both_sides() {
+    let pool = SlicePool::new(vec![0u8; 48]);
+
+    let first = pool.alloc(16).unwrap();
+    let second = pool.alloc(16).unwrap();
+    let third = pool.alloc(16).unwrap();
+
+    // The chunk released last is merged with both of its neighbours
+    drop(first);
+    drop(third);
+    drop(second);
+    assert_eq!(pool.chain.lock().unwrap().len(), 1);
+
+    let first = pool.alloc(8).unwrap();
+    let second = pool.alloc(8).unwrap();
+    mem_eq(&first, 0, &pool);
+    mem_eq(&second, 8, &pool);
+    assert_eq!(pool.allocations(), 2);
+
+    drop(first);
+    drop(second);
+    assert_eq!(pool.allocations(), 0);
+  }
+
+  fn mem_eq(slice: &SliceBox, offset: usize, pool: &SlicePool) {
+    assert_eq!(slice.as_ptr(), unsafe { pool.as_ptr().add(offset) });
+  }
+}
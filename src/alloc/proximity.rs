@@ -1,43 +1,142 @@
-use std::ops::Range;
-use std::slice;
+use std::ops::{Deref, Range};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::RwLock;
+use std::{cmp, mem, slice};
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use std::{io, ptr};
 
-use slice_pool::sync::{SliceBox, SlicePool};
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+use mmap::MemoryMap;
 
+use super::pool::{SliceBox, SlicePool};
 use super::search as region_search;
 use crate::error::{Error, Result};
+use crate::util;
 
-/// Defines the allocation type.
-pub type Allocation = SliceBox<u8>;
+/// The maximum number of searches, if free regions are mapped concurrently.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const MAX_SEARCH_ATTEMPTS: usize = 4;
 
 /// Shared instance containing all pools
-pub struct ProximityAllocator {
-  pub max_distance: usize,
-  pub pools: Vec<SlicePool<u8>>,
+pub struct PoolAllocator {
+  granularity: AtomicUsize,
+  pools: RwLock<Vec<Pool>>,
 }
 
-impl ProximityAllocator {
-  /// Allocates a slice in an eligible memory map.
-  pub fn allocate(&mut self, origin: *const (), size: usize) -> Result<Allocation> {
-    let memory_range = ((origin as usize).saturating_sub(self.max_distance))
-      ..((origin as usize).saturating_add(self.max_distance));
+/// A pool of executable memory.
+pub struct Pool {
+  slices: SlicePool,
+  /// The writable view of the pool, if it is dual mapped.
+  alias: Option<*mut u8>,
+}
+
+impl Pool {
+  /// Allocates a slice from the pool.
+  fn alloc(&self, size: usize) -> Option<Allocation> {
+    let data = self.slices.alloc(size)?;
+    let offset = data.as_ptr() as usize - self.slices.as_ptr() as usize;
+
+    Some(Allocation {
+      alias: self.alias.map(|alias| unsafe { alias.add(offset) }),
+      data,
+    })
+  }
+
+  /// Returns a pointer to the pool's executable memory.
+  fn as_ptr(&self) -> *const u8 {
+    self.slices.as_ptr()
+  }
+
+  /// Returns the size of the pool's memory.
+  fn len(&self) -> usize {
+    self.slices.len()
+  }
+
+  /// Returns the number of active allocations.
+  fn allocations(&self) -> usize {
+    self.slices.allocations()
+  }
+}
+
+unsafe impl Send for Pool {}
+unsafe impl Sync for Pool {}
+
+/// An allocation of executable memory.
+pub struct Allocation {
+  data: SliceBox,
+  alias: Option<*mut u8>,
+}
+
+impl Allocation {
+  /// Returns the writable view of the allocation, if it is dual mapped.
+  pub fn alias(&self) -> Option<*mut u8> {
+    self.alias
+  }
+}
+
+impl Deref for Allocation {
+  type Target = [u8];
+
+  fn deref(&self) -> &Self::Target {
+    self.data.deref()
+  }
+}
+
+unsafe impl Send for Allocation {}
+unsafe impl Sync for Allocation {}
+
+impl PoolAllocator {
+  /// Constructs an allocator, whose pools are at least `granularity` bytes.
+  pub fn new(granularity: usize) -> Self {
+    PoolAllocator {
+      granularity: AtomicUsize::new(granularity),
+      pools: RwLock::new(Vec::new()),
+    }
+  }
+
+  /// Sets the minimum size of subsequently allocated pools.
+  pub fn set_granularity(&self, granularity: usize) {
+    self.granularity.store(granularity, Ordering::SeqCst);
+  }
+
+  /// Allocates a slice in an eligible memory map, within `max_distance` bytes
+  /// of `origin`.
+  pub fn allocate(
+    &self,
+    origin: *const (),
+    size: usize,
+    max_distance: usize,
+  ) -> Result<Allocation> {
+    let memory_range = ((origin as usize).saturating_sub(max_distance))
+      ..((origin as usize).saturating_add(max_distance));
+
+    // Check if an existing pool can handle the allocation request; each pool
+    // has its own lock, so this is performed in parallel.
+    if let Ok(allocation) =
+      Self::allocate_memory(&util::read_lock(&self.pools), &memory_range, size)
+    {
+      return Ok(allocation);
+    }
 
-    // Check if an existing pool can handle the allocation request
-    self.allocate_memory(&memory_range, size).or_else(|_| {
+    // Another thread may have allocated an eligible pool in the meantime
+    let mut pools = util::write_lock(&self.pools);
+    Self::allocate_memory(&pools, &memory_range, size).or_else(|_| {
       // ... otherwise allocate a pool within the memory range
       self.allocate_pool(&memory_range, origin, size).map(|pool| {
         // Use the newly allocated pool for the request
         let allocation = pool.alloc(size).unwrap();
-        self.pools.push(pool);
+        pools.push(pool);
         allocation
       })
     })
   }
 
-  /// Releases the memory pool associated with an allocation.
-  pub fn release(&mut self, value: &Allocation) {
+  /// Releases an allocation, along with its memory pool if it was the last.
+  pub fn release(&self, value: Allocation) {
+    let mut pools = util::write_lock(&self.pools);
+
     // Find the associated memory pool
-    let index = self
-      .pools
+    let index = pools
       .iter()
       .position(|pool| {
         let lower = pool.as_ptr() as usize;
@@ -48,25 +147,26 @@ impl ProximityAllocator {
       })
       .expect("retrieving associated memory pool");
 
-    // Release the pool if the associated allocation is unique
-    if self.pools[index].len() == 1 {
-      self.pools.remove(index);
+    // The allocation is dropped whilst locked, so concurrent releases cannot
+    // both observe another allocation remaining in the pool.
+    mem::drop(value);
+    if pools[index].allocations() == 0 {
+      pools.remove(index);
     }
   }
 
   /// Allocates a chunk using any of the existing pools.
-  fn allocate_memory(&mut self, range: &Range<usize>, size: usize) -> Result<Allocation> {
+  fn allocate_memory(pools: &[Pool], range: &Range<usize>, size: usize) -> Result<Allocation> {
     // Returns true if the pool's memory is within the range
-    let is_pool_in_range = |pool: &SlicePool<u8>| {
+    let is_pool_in_range = |pool: &Pool| {
       let lower = pool.as_ptr() as usize;
       let upper = lower + pool.len();
       range.contains(&lower) && range.contains(&(upper - 1))
     };
 
     // Tries to allocate a slice within any eligible pool
-    self
-      .pools
-      .iter_mut()
+    pools
+      .iter()
       .filter_map(|pool| {
         if is_pool_in_range(pool) {
           pool.alloc(size)
@@ -79,20 +179,54 @@ impl ProximityAllocator {
   }
 
   /// Allocates a new pool close to `origin`.
-  fn allocate_pool(
-    &mut self,
-    range: &Range<usize>,
-    origin: *const (),
-    size: usize,
-  ) -> Result<SlicePool<u8>> {
+  #[cfg(any(target_os = "linux", target_os = "android"))]
+  fn allocate_pool(&self, range: &Range<usize>, origin: *const (), size: usize) -> Result<Pool> {
+    let size = self.pool_size(size);
+
+    // Other threads may map memory concurrently, invalidating the free
+    // regions, in which case the search is repeated.
+    for _ in 0..MAX_SEARCH_ATTEMPTS {
+      let mut stale = false;
+
+      for address in region_search::nearest(origin, range.clone(), size)? {
+        match Self::allocate_fixed_pool(address, size) {
+          Ok(pool) => return Ok(pool),
+          Err(error) if error.raw_os_error() == Some(libc::EEXIST) => {
+            stale = true;
+            break;
+          },
+          Err(error) => log::debug!(
+            "failed to allocate {} bytes at {:?}: {}",
+            size,
+            address,
+            error
+          ),
+        }
+      }
+
+      if !stale {
+        break;
+      }
+    }
+
+    Err(Error::OutOfMemory)
+  }
+
+  /// Allocates a new pool close to `origin`.
+  #[cfg(not(any(target_os = "linux", target_os = "android")))]
+  fn allocate_pool(&self, range: &Range<usize>, origin: *const (), size: usize) -> Result<Pool> {
     let before = region_search::before(origin, Some(range.clone()));
     let after = region_search::after(origin, Some(range.clone()));
+    let size = self.pool_size(size);
 
-    // TODO: Part of the pool can be out of range
     // Try to allocate after the specified address first (mostly because
     // macOS cannot allocate memory before the process's address).
     after
       .chain(before)
+      .filter(|result| match result {
+        Ok(address) => Self::is_range_free(address, size, range),
+        Err(_) => true,
+      })
       .filter_map(|result| match result {
         Ok(address) => Self::allocate_fixed_pool(address, size).map(Ok),
         Err(error) => Some(Err(error)),
@@ -101,8 +235,117 @@ impl ProximityAllocator {
       .unwrap_or(Err(Error::OutOfMemory))
   }
 
+  /// Returns the size of a pool, able to contain an allocation.
+  fn pool_size(&self, size: usize) -> usize {
+    // Pools are shared by nearby allocations, so they span at least a granule
+    let page_size = region::page::size();
+    (cmp::max(size, self.granularity.load(Ordering::SeqCst)) + page_size - 1) & !(page_size - 1)
+  }
+
+  /// Returns whether a pool can be mapped at an address without exceeding the
+  /// range or replacing any existing mapping.
+  #[cfg(not(any(target_os = "linux", target_os = "android")))]
+  fn is_range_free(address: &*const (), size: usize, range: &Range<usize>) -> bool {
+    let lower = *address as usize;
+    let upper = match lower.checked_add(size) {
+      Some(upper) => upper,
+      None => return false,
+    };
+
+    range.contains(&lower)
+      && range.contains(&(upper - 1))
+      && region::query_range(*address, size)
+        .map(|mut regions| regions.next().is_none())
+        .unwrap_or(false)
+  }
+
   /// Tries to allocate fixed memory at the specified address.
-  fn allocate_fixed_pool(address: *const (), size: usize) -> Option<SlicePool<u8>> {
+  ///
+  /// This fails with `EEXIST` if the address is already mapped.
+  #[cfg(any(target_os = "linux", target_os = "android"))]
+  fn allocate_fixed_pool(address: *const (), size: usize) -> io::Result<Pool> {
+    // Prefer separate views for execution and writing, so the memory is never
+    // both writable and executable.
+    match Self::allocate_dual_pool(address, size) {
+      Ok(pool) => return Ok(pool),
+      Err(error) if error.raw_os_error() == Some(libc::EEXIST) => return Err(error),
+      Err(error) => log::debug!(
+        "failed to dual map {} bytes at {:?}: {}",
+        size,
+        address,
+        error
+      ),
+    }
+
+    let map = unsafe {
+      MemoryMap::fixed(
+        address,
+        size,
+        libc::PROT_READ | libc::PROT_EXEC,
+        libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+        -1,
+      )
+    }?;
+
+    Ok(Pool {
+      slices: SlicePool::new(SliceableMemoryMap { map, _alias: None }),
+      alias: None,
+    })
+  }
+
+  /// Tries to allocate fixed memory at the specified address, backed by an
+  /// anonymous file which is mapped twice; once executable (at the address)
+  /// and once writable.
+  #[cfg(any(target_os = "linux", target_os = "android"))]
+  fn allocate_dual_pool(address: *const (), size: usize) -> io::Result<Pool> {
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+
+    let fd = unsafe {
+      libc::syscall(
+        libc::SYS_memfd_create,
+        b"detour\0".as_ptr(),
+        libc::MFD_CLOEXEC,
+      )
+    } as libc::c_int;
+
+    if fd < 0 {
+      return Err(io::Error::last_os_error());
+    }
+
+    // The mappings keep the file alive once it's closed
+    let file = unsafe { std::fs::File::from_raw_fd(fd) };
+    file.set_len(size as u64)?;
+
+    let (map, alias) = unsafe {
+      (
+        MemoryMap::fixed(
+          address,
+          size,
+          libc::PROT_READ | libc::PROT_EXEC,
+          libc::MAP_SHARED,
+          file.as_raw_fd(),
+        )?,
+        MemoryMap::new(
+          size,
+          libc::PROT_READ | libc::PROT_WRITE,
+          libc::MAP_SHARED,
+          file.as_raw_fd(),
+        )?,
+      )
+    };
+
+    Ok(Pool {
+      alias: Some(alias.data()),
+      slices: SlicePool::new(SliceableMemoryMap {
+        map,
+        _alias: Some(alias),
+      }),
+    })
+  }
+
+  /// Tries to allocate fixed memory at the specified address.
+  #[cfg(not(any(target_os = "linux", target_os = "android")))]
+  fn allocate_fixed_pool(address: *const (), size: usize) -> Option<Pool> {
     // Try to allocate memory at the specified address
     let result = mmap::MemoryMap::new(
       size,
@@ -122,21 +365,103 @@ impl ProximityAllocator {
       );
     }
 
-    result.ok().map(SliceableMemoryMap).map(SlicePool::new)
+    result.ok().map(|map| Pool {
+      slices: SlicePool::new(SliceableMemoryMap { map, _alias: None }),
+      alias: None,
+    })
+  }
+}
+
+/// A memory mapping, which is unmapped once dropped.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+struct MemoryMap {
+  data: *mut u8,
+  len: usize,
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+impl MemoryMap {
+  /// Maps memory at a fixed address, without replacing any existing mapping.
+  unsafe fn fixed(
+    address: *const (),
+    size: usize,
+    protection: libc::c_int,
+    flags: libc::c_int,
+    fd: libc::c_int,
+  ) -> io::Result<Self> {
+    let map = Self::map(
+      address,
+      size,
+      protection,
+      flags | libc::MAP_FIXED_NOREPLACE,
+      fd,
+    )?;
+
+    // Kernels prior to 4.17 treat the flag as a hint, mapping elsewhere if the
+    // address is occupied.
+    if map.data as *const () != address {
+      return Err(io::Error::from_raw_os_error(libc::EEXIST));
+    }
+
+    Ok(map)
+  }
+
+  /// Maps memory at an arbitrary address.
+  unsafe fn new(
+    size: usize,
+    protection: libc::c_int,
+    flags: libc::c_int,
+    fd: libc::c_int,
+  ) -> io::Result<Self> {
+    Self::map(ptr::null(), size, protection, flags, fd)
+  }
+
+  unsafe fn map(
+    address: *const (),
+    size: usize,
+    protection: libc::c_int,
+    flags: libc::c_int,
+    fd: libc::c_int,
+  ) -> io::Result<Self> {
+    let data = libc::mmap(address as *mut _, size, protection, flags, fd, 0);
+
+    if data == libc::MAP_FAILED {
+      Err(io::Error::last_os_error())
+    } else {
+      Ok(MemoryMap {
+        data: data as *mut u8,
+        len: size,
+      })
+    }
+  }
+
+  fn data(&self) -> *mut u8 {
+    self.data
+  }
+
+  fn len(&self) -> usize {
+    self.len
+  }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+impl Drop for MemoryMap {
+  fn drop(&mut self) {
+    unsafe { libc::munmap(self.data as *mut _, self.len) };
   }
 }
 
 // TODO: Use memmap-rs instead
-/// A wrapper for making a memory map compatible with `SlicePool`.
-struct SliceableMemoryMap(mmap::MemoryMap);
+/// A wrapper for making a memory map, and its optional writable view,
+/// compatible with `SlicePool`.
+struct SliceableMemoryMap {
+  map: MemoryMap,
+  _alias: Option<MemoryMap>,
+}
 
 impl SliceableMemoryMap {
   pub fn as_slice(&self) -> &[u8] {
-    unsafe { slice::from_raw_parts(self.0.data(), self.0.len()) }
-  }
-
-  pub fn as_mut_slice(&mut self) -> &mut [u8] {
-    unsafe { slice::from_raw_parts_mut(self.0.data(), self.0.len()) }
+    unsafe { slice::from_raw_parts(self.map.data(), self.map.len()) }
   }
 }
 
@@ -146,11 +471,90 @@ impl AsRef<[u8]> for SliceableMemoryMap {
   }
 }
 
-impl AsMut<[u8]> for SliceableMemoryMap {
-  fn as_mut(&mut self) -> &mut [u8] {
-    self.as_mut_slice()
-  }
-}
-
 unsafe impl Send for SliceableMemoryMap {}
 unsafe impl Sync for SliceableMemoryMap {}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// The maximum distance between an allocation and its origin.
+  const RANGE: usize = 0x8000_0000;
+
+  #[test]
+  fn packs_and_releases_pools() -> Result<()> {
+    let allocator = PoolAllocator::new(0x10000);
+
+    // Allocations for nearby origins share a single pool
+    let origin = packs_and_releases_pools as *const ();
+    let allocations = (0..1000)
+      .map(|index| {
+        allocator.allocate(
+          unsafe { (origin as *const u8).add(index * 64) } as _,
+          32,
+          RANGE,
+        )
+      })
+      .collect::<Result<Vec<_>>>()?;
+    assert_eq!(util::read_lock(&allocator.pools).len(), 1);
+
+    // The pool is removed once its last allocation is released
+    for allocation in allocations {
+      allocator.release(allocation);
+    }
+    assert!(util::read_lock(&allocator.pools).is_empty());
+    Ok(())
+  }
+
+  #[test]
+  fn releases_pools_concurrently() -> Result<()> {
+    use std::sync::{Arc, Barrier};
+    use std::thread;
+
+    let allocator = Arc::new(PoolAllocator::new(0x10000));
+    let barrier = Arc::new(Barrier::new(8));
+
+    // Each thread releases its own allocations of the same pool at once
+    let origin = releases_pools_concurrently as *const ();
+    let threads = (0..8)
+      .map(|_| {
+        let allocations = (0..16)
+          .map(|_| allocator.allocate(origin, 32, RANGE))
+          .collect::<Result<Vec<_>>>()?;
+        let (allocator, barrier) = (allocator.clone(), barrier.clone());
+
+        Ok(thread::spawn(move || {
+          barrier.wait();
+          for allocation in allocations {
+            allocator.release(allocation);
+          }
+        }))
+      })
+      .collect::<Result<Vec<_>>>()?;
+    assert_eq!(util::read_lock(&allocator.pools).len(), 1);
+
+    for thread in threads {
+      thread.join().unwrap();
+    }
+    assert!(util::read_lock(&allocator.pools).is_empty());
+    Ok(())
+  }
+
+  #[test]
+  #[cfg(any(target_os = "linux", target_os = "android"))]
+  fn writes_through_dual_mapping() -> Result<()> {
+    let allocator = PoolAllocator::new(0);
+
+    let origin = writes_through_dual_mapping as *const ();
+    let allocation = allocator.allocate(origin, 16, RANGE)?;
+    let alias = allocation.alias().expect("dual mapped allocation");
+
+    // The executable view is never writable
+    let protection = region::query(allocation.as_ptr())?.protection();
+    assert_eq!(protection, region::Protection::READ_EXECUTE);
+
+    unsafe { *alias = 0xC3 };
+    assert_eq!(allocation[0], 0xC3);
+    Ok(())
+  }
+}
@@ -2,6 +2,7 @@ use crate::error::{Error, Result};
 use std::ops::Range;
 
 /// Returns an iterator for free after the specified address.
+#[cfg_attr(any(target_os = "linux", target_os = "android"), allow(dead_code))]
 pub fn after(
   origin: *const (),
   range: Option<Range<usize>>,
@@ -10,6 +11,7 @@ pub fn after(
 }
 
 /// Returns an iterator for free before the specified address.
+#[cfg_attr(any(target_os = "linux", target_os = "android"), allow(dead_code))]
 pub fn before(
   origin: *const (),
   range: Option<Range<usize>>,
@@ -77,3 +79,121 @@ impl Iterator for FreeRegionIter {
     None
   }
 }
+
+/// Returns the closest address within each free region able to fit `size`
+/// bytes, ordered by their distance to the specified address.
+///
+/// Contrary to the iterators, the process' memory map is only parsed once,
+/// instead of being queried page by page.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn nearest(origin: *const (), range: Range<usize>, size: usize) -> Result<Vec<*const ()>> {
+  use std::cmp;
+
+  let page_size = region::page::size();
+  let origin = origin as usize & !(page_size - 1);
+
+  let mut candidates = gaps(range)?
+    .into_iter()
+    .filter(|gap| gap.end - gap.start >= size)
+    .map(|gap| cmp::min(cmp::max(origin, gap.start), gap.end - size))
+    .collect::<Vec<_>>();
+
+  candidates.sort_by_key(|&address| cmp::max(address, origin) - cmp::min(address, origin));
+  Ok(
+    candidates
+      .into_iter()
+      .map(|address| address as *const ())
+      .collect(),
+  )
+}
+
+/// Returns the unmapped regions within a range, in ascending order.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn gaps(range: Range<usize>) -> Result<Vec<Range<usize>>> {
+  use std::cmp;
+
+  let page_size = region::page::size();
+  let maps = std::fs::read_to_string("/proc/self/maps")
+    .map_err(|error| Error::RegionFailure(region::Error::SystemCall(error)))?;
+
+  let mut gaps = Vec::new();
+  let mut lower = cmp::max(range.start, page_size);
+
+  for line in maps.lines() {
+    let parse = || -> Option<Range<usize>> {
+      let mut bounds = line.split_whitespace().next()?.split('-');
+      let start = usize::from_str_radix(bounds.next()?, 16).ok()?;
+      let end = usize::from_str_radix(bounds.next()?, 16).ok()?;
+      Some(start..end)
+    };
+
+    let mapping =
+      parse().ok_or_else(|| Error::RegionFailure(region::Error::ProcfsInput(line.to_string())))?;
+
+    if mapping.start > lower {
+      gaps.push(lower..cmp::min(mapping.start, range.end));
+    }
+
+    lower = cmp::max(lower, mapping.end);
+    if lower >= range.end {
+      break;
+    }
+  }
+
+  if lower < range.end {
+    gaps.push(lower..range.end);
+  }
+
+  gaps.retain(|gap| gap.start < gap.end);
+  Ok(gaps)
+}
+
+#[cfg(all(
+  feature = "nightly",
+  test,
+  any(target_os = "linux", target_os = "android")
+))]
+mod benches {
+  extern crate test;
+
+  use super::*;
+  use test::Bencher;
+
+  /// The size of the searched region.
+  const SIZE: usize = 0x10000;
+
+  /// Returns a range of +/- 2 GB around an address.
+  fn range(origin: *const ()) -> Range<usize> {
+    (origin as usize).saturating_sub(0x8000_0000)..(origin as usize).saturating_add(0x8000_0000)
+  }
+
+  #[bench]
+  fn search_by_query(b: &mut Bencher) {
+    let origin = search_by_query as *const ();
+    let range = range(origin);
+
+    b.iter(|| {
+      after(origin, Some(range.clone()))
+        .chain(before(origin, Some(range.clone())))
+        .filter_map(|result| result.ok())
+        .find(|&address| {
+          region::query_range(address, SIZE)
+            .map(|mut regions| regions.next().is_none())
+            .unwrap_or(false)
+        })
+    });
+  }
+
+  #[bench]
+  fn search_by_maps(b: &mut Bencher) {
+    let origin = search_by_maps as *const ();
+    let range = range(origin);
+
+    b.iter(|| {
+      nearest(origin, range.clone(), SIZE)
+        .unwrap()
+        .first()
+        .cloned()
+    });
+  }
+}
@@ -0,0 +1,204 @@
+//! Relocation of whole functions (aarch64).
+//!
+//! The function is copied as-is, so its layout, including any internal
+//! branches and literal pools, is preserved. Only PC relative instructions
+//! referring to code or data outside of the function are re-encoded for the
+//! clone's location.
+use crate::error::{Error, Result};
+use crate::pic;
+use std::slice;
+
+/// The size of a page, as referred to by `adrp`.
+const PAGE_SIZE: usize = 0x1000;
+
+/// The encoding of a PC relative instruction's immediate.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Kind {
+  /// `b` and `bl` (imm26, ±128 MiB).
+  Branch,
+  /// `b.cond`, `cbz`, `cbnz` and `ldr` (literal) (imm19, ±1 MiB).
+  Imm19,
+  /// `tbz` and `tbnz` (imm14, ±32 KiB).
+  Imm14,
+  /// `adr` (immhi:immlo, ±1 MiB).
+  Adr,
+  /// `adrp` (immhi:immlo in pages, ±4 GiB).
+  Adrp,
+}
+
+impl Kind {
+  /// Returns the kind of a PC relative instruction.
+  fn of(opcode: u32) -> Option<Kind> {
+    if opcode & 0x7C00_0000 == 0x1400_0000 {
+      Some(Kind::Branch)
+    } else if opcode & 0xFF00_0010 == 0x5400_0000
+      || opcode & 0x7E00_0000 == 0x3400_0000
+      || opcode & 0x3B00_0000 == 0x1800_0000
+    {
+      Some(Kind::Imm19)
+    } else if opcode & 0x7E00_0000 == 0x3600_0000 {
+      Some(Kind::Imm14)
+    } else if opcode & 0x9F00_0000 == 0x1000_0000 {
+      Some(Kind::Adr)
+    } else if opcode & 0x9F00_0000 == 0x9000_0000 {
+      Some(Kind::Adrp)
+    } else {
+      None
+    }
+  }
+
+  /// Returns the exclusive bound of the displacement in bytes.
+  fn range(self) -> usize {
+    match self {
+      Kind::Branch => 1 << 27,
+      Kind::Imm19 | Kind::Adr => 1 << 20,
+      Kind::Imm14 => 1 << 15,
+      Kind::Adrp => 1 << 32,
+    }
+  }
+
+  /// Returns the address an instruction refers to (for `adrp`, a page).
+  fn destination(self, opcode: u32, pc: usize) -> usize {
+    let displacement = match self {
+      Kind::Branch => sign_extend(opcode & 0x03FF_FFFF, 26) << 2,
+      Kind::Imm19 => sign_extend((opcode >> 5) & 0x7_FFFF, 19) << 2,
+      Kind::Imm14 => sign_extend((opcode >> 5) & 0x3FFF, 14) << 2,
+      Kind::Adr => sign_extend(Self::adr_immediate(opcode), 21),
+      Kind::Adrp => sign_extend(Self::adr_immediate(opcode), 21) << 12,
+    };
+    Self::origin(self, pc).wrapping_add(displacement as usize)
+  }
+
+  /// Returns the address the displacement is relative to.
+  fn origin(self, pc: usize) -> usize {
+    if self == Kind::Adrp {
+      pc & !(PAGE_SIZE - 1)
+    } else {
+      pc
+    }
+  }
+
+  /// Re-encodes an instruction, located at `pc`, to refer to `destination`.
+  fn encode(self, opcode: u32, pc: usize, destination: usize) -> u32 {
+    let displacement = destination.wrapping_sub(Self::origin(self, pc)) as isize;
+    assert!(displacement.unsigned_abs() < self.range());
+
+    match self {
+      Kind::Branch => (opcode & !0x03FF_FFFF) | ((displacement >> 2) as u32 & 0x03FF_FFFF),
+      Kind::Imm19 => (opcode & !(0x7_FFFF << 5)) | (((displacement >> 2) as u32 & 0x7_FFFF) << 5),
+      Kind::Imm14 => (opcode & !(0x3FFF << 5)) | (((displacement >> 2) as u32 & 0x3FFF) << 5),
+      Kind::Adr | Kind::Adrp => {
+        let immediate = if self == Kind::Adrp {
+          (displacement >> 12) as u32
+        } else {
+          displacement as u32
+        };
+
+        (opcode & !((0x7_FFFF << 5) | (0b11 << 29)))
+          | ((immediate & 0b11) << 29)
+          | (((immediate >> 2) & 0x7_FFFF) << 5)
+      },
+    }
+  }
+
+  /// Returns the immediate of an `adr` or `adrp` (i.e immhi:immlo).
+  fn adr_immediate(opcode: u32) -> u32 {
+    (((opcode >> 5) & 0x7_FFFF) << 2) | ((opcode >> 29) & 0b11)
+  }
+}
+
+/// Sign extends the lower `bits` of a value.
+fn sign_extend(value: u32, bits: u32) -> isize {
+  ((value << (32 - bits)) as i32 >> (32 - bits)) as isize
+}
+
+/// A PC relative instruction referring to an address outside of the function.
+struct Fixup {
+  /// The offset of the instruction within the function.
+  offset: usize,
+  kind: Kind,
+  /// The absolute address referred to.
+  destination: usize,
+}
+
+/// Creates a clone of a function of `size` bytes.
+///
+/// Returns the clone's emitter, and the furthest distance from the function it
+/// may be located at.
+pub unsafe fn builder(function: *const (), size: usize) -> Result<(pic::CodeEmitter, usize)> {
+  let start = function as usize;
+  let length = size - size % 4;
+  if length == 0 {
+    Err(Error::InvalidCode)?;
+  }
+
+  let code = slice::from_raw_parts(function as *const u8, length).to_vec();
+  let mut fixups = Vec::new();
+
+  for (index, opcode) in code.chunks_exact(4).enumerate() {
+    let opcode = u32::from_le_bytes([opcode[0], opcode[1], opcode[2], opcode[3]]);
+    let kind = match Kind::of(opcode) {
+      Some(kind) => kind,
+      None => continue,
+    };
+
+    let offset = index * 4;
+    let destination = kind.destination(opcode, start + offset);
+
+    // Internal references remain valid, since the layout is preserved. Pages
+    // are always re-encoded, since they're paired with a page offset.
+    if kind != Kind::Adrp && (start..start + length).contains(&destination) {
+      continue;
+    }
+
+    fixups.push(Fixup {
+      offset,
+      kind,
+      destination,
+    });
+  }
+
+  // The clone must be close enough for every displacement to remain in range
+  let max_distance = fixups
+    .iter()
+    .map(|fixup| {
+      let origin = fixup.kind.origin(start + fixup.offset);
+      let displacement = fixup.destination.wrapping_sub(origin) as isize;
+      fixup
+        .kind
+        .range()
+        .saturating_sub(displacement.unsigned_abs())
+        .saturating_sub(length + PAGE_SIZE)
+    })
+    .min()
+    .unwrap_or(usize::MAX);
+
+  if max_distance == 0 {
+    Err(Error::UnsupportedInstruction)?;
+  }
+
+  let mut emitter = pic::CodeEmitter::new();
+  emitter.add_thunk(Box::new(pic::UnsafeThunk::new(
+    move |base| {
+      let mut code = code.clone();
+      for fixup in &fixups {
+        let instruction = &mut code[fixup.offset..fixup.offset + 4];
+        let opcode = u32::from_le_bytes([
+          instruction[0],
+          instruction[1],
+          instruction[2],
+          instruction[3],
+        ]);
+
+        let opcode = fixup
+          .kind
+          .encode(opcode, base + fixup.offset, fixup.destination);
+        instruction.copy_from_slice(&opcode.to_le_bytes());
+      }
+      code
+    },
+    length,
+  )));
+
+  Ok((emitter, max_distance))
+}
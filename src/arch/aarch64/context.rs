@@ -0,0 +1,204 @@
+use super::thunk::thunk_dynasm;
+
+/// The register state of a thread entering or leaving a hooked function.
+///
+/// Only the registers used for passing arguments and return values in the
+/// AAPCS64 are captured.
+#[repr(C)]
+#[derive(Debug, Clone)]
+pub struct CpuContext {
+  /// Integer argument registers (`x0`-`x7`) and the indirect result register
+  /// (`x8`).
+  pub x: [u64; 9],
+  /// The link register (`x30`).
+  pub lr: u64,
+  /// The stack pointer upon entry to the function.
+  pub sp: u64,
+  _reserved: u64,
+  /// Vector argument and return registers (`q0`-`q7`).
+  pub v: [u128; 8],
+  /// The state of the context hook that captured the context, if any.
+  pub(crate) hook: usize,
+  /// Whether the original function should be skipped.
+  pub(crate) skip: usize,
+}
+
+impl CpuContext {
+  /// Returns the stack pointer upon entry to the function.
+  pub fn stack_pointer(&self) -> usize {
+    self.sp as usize
+  }
+
+  /// Returns the address the function returns to.
+  pub fn return_address(&self) -> usize {
+    self.lr as usize
+  }
+
+  /// Sets the address the function returns to.
+  pub fn set_return_address(&mut self, address: usize) {
+    self.lr = address as u64;
+  }
+
+  /// Returns the integer return value register (`x0`).
+  pub fn return_value(&self) -> u64 {
+    self.x[0]
+  }
+
+  /// Sets the integer return value register (`x0`).
+  pub fn set_return_value(&mut self, value: u64) {
+    self.x[0] = value;
+  }
+
+  /// Returns the floating point return value register (`d0`).
+  pub fn float_return_value(&self) -> f64 {
+    f64::from_bits(self.v[0] as u64)
+  }
+
+  /// Sets the floating point return value register (`d0`).
+  pub fn set_float_return_value(&mut self, value: f64) {
+    self.v[0] = u128::from(value.to_bits());
+  }
+
+  /// Returns the N-th integer argument.
+  ///
+  /// Arguments beyond the eighth are read from the stack, assuming that all
+  /// preceding stack arguments are integers as well.
+  pub unsafe fn arg(&self, index: usize) -> u64 {
+    match self.x[..8].get(index) {
+      Some(&value) => value,
+      None => *self.stack_argument(index - 8),
+    }
+  }
+
+  /// Overwrites the N-th integer argument.
+  ///
+  /// Arguments beyond the eighth are written to the stack, assuming that all
+  /// preceding stack arguments are integers as well.
+  pub unsafe fn set_arg(&mut self, index: usize, value: u64) {
+    match self.x[..8].get_mut(index) {
+      Some(register) => *register = value,
+      None => *self.stack_argument(index - 8) = value,
+    }
+  }
+
+  /// Returns the N-th floating point argument.
+  ///
+  /// Arguments beyond the eighth are read from the stack, assuming that all
+  /// preceding stack arguments are floating point values as well.
+  pub unsafe fn float_arg(&self, index: usize) -> f64 {
+    match self.v.get(index) {
+      Some(&value) => f64::from_bits(value as u64),
+      None => f64::from_bits(*self.stack_argument(index - 8)),
+    }
+  }
+
+  /// Overwrites the N-th floating point argument.
+  ///
+  /// Arguments beyond the eighth are written to the stack, assuming that all
+  /// preceding stack arguments are floating point values as well.
+  pub unsafe fn set_float_arg(&mut self, index: usize, value: f64) {
+    match self.v.get_mut(index) {
+      Some(register) => *register = u128::from(value.to_bits()),
+      None => *self.stack_argument(index - 8) = value.to_bits(),
+    }
+  }
+
+  /// Returns a pointer to the N-th argument passed on the stack.
+  pub fn stack_argument(&self, index: usize) -> *mut u64 {
+    (self.sp as *mut u64).wrapping_add(index)
+  }
+}
+
+/// Generates a stub which captures the thread's context and invokes a
+/// handler with it.
+///
+/// The handler is called as `extern "C" fn(state, &mut CpuContext) -> usize`.
+/// Once it returns, the (possibly modified) context is restored. If the
+/// returned address is non-zero the stub branches to it, otherwise it returns
+/// to `CpuContext::lr`.
+///
+/// The return address resides in a register, so a stub entered by a `ret`
+/// does not require any special treatment.
+pub fn stub(state: usize, handler: usize, _returning: bool) -> Vec<u8> {
+  thunk_dynasm!(
+    ; sub sp, sp, 240
+    ; stp x0, x1, [sp, 0]
+    ; stp x2, x3, [sp, 16]
+    ; stp x4, x5, [sp, 32]
+    ; stp x6, x7, [sp, 48]
+    ; str x8, [sp, 64]
+    ; str x30, [sp, 72]
+    ; add x9, sp, 240
+    ; str x9, [sp, 80]
+    ; stp q0, q1, [sp, 96]
+    ; stp q2, q3, [sp, 128]
+    ; stp q4, q5, [sp, 160]
+    ; stp q6, q7, [sp, 192]
+    ; ldr x0, >state
+    ; add x1, sp, 0
+    ; ldr x9, >handler
+    ; blr x9
+    ; mov x17, x0
+    ; ldp x0, x1, [sp, 0]
+    ; ldp x2, x3, [sp, 16]
+    ; ldp x4, x5, [sp, 32]
+    ; ldp x6, x7, [sp, 48]
+    ; ldr x8, [sp, 64]
+    ; ldr x30, [sp, 72]
+    ; ldp q0, q1, [sp, 96]
+    ; ldp q2, q3, [sp, 128]
+    ; ldp q4, q5, [sp, 160]
+    ; ldp q6, q7, [sp, 192]
+    ; add sp, sp, 240
+    ; cbz x17, >skip
+    ; br x17
+    ; skip:
+    ; ret
+    ; state:
+    ; .qword state as _
+    ; handler:
+    ; .qword handler as _
+  )
+}
+
+/// Generates a function which invokes another function with a context.
+///
+/// The generated function is called as `extern "C" fn(&mut CpuContext,
+/// function)`. The argument registers are loaded from the context, the first
+/// 16 stack slots are copied from the context's stack, and the return value
+/// registers are written back to the context.
+pub fn invoker() -> Vec<u8> {
+  thunk_dynasm!(
+    ; stp x29, x30, [sp, -16]!
+    ; add x29, sp, 0
+    ; stp x19, x20, [sp, -16]!
+    ; mov x19, x0
+    ; mov x20, x1
+    ; sub sp, sp, 128
+    ; ldr x9, [x19, 80]
+    ; mov x10, 0
+    ; copy:
+    ; ldr x11, [x9, x10]
+    ; str x11, [sp, x10]
+    ; add x10, x10, 8
+    ; cmp x10, 128
+    ; b.ne <copy
+    ; ldp q0, q1, [x19, 96]
+    ; ldp q2, q3, [x19, 128]
+    ; ldp q4, q5, [x19, 160]
+    ; ldp q6, q7, [x19, 192]
+    ; ldp x0, x1, [x19, 0]
+    ; ldp x2, x3, [x19, 16]
+    ; ldp x4, x5, [x19, 32]
+    ; ldp x6, x7, [x19, 48]
+    ; ldr x8, [x19, 64]
+    ; blr x20
+    ; stp x0, x1, [x19, 0]
+    ; stp q0, q1, [x19, 96]
+    ; stp q2, q3, [x19, 128]
+    ; add sp, sp, 128
+    ; ldp x19, x20, [sp], 16
+    ; ldp x29, x30, [sp], 16
+    ; ret
+  )
+}
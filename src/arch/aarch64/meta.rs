@@ -1,11 +1,25 @@
-use super::thunk;
-use crate::{error::Result, pic};
+use super::{context, thunk};
+use crate::error::{Error, Result};
+use crate::pic;
 use bad64::{Imm, Op, Operand, Reg};
+use std::sync::atomic::AtomicBool;
 
-/// The furthest distance between a target and its detour (2 GiB).
-pub const DETOUR_RANGE: usize = 0x8000_0000;
-pub const PAGE_SIZE: usize = 0x1000;
+/// The furthest distance between a target and its relay (128 MiB), i.e the
+/// reach of a single `b` instruction.
+pub const DETOUR_RANGE: usize = thunk::BRANCH_RANGE;
+
+/// The furthest distance between a target and its relay (4 GiB) if it's
+/// patched with a page relative jump, used if no memory is available within
+/// `DETOUR_RANGE`.
+pub const PAGE_DETOUR_RANGE: usize = thunk::PAGE_RANGE - thunk::PAGE_SIZE;
 pub const ALIGNMENT: usize = 8;
+
+/// A breakpoint instruction (`brk #0`).
+pub const BREAKPOINT: &[u8] = &[0x00, 0x00, 0x20, 0xD4];
+
+/// The instructions padding functions which are never executed (`brk #0`, and
+/// the undefined instruction used by LLVM's linker), usable as caves.
+pub const CAVE_PADDING: &[&[u8]] = &[&[0x00, 0x00, 0x20, 0xD4], &[0xD4, 0xD4, 0xD4, 0xD4]];
 pub const CONDITIONAL_OPS: &[bad64::Op] = &[
   Op::B_AL,
   Op::B_CS,
@@ -25,19 +39,97 @@ pub const CONDITIONAL_OPS: &[bad64::Op] = &[
   Op::B_VS,
 ];
 
-/// Returns the preferred prolog size for the target.
+/// Returns the preferred prolog size for the target (a single `b`).
 pub fn prolog_margin(_target: *const ()) -> usize {
+  4
+}
+
+/// Returns the prolog size required to patch the target with a page relative
+/// jump (`adrp`, `add` & `br`).
+pub fn page_prolog_margin(_target: *const ()) -> usize {
   12
 }
 
-/// Creates a relay containing the detour address to be loaded by the three
-/// instruction indirect jump
-pub fn relay_builder(_target: *const (), detour: *const ()) -> Result<Option<pic::CodeEmitter>> {
+/// Returns the prolog size required to patch the target with an absolute
+/// jump, used if no memory is available within `PAGE_DETOUR_RANGE`.
+pub fn absolute_prolog_margin(_target: *const ()) -> usize {
+  16
+}
+
+/// The offset of the destination slot within a relay.
+pub const RELAY_SLOT_OFFSET: usize = 0;
+
+/// The offset of the entry within a relay.
+pub const RELAY_ENTRY_OFFSET: usize = 8;
+
+/// Creates a relay, jumping to the destination stored in its slot.
+///
+/// The slot precedes the entry, so it's aligned and can be written atomically.
+pub fn relay_builder(_target: *const (), detour: *const ()) -> Result<pic::CodeEmitter> {
+  let mut emitter = pic::CodeEmitter::new();
+  emitter.add_thunk(Box::new(thunk::thunk_dynasm!(
+    ; slot:
+    ; .qword detour as _
+    ; ldr x17, <slot
+    ; br x17
+  )));
+  Ok(emitter)
+}
+
+/// The offset of the entry within a switching relay.
+pub const SWITCH_RELAY_ENTRY_OFFSET: usize = RELAY_ENTRY_OFFSET;
+
+/// Creates a relay that jumps to the destination stored in its slot if `flag`
+/// is set, otherwise to the trampoline.
+///
+/// The target must be redirected to the relay's entry, rather than its start.
+pub fn switch_relay_builder(
+  _target: *const (),
+  detour: *const (),
+  trampoline: *const (),
+  flag: *const AtomicBool,
+) -> Result<pic::CodeEmitter> {
   let mut emitter = pic::CodeEmitter::new();
   emitter.add_thunk(Box::new(thunk::thunk_dynasm!(
+    ; slot:
     ; .qword detour as _
+    ; ldr x17, >flag_literal
+    ; ldrb w17, [x17]
+    ; cbz w17, >disabled
+    ; ldr x17, <slot
+    ; br x17
+    ; disabled:
+    ; ldr x17, >trampoline_literal
+    ; br x17
+    ; .align 8
+    ; flag_literal:
+    ; .qword flag as _
+    ; trampoline_literal:
+    ; .qword trampoline as _
   )));
-  Ok(Some(emitter))
+  Ok(emitter)
+}
+
+/// Creates a stub that captures the thread context and invokes `handler`.
+pub fn context_stub_builder(state: *const (), handler: usize) -> pic::CodeEmitter {
+  let mut emitter = pic::CodeEmitter::new();
+  emitter.add_thunk(Box::new(context::stub(state as usize, handler, false)));
+  emitter
+}
+
+/// Creates a stub that functions return to, which captures the thread context
+/// and invokes `handler`.
+pub fn return_stub_builder(state: *const (), handler: usize) -> pic::CodeEmitter {
+  let mut emitter = pic::CodeEmitter::new();
+  emitter.add_thunk(Box::new(context::stub(state as usize, handler, true)));
+  emitter
+}
+
+/// Creates a function that invokes another function with a thread context.
+pub fn invoker_builder() -> pic::CodeEmitter {
+  let mut emitter = pic::CodeEmitter::new();
+  emitter.add_thunk(Box::new(context::invoker()));
+  emitter
 }
 
 fn imm_to_signed(imm: &Imm) -> i64 {
@@ -54,6 +146,22 @@ pub unsafe fn skip_jmps(target: *const ()) -> *const () {
     .unwrap_or(target)
 }
 
+/// Returns the length and destination of a call instruction (i.e `bl`).
+pub unsafe fn decode_call(address: *const ()) -> Result<(usize, *const ())> {
+  let code = std::slice::from_raw_parts(address as *const u8, 4);
+  let instruction = bad64::disasm(code, address as u64)
+    .next()
+    .and_then(|result| result.ok())
+    .ok_or(Error::InvalidCode)?;
+
+  match instruction.operands() {
+    [Operand::Label(destination)] if instruction.op() == Op::BL => {
+      Ok((4, imm_to_signed(destination) as usize as *const ()))
+    },
+    _ => Err(Error::UnsupportedInstruction),
+  }
+}
+
 /// Skip potential jumps to import functions
 fn skip_import_jmp(code: &[u8], address: u64) -> Option<u64> {
   let instructions: Vec<_> = bad64::disasm(code, address).collect();
@@ -108,43 +216,3 @@ pub unsafe fn clear_instruction_cache(code: &[u8]) {
   }
   __clear_cache(code.as_ptr(), code.as_ptr().add(code.len()));
 }
-
-#[cfg(test)]
-mod tests {
-  use super::*;
-
-  // #[test]
-  // fn test_find_jmp() {
-  //   #[naked]
-  //   unsafe extern "C" fn import_func() {
-  //     asm!(
-  //       "adrp x16, label",
-  //       "ldr x16, [x16, :lo12:label]",
-  //       "br x16",
-  //       "nop",
-  //       "label:",
-  //       "nop",
-  //       options(noreturn)
-  //     )
-  //   }
-
-  //   let import_func_addr = import_func as *const () as u64;
-  //   let mem = unsafe { std::slice::from_raw_parts(import_func as *const u8,
-  // 12) };   let real_import_addr = skip_import_jmp(mem, import_func_addr);
-  //   // Label is 4 instructions from the start of the function
-  //   assert_eq!(real_import_addr, Some(import_func_addr + 4 * 4));
-  // }
-
-  // #[test]
-  // fn test_find_jmp2() {
-  //   let func = [
-  //     0x50u8, 0x3F, 0x00, 0xB0, 0x10, 0x7A, 0x41, 0xF9, 0x00, 0x02, 0x1F,
-  // 0xD6,   ];
-
-  //   let mem = unsafe { std::slice::from_raw_parts(&func as *const u8, 12) };
-
-  //   let import_addr = skip_import_jmp(mem, 0x101B93A78);
-
-  //   assert_eq!(import_addr, Some(0x10237C2F0));
-  // }
-}
@@ -1,12 +1,25 @@
-pub use self::patcher::Patcher;
+// Only relocation is used on other hosts
+#![cfg_attr(not(target_arch = "aarch64"), allow(dead_code))]
 pub use self::trampoline::Trampoline;
 
-pub mod meta;
-mod patcher;
+use cfg_if::cfg_if;
+
+cfg_if! {
+    if #[cfg(target_arch = "aarch64")] {
+        pub use self::context::CpuContext;
+        pub use self::patcher::Patcher;
+
+        pub mod clone;
+        mod context;
+        pub mod meta;
+        mod patcher;
+    }
+}
+
 mod thunk;
 mod trampoline;
 
-#[cfg(all(feature = "nightly", test))]
+#[cfg(all(feature = "nightly", test, target_arch = "aarch64"))]
 mod tests {
   use super::*;
   use crate::*;
@@ -80,6 +93,121 @@ mod tests {
     unsafe { detour_test(mem::transmute(branch_ret as usize), value) }
   }
 
+  #[test]
+  fn detour_single_branch() {
+    #[naked]
+    unsafe extern "C" fn branch_ret() -> usize {
+      asm!("mov x0, #7", "nop", "ret", options(noreturn))
+    }
+
+    unsafe {
+      let target = branch_ret as *const u32;
+      let original = std::slice::from_raw_parts(target, 3).to_vec();
+      let hook = RawDetour::new(target as *const (), ret10 as *const ()).unwrap();
+
+      // Only the first instruction is replaced, with a `b` to the relay
+      hook.enable().unwrap();
+      assert_eq!(*target & 0xFC00_0000, 0x1400_0000);
+      assert_eq!(std::slice::from_raw_parts(target.add(1), 2), &original[1..]);
+      assert_eq!(branch_ret(), 10);
+
+      hook.disable().unwrap();
+      assert_eq!(std::slice::from_raw_parts(target, 3), &original[..]);
+      assert_eq!(branch_ret(), 7);
+    }
+  }
+
+  #[test]
+  fn detour_page_branch() {
+    use super::meta::{DETOUR_RANGE, PAGE_DETOUR_RANGE};
+    use std::sync::Arc;
+
+    /// An allocator without any memory within reach of a `b`.
+    struct DistantAllocator(ProximityAllocator);
+
+    unsafe impl ExecutableAllocator for DistantAllocator {
+      fn allocate_near(
+        &self,
+        origin: *const (),
+        size: usize,
+        max_distance: usize,
+      ) -> Result<ExecutableBlock> {
+        if max_distance <= DETOUR_RANGE {
+          Err(Error::OutOfMemory)?;
+        }
+
+        let origin = (origin as usize + DETOUR_RANGE * 4) as *const ();
+        self.0.allocate_near(origin, size, DETOUR_RANGE)
+      }
+
+      unsafe fn release(&self, block: ExecutableBlock) {
+        self.0.release(block)
+      }
+    }
+
+    #[naked]
+    unsafe extern "C" fn branch_ret() -> usize {
+      asm!("mov x0, #7", "nop", "nop", "ret", options(noreturn))
+    }
+
+    unsafe {
+      let target = branch_ret as *const u32;
+      let original = std::slice::from_raw_parts(target, 4).to_vec();
+      let allocator = Arc::new(DistantAllocator(ProximityAllocator::new(0x10000)));
+      let hook =
+        RawDetour::with_allocator(target as *const (), ret10 as *const (), allocator).unwrap();
+
+      let distance = (hook.trampoline() as *const () as isize - target as isize).unsigned_abs();
+      assert!(distance > DETOUR_RANGE && distance <= PAGE_DETOUR_RANGE);
+
+      // The first three instructions are replaced, with an `adrp`, `add` & `br`
+      hook.enable().unwrap();
+      assert_eq!(*target & 0x9F00_0000, 0x9000_0000);
+      assert_eq!(*target.add(3), original[3]);
+      assert_eq!(branch_ret(), 10);
+
+      hook.disable().unwrap();
+      assert_eq!(std::slice::from_raw_parts(target, 4), &original[..]);
+      assert_eq!(branch_ret(), 7);
+    }
+  }
+
+  #[test]
+  fn detour_call_site() {
+    use crate::CallSiteDetour;
+
+    #[naked]
+    unsafe extern "C" fn call_ret7() -> usize {
+      asm!(
+        "stp x29, x30, [sp, #-16]!",
+        "bl {}",
+        "ldp x29, x30, [sp], #16",
+        "ret",
+        sym ret7,
+        options(noreturn)
+      )
+    }
+
+    unsafe extern "C" fn ret7() -> usize {
+      7
+    }
+
+    unsafe {
+      let call = (call_ret7 as usize + 4) as *const u32;
+      let hook = CallSiteDetour::new(call as *const (), ret10 as *const ()).unwrap();
+      assert_eq!(hook.original() as *const (), ret7 as *const ());
+
+      // The `bl` is replaced by another `bl`, to the detour
+      hook.enable().unwrap();
+      assert_eq!(*call & 0xFC00_0000, 0x9400_0000);
+      assert_eq!(call_ret7(), 10);
+      assert_eq!(ret7(), 7);
+
+      hook.disable().unwrap();
+      assert_eq!(call_ret7(), 7);
+    }
+  }
+
   /// Default detour target.
   unsafe extern "C" fn ret10() -> usize {
     10
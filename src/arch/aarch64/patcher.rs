@@ -1,7 +1,7 @@
 use super::meta;
 use super::thunk;
-use crate::error::Result;
-use crate::pic;
+use crate::error::{Error, Result};
+use crate::{pic, util};
 use std::slice;
 
 pub struct Patcher {
@@ -18,42 +18,104 @@ impl Patcher {
   /// * `target` - An address that should be hooked.
   /// * `detour` - An address that the target should be redirected to.
   /// * `prolog_size` - The available inline space for the hook.
-  pub unsafe fn new(target: *const (), detour: *const (), prolog_size: usize) -> Result<Patcher> {
-    let patch_area = slice::from_raw_parts_mut(target as *mut u8, prolog_size);
+  /// * `_trampoline` - An address equivalent to the original target (unused,
+  ///   since a single instruction is patched atomically).
+  pub unsafe fn new(
+    target: *const (),
+    detour: *const (),
+    prolog_size: usize,
+    _trampoline: *const (),
+  ) -> Result<Patcher> {
+    // A single `b` is used if possible, since it's written atomically
+    let emitter = if Self::is_within_reach(target, detour) {
+      Self::hook_template(thunk::gen_branch(detour as usize))
+    } else if Self::is_within_page_reach(target, detour)
+      && prolog_size >= meta::page_prolog_margin(target)
+    {
+      Self::hook_template(thunk::gen_jmp_page(detour as usize))
+    } else if prolog_size >= meta::absolute_prolog_margin(target) {
+      Self::hook_template(thunk::gen_jmp_immediate(detour as usize))
+    } else {
+      Err(Error::NoPatchArea)?
+    };
 
+    let patch_area = slice::from_raw_parts_mut(target as *mut u8, emitter.len());
     let original_prolog = patch_area.to_vec();
+
     Ok(Patcher {
       original_prolog,
-      detour_prolog: Self::hook_template(detour).emit(target),
+      detour_prolog: emitter.emit(target),
+      patch_area,
+    })
+  }
+
+  /// Creates a patcher for a call instruction, redirecting it to `destination`.
+  ///
+  /// # Arguments
+  ///
+  /// * `call` - The address of the call instruction (i.e `bl`).
+  /// * `_call_size` - The size of the call instruction (always 4).
+  /// * `destination` - An address, within reach of a `bl` instruction.
+  /// * `_trampoline` - Unused, since the call is patched atomically.
+  pub unsafe fn with_call(
+    call: *const (),
+    _call_size: usize,
+    destination: *const (),
+    _trampoline: *const (),
+  ) -> Result<Patcher> {
+    if !Self::is_within_reach(call, destination) {
+      Err(Error::NoPatchArea)?;
+    }
+
+    let emitter = Self::hook_template(thunk::gen_branch_link(destination as usize));
+    let patch_area = slice::from_raw_parts_mut(call as *mut u8, emitter.len());
+
+    Ok(Patcher {
+      original_prolog: patch_area.to_vec(),
+      detour_prolog: emitter.emit(call),
       patch_area,
     })
   }
 
-  /// Returns the target's patch area.
-  pub fn area(&self) -> &[u8] {
-    self.patch_area
+  /// Returns whether the patch area contains the detour's patch.
+  pub fn is_patched(&self) -> bool {
+    *self.patch_area == *self.detour_prolog
+  }
+
+  /// Returns whether the detour can be reached using a `b` instruction.
+  fn is_within_reach(target: *const (), detour: *const ()) -> bool {
+    let displacement = (detour as isize).wrapping_sub(target as isize);
+    (-(thunk::BRANCH_RANGE as isize)..thunk::BRANCH_RANGE as isize).contains(&displacement)
   }
 
-  fn hook_template(detour: *const ()) -> pic::CodeEmitter {
+  /// Returns whether the detour's page can be reached using an `adrp`
+  /// instruction.
+  fn is_within_page_reach(target: *const (), detour: *const ()) -> bool {
+    let displacement = (detour as isize & !0xFFF).wrapping_sub(target as isize & !0xFFF);
+    (-(thunk::PAGE_RANGE as isize)..thunk::PAGE_RANGE as isize).contains(&displacement)
+  }
+
+  fn hook_template(thunk: Box<dyn pic::Thunkable>) -> pic::CodeEmitter {
     let mut emitter = pic::CodeEmitter::new();
-    emitter.add_thunk(thunk::gen_jmp_indirect(detour as usize));
+    emitter.add_thunk(thunk);
     emitter
   }
 
   /// Either patches or unpatches the function.
-  pub unsafe fn toggle(&mut self, enable: bool) {
-    println!("enable {}", enable);
-    // Copy either the detour or the original bytes of the function
-    self.patch_area.copy_from_slice(if enable {
-      &self.detour_prolog
-    } else {
-      &self.original_prolog
-    });
+  pub unsafe fn toggle(&mut self, enable: bool) -> Result<()> {
+    // Copy either the detour or the original bytes of the function; a single
+    // aligned instruction (i.e a `b`) is stored atomically, so it's never
+    // observed torn, unlike the longer page relative and absolute jumps
+    util::write_code(
+      self.patch_area.as_ptr(),
+      if enable {
+        &self.detour_prolog
+      } else {
+        &self.original_prolog
+      },
+    )?;
 
     meta::clear_instruction_cache(self.patch_area);
-
-    // let  instructions: Vec<_> =
-    //   bad64::disasm(self.patch_area, self.patch_area.as_ptr() as
-    // u64).collect(); dbg!(&instructions);
+    Ok(())
   }
 }
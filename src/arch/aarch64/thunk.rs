@@ -1,8 +1,5 @@
-use super::meta;
-use crate::error::Result;
 use crate::pic::{self, FixedThunk};
-use bad64::{Imm, Instruction, Op, Operand, Reg};
-use dynasmrt::{dynasm, DynasmLabelApi};
+use dynasmrt::DynasmLabelApi;
 use generic_array::{typenum, GenericArray};
 
 macro_rules! thunk_dynasm {
@@ -18,9 +15,10 @@ macro_rules! thunk_dynasm {
       buf.deref().to_vec()
     }}
   }
+#[cfg(target_arch = "aarch64")]
 pub(crate) use thunk_dynasm;
 
-// Generate a branch to an absolute address. Takes 4 + 4 + 8 = 16 instructions
+// Generate a branch to an absolute address. Takes 4 + 4 + 8 = 16 bytes
 pub fn gen_jmp_immediate(target: usize) -> Box<dyn pic::Thunkable> {
   Box::new(thunk_dynasm!(
     ; ldr x17, >target
@@ -30,130 +28,141 @@ pub fn gen_jmp_immediate(target: usize) -> Box<dyn pic::Thunkable> {
   ))
 }
 
-// Generate a branch to an address loaded from a memory location located
-// relative to the instruction address. Takes 4 + 4 + 4 = 12 instructions
-pub fn gen_jmp_indirect(detour_value: usize) -> Box<dyn pic::Thunkable> {
-  // Ensure address is aligned - dynasm will gladly truncate the page_off without
-  // warning
-  assert!(detour_value % 8 == 0);
+/// The reach of a `b` instruction (±128 MiB).
+pub const BRANCH_RANGE: usize = 0x800_0000;
+
+/// The size of a page, as referred to by `adrp`.
+pub const PAGE_SIZE: usize = 0x1000;
+
+/// The reach of an `adrp` instruction (±4 GiB).
+pub const PAGE_RANGE: usize = 0x1_0000_0000;
+
+// Generate a relative branch to an address within `BRANCH_RANGE`. Takes 4
+// bytes, so it can be written atomically.
+pub fn gen_branch(destination: usize) -> Box<dyn pic::Thunkable> {
+  relative_branch(destination, false)
+}
+
+// Generate a relative call (i.e `bl`) to an address within `BRANCH_RANGE`.
+// Takes 4 bytes, so it can be written atomically.
+pub fn gen_branch_link(destination: usize) -> Box<dyn pic::Thunkable> {
+  relative_branch(destination, true)
+}
+
+// Generate a branch to an address within `PAGE_RANGE`, relative to the page of
+// the instruction. Takes 4 + 4 + 4 = 12 bytes
+pub fn gen_jmp_page(destination: usize) -> Box<dyn pic::Thunkable> {
   Box::new(FixedThunk::<typenum::U12>::new(move |pc| {
-    let page = (detour_value & !0xfff) as isize - (pc & !0xfff) as isize;
-    let page_off = (detour_value & 0xfff) as u32;
+    let page = (destination & !0xfff) as isize - (pc & !0xfff) as isize;
+    assert!((-(PAGE_RANGE as isize)..PAGE_RANGE as isize).contains(&page));
+
     GenericArray::clone_from_slice(&thunk_dynasm!(
       ; adrp x17, page
-      ; ldr x17, [x17, page_off]
+      ; add x17, x17, (destination & 0xfff) as u32
       ; br x17
     ))
   }))
 }
 
-pub fn gen_adr(instruction: &Instruction) -> Result<Box<dyn pic::Thunkable>> {
-  assert_eq!(instruction.op(), Op::ADR);
-  Ok(match instruction.operands() {
-    [Operand::Reg { reg, arrspec: None }, Operand::Label(Imm::Unsigned(target))] => {
-      let target = *target as usize;
-      let reg = *reg;
-      Box::new(unsafe {
-        pic::UnsafeThunk::new(
-          move |dest| {
-            let delta = target as isize - dest as isize;
-            let delta_page =
-              (target / meta::PAGE_SIZE) as isize - (dest / meta::PAGE_SIZE) as isize;
-            let page = (target & !0xfff) as isize - (dest & !0xfff) as isize;
-
-            let max_range = bit_range(20);
-            if max_range.contains(&delta) {
-              thunk_dynasm!(
-                ; adr X(reg_no(reg).unwrap()), delta
-              )
-            } else if max_range.contains(&delta_page) {
-              thunk_dynasm!(
-                  ; adrp X(reg_no(reg).unwrap()), page
-                  ; add X(reg_no(reg).unwrap()), X(reg_no(reg).unwrap()), (target & 0xFFF) as u32
-              )
-            } else {
-              // TODO: handle 32bit register type
-              gen_mov_64(reg, target)
-            }
-          },
-          4 * 4,
-        )
-      })
-    },
-    _ => unimplemented!(),
+/// Constructs either a `b` or `bl` instruction.
+fn relative_branch(destination: usize, link: bool) -> Box<dyn pic::Thunkable> {
+  const B: u32 = 0x1400_0000;
+  const BL: u32 = 0x9400_0000;
+
+  Box::new(FixedThunk::<typenum::U4>::new(move |pc| {
+    let displacement = destination as isize - pc as isize;
+    assert!(displacement % 4 == 0);
+    assert!((-(BRANCH_RANGE as isize)..BRANCH_RANGE as isize).contains(&displacement));
+
+    // B/BL imm26, where the immediate is the displacement in instructions
+    let opcode = (if link { BL } else { B }) | ((displacement >> 2) as u32 & 0x03FF_FFFF);
+    GenericArray::clone_from_slice(&opcode.to_le_bytes())
+  }))
+}
+
+/// Generates an `adr` of `target` into the X register `reg`.
+pub fn gen_adr(reg: u32, target: usize) -> Box<dyn pic::Thunkable> {
+  Box::new(unsafe {
+    pic::UnsafeThunk::new(
+      move |dest| {
+        let delta = target as isize - dest as isize;
+        let delta_page = (target / PAGE_SIZE) as isize - (dest / PAGE_SIZE) as isize;
+        let page = (target & !0xfff) as isize - (dest & !0xfff) as isize;
+
+        let max_range = bit_range(20);
+        if max_range.contains(&delta) {
+          thunk_dynasm!(
+            ; adr X(reg), delta
+          )
+        } else if max_range.contains(&delta_page) {
+          thunk_dynasm!(
+              ; adrp X(reg), page
+              ; add X(reg), X(reg), (target & 0xFFF) as u32
+          )
+        } else {
+          // TODO: handle 32bit register type
+          gen_mov_64(reg, target)
+        }
+      },
+      4 * 4,
+    )
   })
 }
 
-pub fn gen_adrp(instruction: &Instruction) -> Result<Box<dyn pic::Thunkable>> {
-  assert_eq!(instruction.op(), Op::ADRP);
-  Ok(match instruction.operands() {
-    [Operand::Reg { reg, arrspec: None }, Operand::Label(Imm::Unsigned(target))] => {
-      let target = *target as usize;
-      let reg = *reg;
-      Box::new(unsafe {
-        pic::UnsafeThunk::new(
-          move |dest| {
-            let delta_page =
-              (target / meta::PAGE_SIZE) as isize - (dest / meta::PAGE_SIZE) as isize;
-            let page = (target & !0xfff) as isize - (dest & !0xfff) as isize;
-            let max_range = bit_range(20);
-            if max_range.contains(&page) {
-              thunk_dynasm!(
-                  ; adrp X(reg_no(reg).unwrap()), delta_page
-              )
-            } else {
-              // TODO: handle 32bit register type
-              gen_mov_64(reg, target)
-            }
-          },
-          4 * 4,
-        )
-      })
-    },
-    _ => unimplemented!(),
+/// Generates an `adrp` of the page `target` into the X register `reg`.
+pub fn gen_adrp(reg: u32, target: usize) -> Box<dyn pic::Thunkable> {
+  Box::new(unsafe {
+    pic::UnsafeThunk::new(
+      move |dest| {
+        let delta_page = (target / PAGE_SIZE) as isize - (dest / PAGE_SIZE) as isize;
+        let page = (target & !0xfff) as isize - (dest & !0xfff) as isize;
+        let max_range = bit_range(20);
+        if max_range.contains(&delta_page) {
+          thunk_dynasm!(
+              ; adrp X(reg), page
+          )
+        } else {
+          // TODO: handle 32bit register type
+          gen_mov_64(reg, target)
+        }
+      },
+      4 * 4,
+    )
   })
 }
 
-pub fn gen_ldr_literal(instruction: &Instruction) -> Result<Box<dyn pic::Thunkable>> {
-  assert_eq!(instruction.op(), Op::LDR);
-  Ok(match instruction.operands() {
-    [Operand::Reg { reg, arrspec: None }, Operand::Label(Imm::Unsigned(target))] => {
-      let target = *target as usize;
-      let reg = *reg;
-      Box::new(unsafe {
-        pic::UnsafeThunk::new(
-          move |dest| {
-            let delta = target as isize - dest as isize;
-            let max_range = bit_range(21);
-            if max_range.contains(&delta) {
-              // TODO: support 32 bit target register
-              // TODO: support fp neon target register
-              thunk_dynasm!(
-                  ; ldr X(reg_no(reg).unwrap()), delta
-              )
-            } else {
-              // Store address in temporary register, then load
-              // TODO: support 32 bit target register
-              let reg = reg_no(reg).unwrap();
-              thunk_dynasm!(
-                  ; movk X(17), (target & 0xFFFF) as u32, LSL 0
-                  ; movk X(17), ((target >> 16) & 0xFFFF) as u32, LSL 16
-                  ; movk X(17), ((target >> 32) & 0xFFFF) as u32, LSL 32
-                  ; movk X(17), ((target >> 48) & 0xFFFF) as u32, LSL 48
-                  ; ldr X(reg), [X(17)]
-              )
-            }
-          },
-          5 * 4,
-        )
-      })
-    },
-    _ => unimplemented!(),
+/// Generates a `ldr` (literal) of the value at `target` into the X register
+/// `reg`.
+pub fn gen_ldr_literal(reg: u32, target: usize) -> Box<dyn pic::Thunkable> {
+  Box::new(unsafe {
+    pic::UnsafeThunk::new(
+      move |dest| {
+        let delta = target as isize - dest as isize;
+        let max_range = bit_range(20);
+        if max_range.contains(&delta) {
+          // TODO: support 32 bit target register
+          // TODO: support fp neon target register
+          thunk_dynasm!(
+              ; ldr X(reg), delta
+          )
+        } else {
+          // Store address in temporary register, then load
+          // TODO: support 32 bit target register
+          thunk_dynasm!(
+              ; movk X(17), (target & 0xFFFF) as u32, LSL 0
+              ; movk X(17), ((target >> 16) & 0xFFFF) as u32, LSL 16
+              ; movk X(17), ((target >> 32) & 0xFFFF) as u32, LSL 32
+              ; movk X(17), ((target >> 48) & 0xFFFF) as u32, LSL 48
+              ; ldr X(reg), [X(17)]
+          )
+        }
+      },
+      5 * 4,
+    )
   })
 }
 
-pub fn gen_mov_64(reg: Reg, value: usize) -> Vec<u8> {
-  let reg = reg_no(reg).unwrap();
+pub fn gen_mov_64(reg: u32, value: usize) -> Vec<u8> {
   thunk_dynasm!(
       ; movk X(reg), (value & 0xFFFF) as u32, LSL 0
       ; movk X(reg), ((value >> 16) & 0xFFFF) as u32, LSL 16
@@ -167,40 +176,3 @@ fn bit_range(bits: u8) -> std::ops::Range<isize> {
   let max_val = 1isize << bits;
   -max_val..max_val
 }
-
-fn reg_no(reg: Reg) -> Option<u32> {
-  Some(match reg {
-    Reg::X0 => 0,
-    Reg::X1 => 1,
-    Reg::X2 => 2,
-    Reg::X3 => 3,
-    Reg::X4 => 4,
-    Reg::X5 => 5,
-    Reg::X6 => 6,
-    Reg::X7 => 7,
-    Reg::X8 => 8,
-    Reg::X9 => 9,
-    Reg::X10 => 10,
-    Reg::X11 => 11,
-    Reg::X12 => 12,
-    Reg::X13 => 13,
-    Reg::X14 => 14,
-    Reg::X15 => 15,
-    Reg::X16 => 16,
-    Reg::X17 => 17,
-    Reg::X18 => 18,
-    Reg::X19 => 19,
-    Reg::X20 => 20,
-    Reg::X21 => 21,
-    Reg::X22 => 22,
-    Reg::X23 => 23,
-    Reg::X24 => 24,
-    Reg::X25 => 25,
-    Reg::X26 => 26,
-    Reg::X27 => 27,
-    Reg::X28 => 28,
-    Reg::X29 => 29,
-    Reg::X30 => 30,
-    _ => return None,
-  })
-}
@@ -1,9 +1,9 @@
-use super::{meta, thunk};
+use super::thunk;
 use crate::error::{Error, Result};
 use crate::pic;
-use bad64::{Instruction, Op, Operand};
+use std::slice;
 
-/// A trampoline generator (x86/x64).
+/// A trampoline generator (AArch64).
 pub struct Trampoline {
   emitter: pic::CodeEmitter,
   prolog_size: usize,
@@ -12,7 +12,25 @@ pub struct Trampoline {
 impl Trampoline {
   /// Constructs a new trampoline for an address.
   pub unsafe fn new(target: *const (), margin: usize) -> Result<Trampoline> {
-    Builder::new(target, margin).build()
+    // Instructions are fixed-width, so only the margin is read
+    let prolog = slice::from_raw_parts(target as *const u8, (margin + 3) / 4 * 4);
+    Self::from_code(prolog, target as usize, margin)
+  }
+
+  /// Constructs a trampoline for an address, which can be located at any
+  /// distance from it.
+  ///
+  /// Relocated instructions already fall back to absolute addressing when out
+  /// of range, so this is equivalent to `new`.
+  pub unsafe fn with_absolute_operands(target: *const (), margin: usize) -> Result<Trampoline> {
+    Self::new(target, margin)
+  }
+
+  /// Constructs a trampoline for a buffer of code, located at `address`.
+  ///
+  /// The code does not need to be mapped, nor belong to the host.
+  pub fn from_code(code: &[u8], address: usize, margin: usize) -> Result<Trampoline> {
+    Builder::new(code, address, margin).build()
   }
 
   /// Returns a reference to the trampoline's code emitter.
@@ -27,28 +45,22 @@ impl Trampoline {
 }
 
 /// A trampoline builder.
-struct Builder {
-  // /// Disassembler for x86/x64.
-  // disassembler: Disassembler,
-  // /// Target destination for a potential internal branch.
-  // branch_address: Option<usize>,
-  /// Total amount of bytes disassembled.
-  // total_bytes_disassembled: usize,
+struct Builder<'a> {
+  /// The code being relocated.
+  code: &'a [u8],
   /// The preferred minimum amount of bytes disassembled.
   margin: usize,
   /// Whether disassembling has finished or not.
   finished: bool,
-  /// The target the trampoline is adapted for.
-  target: *const (),
+  /// The address the trampoline is adapted for.
+  target: usize,
 }
 
-impl Builder {
+impl<'a> Builder<'a> {
   /// Returns a trampoline builder.
-  pub fn new(target: *const (), margin: usize) -> Self {
+  pub fn new(code: &'a [u8], target: usize, margin: usize) -> Self {
     Builder {
-      // disassembler: Disassembler::new(target),
-      // branch_address: None,
-      // total_bytes_disassembled: 0,
+      code,
       finished: false,
       target,
       margin,
@@ -57,29 +69,27 @@ impl Builder {
 
   /// Creates a trampoline with the supplied settings.
   ///
-  /// Margins larger than five bytes may lead to undefined behavior.
+  /// The margin is rounded up to whole instructions, and may be that of any
+  /// patch (4, 12 or 16 bytes). An unconditional `b` ends the prolog, and any
+  /// other relative branch within it is unsupported.
   pub fn build(mut self) -> Result<Trampoline> {
-    let mem = unsafe { std::slice::from_raw_parts(self.target as *const u8, self.margin + 4) };
-    let mut instructions = bad64::disasm(mem, self.target as u64);
-
     let mut emitter = pic::CodeEmitter::new();
 
-    // log::debug!("original moved instructions:");
     let mut bytes_disassembled = 0;
     while !self.finished {
-      let instruction = instructions
-        .next()
-        .and_then(|r| r.ok())
+      let opcode = self
+        .code
+        .get(bytes_disassembled..bytes_disassembled + 4)
+        .map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
         .ok_or(Error::InvalidCode)?;
+      let address = self.target + bytes_disassembled;
       bytes_disassembled += 4;
 
-      // log::debug!("{}", instruction);
-
-      let thunk = self.copy_instruction(&instruction)?;
+      let thunk = self.copy_instruction(opcode, address)?;
       emitter.add_thunk(thunk);
 
       // Function ends here
-      if self.instruction_ends_code(&instruction) {
+      if self.instruction_ends_code(opcode) {
         self.finished = true;
       }
 
@@ -87,11 +97,7 @@ impl Builder {
       // function
       if bytes_disassembled >= self.margin && !self.finished {
         self.finished = true;
-        let next_instruction = instructions
-          .next()
-          .and_then(|r| r.ok())
-          .ok_or(Error::InvalidCode)?;
-        emitter.add_thunk(thunk::gen_jmp_immediate(next_instruction.address() as usize));
+        emitter.add_thunk(thunk::gen_jmp_immediate(self.target + bytes_disassembled));
       }
     }
 
@@ -103,25 +109,57 @@ impl Builder {
 
   // Copy the instruction into a position-independant thunk, or one that will
   // generate the correct code for the offset
-  fn copy_instruction(&mut self, instruction: &Instruction) -> Result<Box<dyn pic::Thunkable>> {
-    Ok(match instruction.op() {
-      // Instruction relative load instructions
-      Op::LDR if matches!(instruction.operands().get(1), Some(Operand::Label(_))) => {
-        thunk::gen_ldr_literal(instruction)?
+  fn copy_instruction(&mut self, opcode: u32, address: usize) -> Result<Box<dyn pic::Thunkable>> {
+    let register = opcode & 0x1F;
+
+    Ok(match opcode {
+      // Instruction relative load instructions (only `ldr Xt, label`)
+      _ if opcode & 0xFF00_0000 == 0x5800_0000 => {
+        let target = address.wrapping_add((sign_extend(opcode >> 5, 19) << 2) as usize);
+        thunk::gen_ldr_literal(register, target)
+      },
+      _ if opcode & 0x9F00_0000 == 0x1000_0000 => {
+        let target = address.wrapping_add(sign_extend(adr_immediate(opcode), 21) as usize);
+        thunk::gen_adr(register, target)
+      },
+      _ if opcode & 0x9F00_0000 == 0x9000_0000 => {
+        let page = (sign_extend(adr_immediate(opcode), 21) << 12) as usize;
+        thunk::gen_adrp(register, (address & !0xFFF).wrapping_add(page))
       },
-      Op::ADR => thunk::gen_adr(instruction)?,
-      Op::ADRP => thunk::gen_adrp(instruction)?,
       // Branching instructions
-      op if meta::CONDITIONAL_OPS.contains(&op) => unimplemented!(),
-      Op::B | Op::BL => unimplemented!(),
-      Op::CBZ | Op::CBNZ => unimplemented!(),
-      Op::TBZ | Op::TBNZ => unimplemented!(),
+      _ if opcode & 0xFC00_0000 == 0x1400_0000 => {
+        let target = address.wrapping_add((sign_extend(opcode, 26) << 2) as usize);
+        thunk::gen_jmp_immediate(target)
+      },
+      // Any other PC relative instructions (i.e `bl`, `b.cond`, `cbz`,
+      // `tbz`, and other literal loads)
+      _ if opcode & 0x7C00_0000 == 0x1400_0000
+        || opcode & 0xFF00_0010 == 0x5400_0000
+        || opcode & 0x7C00_0000 == 0x3400_0000
+        || opcode & 0x3B00_0000 == 0x1800_0000 =>
+      {
+        Err(Error::UnsupportedInstruction)?
+      },
       // Plainly copy all other instructions
-      _ => Box::new(instruction.opcode().to_le_bytes().to_vec()),
+      _ => Box::new(opcode.to_le_bytes().to_vec()),
     })
   }
 
-  fn instruction_ends_code(&mut self, instruction: &Instruction) -> bool {
-    matches!(instruction.op(), Op::RET | Op::B | Op::BR)
+  fn instruction_ends_code(&mut self, opcode: u32) -> bool {
+    const RET: u32 = 0xD65F_0000;
+    const BR: u32 = 0xD61F_0000;
+
+    // B imm26, or RET/BR with any register
+    opcode & 0xFC00_0000 == 0x1400_0000 || opcode & 0xFFFF_FC1F == RET || opcode & 0xFFFF_FC1F == BR
   }
 }
+
+/// Returns the immediate of an `adr` or `adrp` (i.e immhi:immlo).
+fn adr_immediate(opcode: u32) -> u32 {
+  (((opcode >> 5) & 0x7_FFFF) << 2) | ((opcode >> 29) & 0b11)
+}
+
+/// Sign extends the lower `bits` of a value.
+fn sign_extend(value: u32, bits: u32) -> isize {
+  ((value << (32 - bits)) as i32 >> (32 - bits)) as isize
+}
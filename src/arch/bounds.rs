@@ -0,0 +1,222 @@
+//! Resolution of a function's size, using its symbol or its unwind info.
+use std::{mem, ptr};
+
+/// Returns the size of a function in bytes, if it can be determined.
+///
+/// The function's dynamic symbol is preferred, but since only exported
+/// functions have one, the size is otherwise derived from the function's
+/// call frame information (i.e `.eh_frame`).
+pub unsafe fn function_size(function: *const ()) -> Option<usize> {
+  symbol_size(function).or_else(|| unwind_size(function))
+}
+
+/// Returns whether an address is within the bounds of its nearest dynamic
+/// symbol, rather than between symbols.
+pub unsafe fn is_within_symbol(address: *const ()) -> bool {
+  let mut info: libc::Dl_info = mem::zeroed();
+  if libc::dladdr(address as *const _, &mut info) == 0 || info.dli_saddr.is_null() {
+    return false;
+  }
+
+  let start = info.dli_saddr as usize;
+  matches!(symbol_size(start as *const ()), Some(size) if (address as usize) < start + size)
+}
+
+/// Returns the size of a function's dynamic symbol.
+#[cfg(target_env = "gnu")]
+unsafe fn symbol_size(function: *const ()) -> Option<usize> {
+  /// Requests the symbol's entry from `dladdr1`.
+  const RTLD_DL_SYMENT: libc::c_int = 1;
+
+  /// The ELF symbol table entry.
+  #[cfg(target_pointer_width = "64")]
+  #[allow(dead_code)]
+  #[repr(C)]
+  struct Symbol {
+    name: u32,
+    info: u8,
+    other: u8,
+    section: u16,
+    value: u64,
+    size: u64,
+  }
+
+  /// The ELF symbol table entry.
+  #[cfg(target_pointer_width = "32")]
+  #[allow(dead_code)]
+  #[repr(C)]
+  struct Symbol {
+    name: u32,
+    value: u32,
+    size: u32,
+    info: u8,
+    other: u8,
+    section: u16,
+  }
+
+  extern "C" {
+    fn dladdr1(
+      address: *const libc::c_void,
+      info: *mut libc::Dl_info,
+      extra: *mut *mut libc::c_void,
+      flags: libc::c_int,
+    ) -> libc::c_int;
+  }
+
+  let mut info: libc::Dl_info = mem::zeroed();
+  let mut symbol: *const Symbol = ptr::null();
+  let result = dladdr1(
+    function as *const _,
+    &mut info,
+    &mut symbol as *mut _ as *mut _,
+    RTLD_DL_SYMENT,
+  );
+
+  // The address must be the symbol's start, not merely within it
+  if result == 0 || symbol.is_null() || !ptr::eq(info.dli_saddr as *const (), function) {
+    return None;
+  }
+
+  Some((*symbol).size as usize).filter(|&size| size > 0)
+}
+
+/// Returns the size of a function's dynamic symbol.
+#[cfg(not(target_env = "gnu"))]
+unsafe fn symbol_size(_function: *const ()) -> Option<usize> {
+  None
+}
+
+/// Returns the size of a function, as described by its FDE (i.e frame
+/// description entry).
+unsafe fn unwind_size(function: *const ()) -> Option<usize> {
+  /// The base addresses of the FDE's object.
+  #[repr(C)]
+  struct Bases {
+    text: *mut libc::c_void,
+    data: *mut libc::c_void,
+    function: *mut libc::c_void,
+  }
+
+  extern "C" {
+    fn _Unwind_Find_FDE(pc: *mut libc::c_void, bases: *mut Bases) -> *const u8;
+  }
+
+  let mut bases: Bases = mem::zeroed();
+  let fde = _Unwind_Find_FDE(function as *mut _, &mut bases);
+
+  // The FDE must begin at the function, not merely cover it
+  if fde.is_null() || !ptr::eq(bases.function as *const (), function) {
+    return None;
+  }
+
+  // Skip the FDE's length, and resolve its CIE (i.e common information entry)
+  let mut reader = Reader(fde.add(4));
+  let cie_offset = reader.u32() as usize;
+  let cie = fde.add(4).sub(cie_offset);
+  let encoding = pointer_encoding(cie)?;
+
+  // The FDE's initial location is followed by its range, in the same format
+  reader.pointer(encoding)?;
+  reader.pointer(encoding)
+}
+
+/// Returns the pointer encoding of a CIE's FDEs.
+unsafe fn pointer_encoding(cie: *const u8) -> Option<u8> {
+  /// The default encoding (i.e an absolute pointer).
+  const DW_EH_PE_ABSPTR: u8 = 0x00;
+
+  // Skip the CIE's length and identifier
+  let mut reader = Reader(cie.add(8));
+  let version = reader.u8();
+
+  let mut augmentation = Vec::new();
+  loop {
+    match reader.u8() {
+      0 => break,
+      byte => augmentation.push(byte),
+    }
+  }
+
+  // Skip the code and data alignment factors, and the return address register
+  reader.uleb128();
+  reader.uleb128();
+  if version == 1 {
+    reader.u8();
+  } else {
+    reader.uleb128();
+  }
+
+  if augmentation.first() != Some(&b'z') {
+    return Some(DW_EH_PE_ABSPTR);
+  }
+
+  reader.uleb128();
+  for &character in &augmentation[1..] {
+    match character {
+      b'R' => return Some(reader.u8()),
+      b'P' => {
+        let encoding = reader.u8();
+        reader.pointer(encoding)?;
+      },
+      b'L' => {
+        reader.u8();
+      },
+      b'S' | b'B' => (),
+      _ => return None,
+    }
+  }
+
+  Some(DW_EH_PE_ABSPTR)
+}
+
+/// A cursor over call frame information.
+struct Reader(*const u8);
+
+impl Reader {
+  /// Reads a value of type `T`, which may be unaligned.
+  unsafe fn read<T: Copy>(&mut self) -> T {
+    let value = ptr::read_unaligned(self.0 as *const T);
+    self.0 = self.0.add(mem::size_of::<T>());
+    value
+  }
+
+  unsafe fn u8(&mut self) -> u8 {
+    self.read()
+  }
+
+  unsafe fn u32(&mut self) -> u32 {
+    self.read()
+  }
+
+  unsafe fn uleb128(&mut self) -> u64 {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+      let byte = self.u8();
+      if shift < 64 {
+        value |= u64::from(byte & 0x7F) << shift;
+      }
+      shift += 7;
+      if byte & 0x80 == 0 {
+        return value;
+      }
+    }
+  }
+
+  /// Reads a pointer's value, without applying its relative base.
+  ///
+  /// Only the value's format (i.e the lower nibble of the encoding) is
+  /// supported, which suffices for skipping pointers and reading ranges.
+  unsafe fn pointer(&mut self, encoding: u8) -> Option<usize> {
+    Some(match encoding & 0x0F {
+      0x00 => self.read::<usize>(),
+      0x02 => self.read::<u16>() as usize,
+      0x03 => self.read::<u32>() as usize,
+      0x04 => self.read::<u64>() as usize,
+      0x0A => self.read::<i16>() as usize,
+      0x0B => self.read::<i32>() as usize,
+      0x0C => self.read::<i64>() as usize,
+      _ => return None,
+    })
+  }
+}
@@ -0,0 +1,335 @@
+//! Breakpoints redirecting the threads executing them.
+//!
+//! A process-wide `SIGTRAP` handler looks up the address of the breakpoint a
+//! thread trapped on, and resumes the thread at the breakpoint's destination.
+//! Any other trap is forwarded to the previously installed handler.
+use super::{memory, signal};
+use crate::error::{Error, Result};
+use crate::{alloc, arch, util};
+use cfg_if::cfg_if;
+use lazy_static::lazy_static;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::{fmt, slice};
+
+/// The maximum number of simultaneous breakpoints.
+const CAPACITY: usize = 256;
+
+/// The address of a slot being reassigned.
+const RESERVED: usize = usize::MAX;
+
+lazy_static! {
+  /// Serializes modifications of the slots.
+  static ref REGISTRY_LOCK: Mutex<()> = Mutex::new(());
+}
+
+cfg_if! {
+  if #[cfg(any(target_arch = "x86", target_arch = "x86_64"))] {
+    /// The signal code of traps caused by an `int3`.
+    const SI_KERNEL: libc::c_int = 0x80;
+
+    #[cfg(target_arch = "x86_64")]
+    const REG_IP: libc::c_int = libc::REG_RIP;
+    #[cfg(target_arch = "x86")]
+    const REG_IP: libc::c_int = libc::REG_EIP;
+
+    /// Returns the instruction pointer, and the address of the breakpoint
+    /// executed (if it was the cause of the trap, according to its `code`).
+    unsafe fn trap_address(
+      code: libc::c_int,
+      context: &mut libc::ucontext_t,
+    ) -> (&mut libc::greg_t, Option<usize>) {
+      let ip = &mut context.uc_mcontext.gregs[REG_IP as usize];
+
+      // The instruction pointer is located after the `int3`
+      let address = (*ip as usize).wrapping_sub(1);
+      (ip, (code == SI_KERNEL).then_some(address))
+    }
+  } else if #[cfg(target_arch = "aarch64")] {
+    /// The signal code of traps caused by a `brk`.
+    const TRAP_BRKPT: libc::c_int = 1;
+
+    /// Returns the instruction pointer, and the address of the breakpoint
+    /// executed (if it was the cause of the trap, according to its `code`).
+    unsafe fn trap_address(
+      code: libc::c_int,
+      context: &mut libc::ucontext_t,
+    ) -> (&mut libc::c_ulonglong, Option<usize>) {
+      let pc = &mut context.uc_mcontext.pc;
+      let address = *pc as usize;
+      (pc, (code == TRAP_BRKPT).then_some(address))
+    }
+  }
+}
+
+/// A breakpoint, which is retired (i.e has no destination) once removed.
+///
+/// Retired slots are kept until reassigned, so threads which trapped on a
+/// breakpoint before its removal can still be identified.
+struct Slot {
+  address: AtomicUsize,
+  destination: AtomicUsize,
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_SLOT: Slot = Slot {
+  address: AtomicUsize::new(0),
+  destination: AtomicUsize::new(0),
+};
+
+/// The registered breakpoints.
+///
+/// These are read by the signal handler, so they're lock-free.
+static SLOTS: [Slot; CAPACITY] = [EMPTY_SLOT; CAPACITY];
+
+/// The `SIGTRAP` handler of breakpoints.
+static HANDLER: signal::Handler = signal::Handler::new();
+
+/// Registers a breakpoint, redirecting threads executing `address` to
+/// `destination`.
+///
+/// The breakpoint instruction itself is written by the caller.
+pub fn register(address: *const (), destination: *const ()) -> Result<()> {
+  HANDLER.install(libc::SIGTRAP, handle_trap);
+
+  let _guard = util::lock(&REGISTRY_LOCK);
+  let (address, destination) = (address as usize, destination as usize);
+
+  if let Some(slot) = find(address) {
+    slot.destination.store(destination, Ordering::SeqCst);
+    return Ok(());
+  }
+
+  // Unused slots are preferred over retired ones
+  let slot = SLOTS
+    .iter()
+    .find(|slot| slot.address.load(Ordering::SeqCst) == 0)
+    .or_else(|| {
+      SLOTS
+        .iter()
+        .find(|slot| slot.destination.load(Ordering::SeqCst) == 0)
+    })
+    .ok_or(Error::OutOfMemory)?;
+
+  slot.address.store(RESERVED, Ordering::SeqCst);
+  slot.destination.store(destination, Ordering::SeqCst);
+  slot.address.store(address, Ordering::SeqCst);
+  Ok(())
+}
+
+/// Changes the destination of a registered breakpoint.
+pub fn redirect(address: *const (), destination: *const ()) {
+  let _guard = util::lock(&REGISTRY_LOCK);
+  if let Some(slot) = find(address as usize) {
+    if slot.destination.load(Ordering::SeqCst) != 0 {
+      slot
+        .destination
+        .store(destination as usize, Ordering::SeqCst);
+    }
+  }
+}
+
+/// Retires a breakpoint, once its instruction has been removed.
+///
+/// Threads that trapped before its removal re-execute the restored code.
+pub fn unregister(address: *const ()) {
+  let _guard = util::lock(&REGISTRY_LOCK);
+  if let Some(slot) = find(address as usize) {
+    slot.destination.store(0, Ordering::SeqCst);
+  }
+}
+
+/// Returns the slot of a breakpoint.
+fn find(address: usize) -> Option<&'static Slot> {
+  SLOTS
+    .iter()
+    .find(|slot| slot.address.load(Ordering::SeqCst) == address)
+}
+
+/// Returns the destination of a breakpoint, or zero if it's retired.
+fn lookup(address: usize) -> Option<usize> {
+  SLOTS.iter().find_map(|slot| {
+    if slot.address.load(Ordering::SeqCst) != address {
+      return None;
+    }
+
+    // The slot may have been reassigned whilst reading its destination
+    let destination = slot.destination.load(Ordering::SeqCst);
+    (slot.address.load(Ordering::SeqCst) == address).then_some(destination)
+  })
+}
+
+/// Redirects threads trapped by a registered breakpoint to its destination.
+unsafe extern "C" fn handle_trap(
+  signal: libc::c_int,
+  info: *mut libc::siginfo_t,
+  context: *mut libc::c_void,
+) {
+  let (ip, address) = trap_address((*info).si_code, &mut *(context as *mut libc::ucontext_t));
+
+  if let Some(address) = address {
+    match lookup(address) {
+      Some(0) => {
+        // The breakpoint has been removed since the thread trapped, so the
+        // restored code is executed instead
+        let code = slice::from_raw_parts(address as *const u8, arch::meta::BREAKPOINT.len());
+        if code != arch::meta::BREAKPOINT {
+          *ip = address as _;
+          return;
+        }
+      },
+      Some(destination) => {
+        *ip = destination as _;
+        return;
+      },
+      None => (),
+    }
+  }
+
+  HANDLER.forward(signal, info, context);
+}
+
+/// A hook replacing the first instruction of a target with a breakpoint.
+///
+/// Threads executing the breakpoint are redirected to the detour by the
+/// `SIGTRAP` handler. Since only a single instruction is replaced, any function
+/// can be hooked, at the cost of a signal per invocation.
+pub struct Breakpoint {
+  target: *const (),
+  detour: AtomicUsize,
+  trampoline: alloc::ExecutableMemory,
+  original: Vec<u8>,
+  enabled: AtomicBool,
+}
+
+impl Breakpoint {
+  /// Constructs a breakpoint hook, redirecting `target` to `detour`.
+  pub unsafe fn new(target: *const (), detour: *const ()) -> Result<Self> {
+    if target == detour {
+      Err(Error::SameAddress)?;
+    }
+
+    if !util::is_executable_address(target)? || !util::is_executable_address(detour)? {
+      Err(Error::NotExecutable)?;
+    }
+
+    let target = arch::meta::skip_jmps(target);
+    let _guard = memory::lock_target(target);
+
+    // Only the instruction replaced by the breakpoint is relocated
+    let trampoline = arch::Trampoline::new(target, arch::meta::BREAKPOINT.len())?;
+    let trampoline = memory::allocate_pic(&memory::allocator(), trampoline.emitter(), target)?;
+    let original =
+      slice::from_raw_parts(target as *const u8, arch::meta::BREAKPOINT.len()).to_vec();
+
+    log::debug!("breakpoint trampoline at {:?}", trampoline.as_ptr());
+    Ok(Breakpoint {
+      target,
+      detour: AtomicUsize::new(detour as usize),
+      trampoline,
+      original,
+      enabled: AtomicBool::new(false),
+    })
+  }
+
+  /// Enables the breakpoint.
+  pub unsafe fn enable(&self) -> Result<()> {
+    self.toggle(true)
+  }
+
+  /// Disables the breakpoint.
+  pub unsafe fn disable(&self) -> Result<()> {
+    self.toggle(false)
+  }
+
+  /// Changes the detour, regardless of whether the breakpoint is enabled or
+  /// not.
+  pub unsafe fn set_detour(&self, detour: *const ()) -> Result<()> {
+    if !util::is_executable_address(detour)? {
+      Err(Error::NotExecutable)?;
+    }
+
+    let _guard = memory::lock_target(self.target);
+    self.detour.store(detour as usize, Ordering::SeqCst);
+    redirect(self.target, detour);
+    Ok(())
+  }
+
+  /// Returns whether the breakpoint is enabled or not.
+  pub fn is_enabled(&self) -> bool {
+    self.enabled.load(Ordering::SeqCst)
+  }
+
+  /// Returns a reference to the generated trampoline.
+  pub fn trampoline(&self) -> &() {
+    unsafe {
+      (self.trampoline.as_ptr() as *const ())
+        .as_ref()
+        .expect("trampoline should not be null")
+    }
+  }
+
+  /// Writes or removes the breakpoint.
+  unsafe fn toggle(&self, enabled: bool) -> Result<()> {
+    let _guard = memory::lock_target(self.target);
+
+    if self.enabled.load(Ordering::SeqCst) == enabled {
+      return Ok(());
+    }
+
+    // The breakpoint is registered whilst its instruction may be executed
+    if enabled {
+      register(self.target, self.detour.load(Ordering::SeqCst) as *const ())?;
+      if let Err(error) = Self::write(self.target, arch::meta::BREAKPOINT) {
+        unregister(self.target);
+        return Err(error);
+      }
+    } else {
+      Self::write(self.target, &self.original)?;
+      unregister(self.target);
+    }
+
+    self.enabled.store(enabled, Ordering::SeqCst);
+    Ok(())
+  }
+
+  /// Writes an instruction, which is stored atomically.
+  unsafe fn write(address: *const (), code: &[u8]) -> Result<()> {
+    util::write_code(address as *const u8, code)?;
+
+    #[cfg(target_arch = "aarch64")]
+    arch::meta::clear_instruction_cache(slice::from_raw_parts(address as *const u8, code.len()));
+
+    Ok(())
+  }
+}
+
+impl Drop for Breakpoint {
+  /// Removes the breakpoint, if enabled.
+  fn drop(&mut self) {
+    let result = unsafe {
+      if util::is_executable_address(self.target).unwrap_or(false) {
+        self.disable()
+      } else {
+        unregister(self.target);
+        Ok(())
+      }
+    };
+    debug_assert!(result.is_ok());
+  }
+}
+
+impl fmt::Debug for Breakpoint {
+  /// Output whether the breakpoint is enabled or not.
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "Breakpoint {{ enabled: {}, trampoline: {:?} }}",
+      self.is_enabled(),
+      self.trampoline()
+    )
+  }
+}
+
+unsafe impl Send for Breakpoint {}
+unsafe impl Sync for Breakpoint {}
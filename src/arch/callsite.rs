@@ -0,0 +1,172 @@
+use super::memory;
+use crate::alloc;
+use crate::error::{Error, Result};
+use crate::{arch, util};
+use std::cell::UnsafeCell;
+use std::fmt;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// A hook of a single call instruction.
+///
+/// Only the call's destination is replaced, so the callee, and any other
+/// callers of it, are left untouched.
+pub struct CallSite {
+  call: *const (),
+  original: *const (),
+  // The relay and trampoline are referenced by the patched call
+  #[allow(dead_code)]
+  relay: Option<alloc::ExecutableMemory>,
+  #[allow(dead_code)]
+  trampoline: Option<alloc::ExecutableMemory>,
+  patcher: UnsafeCell<arch::Patcher>,
+  enabled: AtomicBool,
+}
+
+impl CallSite {
+  /// Constructs a hook, redirecting the call instruction at `call` to
+  /// `detour`.
+  pub unsafe fn new(call: *const (), detour: *const ()) -> Result<Self> {
+    if !util::is_executable_address(call)? || !util::is_executable_address(detour)? {
+      Err(Error::NotExecutable)?;
+    }
+
+    let _guard = memory::lock_target(call);
+    let (call_size, original) = arch::meta::decode_call(call)?;
+
+    if original == detour {
+      Err(Error::SameAddress)?;
+    }
+
+    // The call is either redirected directly, or via a relay if the detour is
+    // out of its reach
+    let allocator = memory::allocator();
+    let (relay, destination) = if Self::is_within_reach(call, call_size, detour) {
+      (None, detour)
+    } else {
+      let relay =
+        memory::allocate_pic(&allocator, &arch::meta::relay_builder(call, detour)?, call)?;
+      let entry = relay.as_ptr().add(arch::meta::RELAY_ENTRY_OFFSET) as *const ();
+      (Some(relay), entry)
+    };
+
+    let trampoline = Self::relocate(&allocator, call, call_size)?;
+    let trampoline_address = trampoline
+      .as_ref()
+      .map_or(ptr::null(), |trampoline| trampoline.as_ptr() as *const ());
+
+    log::debug!("call site at {:?}", call);
+    log::debug!("original destination at {:?}", original);
+
+    Ok(CallSite {
+      patcher: UnsafeCell::new(arch::Patcher::with_call(
+        call,
+        call_size,
+        destination,
+        trampoline_address,
+      )?),
+      call,
+      original,
+      relay,
+      trampoline,
+      enabled: AtomicBool::default(),
+    })
+  }
+
+  /// Enables the hook.
+  pub unsafe fn enable(&self) -> Result<()> {
+    self.toggle(true)
+  }
+
+  /// Disables the hook.
+  pub unsafe fn disable(&self) -> Result<()> {
+    self.toggle(false)
+  }
+
+  /// Returns whether the hook is enabled or not.
+  pub fn is_enabled(&self) -> bool {
+    self.enabled.load(Ordering::SeqCst)
+  }
+
+  /// Returns the call's original destination.
+  pub fn original(&self) -> &() {
+    unsafe {
+      self
+        .original
+        .as_ref()
+        .expect("original destination should not be null")
+    }
+  }
+
+  /// Patches or restores the call instruction.
+  unsafe fn toggle(&self, enabled: bool) -> Result<()> {
+    let _guard = memory::lock_target(self.call);
+
+    if self.enabled.load(Ordering::SeqCst) == enabled {
+      return Ok(());
+    }
+
+    (*self.patcher.get()).toggle(enabled)?;
+    self.enabled.store(enabled, Ordering::SeqCst);
+    Ok(())
+  }
+
+  /// Returns whether the detour can be called directly from the call site.
+  fn is_within_reach(call: *const (), call_size: usize, detour: *const ()) -> bool {
+    let displacement = (detour as isize).wrapping_sub(call as isize);
+    arch::is_within_range(displacement)
+      && arch::is_within_range(displacement.wrapping_sub(call_size as isize))
+  }
+
+  /// Relocates the call instruction, so threads reaching it whilst it's being
+  /// patched can execute an equivalent copy of it.
+  #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+  unsafe fn relocate(
+    allocator: &std::sync::Arc<dyn alloc::ExecutableAllocator>,
+    call: *const (),
+    call_size: usize,
+  ) -> Result<Option<alloc::ExecutableMemory>> {
+    let trampoline = arch::Trampoline::new(call, call_size)?;
+    memory::allocate_pic(allocator, trampoline.emitter(), call).map(Some)
+  }
+
+  /// The call is patched atomically on aarch64, so it's never relocated.
+  #[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+  unsafe fn relocate(
+    _allocator: &std::sync::Arc<dyn alloc::ExecutableAllocator>,
+    _call: *const (),
+    _call_size: usize,
+  ) -> Result<Option<alloc::ExecutableMemory>> {
+    Ok(None)
+  }
+}
+
+impl Drop for CallSite {
+  /// Restores the call instruction, if enabled and still mapped.
+  fn drop(&mut self) {
+    let result = unsafe {
+      if util::is_executable_address(self.call).unwrap_or(false) {
+        self.disable()
+      } else {
+        Ok(())
+      }
+    };
+    debug_assert!(result.is_ok());
+  }
+}
+
+impl fmt::Debug for CallSite {
+  /// Output whether the hook is enabled or not.
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "CallSite {{ enabled: {}, call: {:?}, original: {:?} }}",
+      self.is_enabled(),
+      self.call,
+      self.original
+    )
+  }
+}
+
+unsafe impl Send for CallSite {}
+unsafe impl Sync for CallSite {}
@@ -0,0 +1,188 @@
+use super::memory;
+use crate::error::Result;
+use crate::{alloc, arch};
+use std::fmt;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::process;
+use std::sync::atomic::{AtomicU64, AtomicU8, AtomicUsize, Ordering};
+
+/// Describes how a panic within a context detour's callback is handled.
+///
+/// The callback is invoked by generated code, so the panic can never unwind
+/// into the detoured function's caller.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContextPanicPolicy {
+  /// Aborts the process with a diagnostic (the default).
+  Abort,
+  /// Calls the original function, with the registers as they were before the
+  /// callback was invoked.
+  CallOriginal,
+  /// Returns a value to the caller, skipping the original function.
+  Return(u64),
+}
+
+/// The shared state of a context hook, referenced by its stubs.
+struct State {
+  callback: Box<dyn Fn(&mut arch::CpuContext) + Send + Sync>,
+  trampoline: AtomicUsize,
+  invoker: usize,
+  panic_policy: AtomicU8,
+  panic_value: AtomicU64,
+}
+
+impl State {
+  /// Returns the current panic policy.
+  fn panic_policy(&self) -> ContextPanicPolicy {
+    match self.panic_policy.load(Ordering::SeqCst) {
+      1 => ContextPanicPolicy::CallOriginal,
+      2 => ContextPanicPolicy::Return(self.panic_value.load(Ordering::SeqCst)),
+      _ => ContextPanicPolicy::Abort,
+    }
+  }
+}
+
+/// A hook invoking a callback with the register context of each invocation.
+///
+/// The entry stub captures the argument registers, invokes the callback, and
+/// restores the (possibly modified) registers before forwarding the
+/// invocation to the trampoline. If the callback skips the original function,
+/// the stub returns to the caller directly, with the context's return value.
+pub struct ContextHook {
+  state: Box<State>,
+  entry: alloc::ExecutableMemory,
+  invoker: alloc::ExecutableMemory,
+}
+
+impl ContextHook {
+  /// Creates the stubs for a context hook, allocated close to `target`.
+  pub unsafe fn new<C>(target: *const (), callback: C) -> Result<Self>
+  where
+    C: Fn(&mut arch::CpuContext) + Send + Sync + 'static,
+  {
+    let allocator = memory::allocator();
+    let invoker = memory::allocate_pic(&allocator, &arch::meta::invoker_builder(), target)?;
+
+    let state = Box::new(State {
+      callback: Box::new(callback),
+      trampoline: AtomicUsize::new(0),
+      invoker: invoker.as_ptr() as usize,
+      panic_policy: AtomicU8::new(0),
+      panic_value: AtomicU64::new(0),
+    });
+
+    let entry = memory::allocate_pic(
+      &allocator,
+      &arch::meta::context_stub_builder(
+        &*state as *const State as *const (),
+        handle as *const () as usize,
+      ),
+      target,
+    )?;
+
+    log::debug!("context hook entry at {:?}", entry.as_ptr());
+    Ok(ContextHook {
+      state,
+      entry,
+      invoker,
+    })
+  }
+
+  /// Returns the address of the entry stub, used as the detour.
+  pub fn entry(&self) -> *const () {
+    self.entry.as_ptr() as *const ()
+  }
+
+  /// Sets the address the entry stub forwards invocations to.
+  pub fn set_trampoline(&self, trampoline: *const ()) {
+    self
+      .state
+      .trampoline
+      .store(trampoline as usize, Ordering::SeqCst);
+  }
+
+  /// Changes how a panic within the callback is handled.
+  pub fn set_panic_policy(&self, policy: ContextPanicPolicy) {
+    // The policy is plain data, so it is replaced without any reclamation
+    let kind = match policy {
+      ContextPanicPolicy::Abort => 0,
+      ContextPanicPolicy::CallOriginal => 1,
+      ContextPanicPolicy::Return(value) => {
+        self.state.panic_value.store(value, Ordering::SeqCst);
+        2
+      },
+    };
+    self.state.panic_policy.store(kind, Ordering::SeqCst);
+  }
+}
+
+impl fmt::Debug for ContextHook {
+  /// Output the location of the stubs.
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "ContextHook {{ entry: {:?}, invoker: {:?} }}",
+      self.entry.as_ptr(),
+      self.invoker.as_ptr()
+    )
+  }
+}
+
+impl arch::CpuContext {
+  /// Skips the original function once the callback returns.
+  ///
+  /// The caller resumes with the context's return value registers. This is
+  /// only respected by a [ContextDetour](./struct.ContextDetour.html).
+  pub fn skip(&mut self) {
+    self.skip = 1;
+  }
+
+  /// Calls the original function with the (possibly modified) context.
+  ///
+  /// The return value registers of the context are updated with the result,
+  /// and the original function is skipped once the callback returns. Only
+  /// the first 16 stack slots are forwarded to the function.
+  ///
+  /// Panics if the context was not captured by a
+  /// [ContextDetour](./struct.ContextDetour.html).
+  pub unsafe fn call_original(&mut self) {
+    assert!(self.hook != 0, "context is not owned by a context detour");
+    let state = &*(self.hook as *const State);
+
+    let invoker: extern "C" fn(*mut arch::CpuContext, usize) = mem::transmute(state.invoker);
+    invoker(self, state.trampoline.load(Ordering::SeqCst));
+    self.skip = 1;
+  }
+}
+
+/// Called upon entry to the hooked function.
+unsafe extern "C" fn handle(state: &State, context: &mut arch::CpuContext) -> usize {
+  context.hook = state as *const State as usize;
+  context.skip = 0;
+
+  let policy = state.panic_policy();
+  let backup = match policy {
+    ContextPanicPolicy::CallOriginal => Some(context.clone()),
+    _ => None,
+  };
+
+  if panic::catch_unwind(AssertUnwindSafe(|| (state.callback)(context))).is_err() {
+    match (policy, backup) {
+      (ContextPanicPolicy::CallOriginal, Some(backup)) => *context = backup,
+      (ContextPanicPolicy::Return(value), _) => {
+        context.set_return_value(value);
+        context.skip = 1;
+      },
+      _ => {
+        eprintln!("detour: context hook callback panicked, aborting");
+        process::abort();
+      },
+    }
+  }
+
+  if context.skip != 0 {
+    0
+  } else {
+    state.trampoline.load(Ordering::SeqCst)
+  }
+}
@@ -1,31 +1,74 @@
 use super::memory;
+use crate::alloc::{self, ExecutableAllocator};
 use crate::error::{Error, Result};
-use crate::{alloc, arch, util};
+use crate::{arch, util};
 use std::cell::UnsafeCell;
-use std::fmt;
+use std::{fmt, mem};
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Where the trampoline and relay of a detour are allocated.
+#[derive(Clone, Copy)]
+enum Placement {
+  /// Within `DETOUR_RANGE` of the target.
+  Near,
+  /// Within a distance of the target, reachable by a longer patch.
+  #[cfg_attr(not(target_arch = "aarch64"), allow(dead_code))]
+  Within(usize),
+  /// At any distance from the target, which is patched with an absolute jump.
+  #[cfg_attr(
+    not(any(target_arch = "x86_64", target_arch = "aarch64")),
+    allow(dead_code)
+  )]
+  Anywhere,
+}
 
 /// An architecture-independent implementation of a base detour.
 ///
 /// This class is never instantiated by itself, it merely exposes an API
 /// available through it's descendants.
 pub struct Detour {
-  #[allow(dead_code)]
-  relay: Option<alloc::ExecutableMemory>,
+  target: *const (),
+  relay: alloc::ExecutableMemory,
   trampoline: alloc::ExecutableMemory,
   patcher: UnsafeCell<arch::Patcher>,
-  enabled: AtomicBool,
+  enabled: Box<AtomicBool>,
+  fast_toggle: bool,
 }
 
 impl Detour {
   pub unsafe fn new(target: *const (), detour: *const ()) -> Result<Self> {
+    Self::create(target, detour, false, memory::allocator())
+  }
+
+  /// Constructs a detour, whose target remains patched during its lifetime.
+  ///
+  /// The relay chooses between the detour and the trampoline depending on the
+  /// enabled flag, so toggling it does not modify any code.
+  pub unsafe fn with_fast_toggle(target: *const (), detour: *const ()) -> Result<Self> {
+    Self::create(target, detour, true, memory::allocator())
+  }
+
+  /// Constructs a detour, whose trampoline and relay are allocated by
+  /// `allocator`.
+  pub unsafe fn with_allocator(
+    target: *const (),
+    detour: *const (),
+    allocator: Arc<dyn ExecutableAllocator>,
+  ) -> Result<Self> {
+    Self::create(target, detour, false, allocator)
+  }
+
+  unsafe fn create(
+    target: *const (),
+    detour: *const (),
+    fast_toggle: bool,
+    allocator: Arc<dyn ExecutableAllocator>,
+  ) -> Result<Self> {
     if target == detour {
       Err(Error::SameAddress)?;
     }
 
-    // Lock this so OS operations are not performed in parallell
-    let mut pool = memory::POOL.lock().unwrap();
-
     if !util::is_executable_address(target)? || !util::is_executable_address(detour)? {
       Err(Error::NotExecutable)?;
     }
@@ -33,41 +76,115 @@ impl Detour {
     // Resolve real target address
     let target = arch::meta::skip_jmps(target);
 
+    // Only the target is locked, so detours of other targets are created in
+    // parallel
+    let guard = memory::lock_target(target);
+
     // Create a trampoline generator for the target function
     let margin = arch::meta::prolog_margin(target);
     let trampoline = arch::Trampoline::new(target, margin)?;
 
-    // A relay is used in case a normal branch cannot reach the destination
-    let relay = if let Some(emitter) = arch::meta::relay_builder(target, detour)? {
-      Some(memory::allocate_pic(&mut pool, &emitter, target)?)
+    let build = |trampoline, placement| {
+      Self::build(
+        target,
+        detour,
+        trampoline,
+        fast_toggle,
+        &allocator,
+        placement,
+      )
+    };
+    let result = build(trampoline, Placement::Near);
+
+    // Without any memory within reach of a `b`, it's patched with a page
+    // relative jump, reaching memory within 4 GiB.
+    #[cfg(target_arch = "aarch64")]
+    let result = match result {
+      Err(Error::OutOfMemory) => {
+        let margin = arch::meta::page_prolog_margin(target);
+        let trampoline = arch::Trampoline::new(target, margin)?;
+        build(trampoline, Placement::Within(arch::meta::PAGE_DETOUR_RANGE))
+      },
+      result => result,
+    };
+
+    let detour = match result {
+      // Without any memory close to the target, it's patched with an absolute
+      // jump, so the trampoline and relay can be located anywhere.
+      #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+      Err(Error::OutOfMemory) => {
+        let margin = arch::meta::absolute_prolog_margin(target);
+        let trampoline = arch::Trampoline::with_absolute_operands(target, margin)?;
+        build(trampoline, Placement::Anywhere)
+      },
+      result => result,
+    }?;
+
+    let result = if fast_toggle {
+      detour.patch_unlocked(true)
     } else {
-      None
+      Ok(())
     };
 
-    let trampoline_code = memory::allocate_pic(&mut pool, trampoline.emitter(), target)?;
+    // A failed detour locks the target again when dropped, so it must be
+    // released beforehand
+    mem::drop(guard);
+    result.map(|_| detour)
+  }
 
-    log::debug!(
-      "relay at {:?}",
-      &relay.as_ref().map(|code| code.as_ptr() as *const ())
-    );
+  /// Allocates the trampoline and relay, and prepares the target's patch.
+  unsafe fn build(
+    target: *const (),
+    detour: *const (),
+    trampoline: arch::Trampoline,
+    fast_toggle: bool,
+    allocator: &Arc<dyn ExecutableAllocator>,
+    placement: Placement,
+  ) -> Result<Self> {
+    let allocate = |emitter: &_| match placement {
+      Placement::Near => memory::allocate_pic(allocator, emitter, target),
+      Placement::Within(distance) => {
+        memory::allocate_pic_within(allocator, emitter, target, distance)
+      },
+      Placement::Anywhere => memory::allocate_pic_anywhere(allocator, emitter, target),
+    };
+
+    let trampoline_code = allocate(trampoline.emitter())?;
+    let enabled = Box::new(AtomicBool::default());
+
+    // The target is redirected to a relay, containing the detour's address
+    let (relay_emitter, entry) = if fast_toggle {
+      let emitter = arch::meta::switch_relay_builder(
+        target,
+        detour,
+        trampoline_code.as_ptr() as *const (),
+        &*enabled,
+      )?;
+      (emitter, arch::meta::SWITCH_RELAY_ENTRY_OFFSET)
+    } else {
+      (
+        arch::meta::relay_builder(target, detour)?,
+        arch::meta::RELAY_ENTRY_OFFSET,
+      )
+    };
+    let relay = allocate(&relay_emitter)?;
+
+    log::debug!("relay at {:?}", relay.as_ptr() as *const ());
     log::debug!("detour at {:?}", &detour);
     log::debug!("trampoline at {:?}", trampoline_code.as_ptr() as *const ());
     log::debug!("original at {:?}", &target);
 
-    // If a relay is supplied, use it instead of the detour address
-    let detour = relay
-      .as_ref()
-      .map(|code| code.as_ptr() as *const ())
-      .unwrap_or(detour);
-
     Ok(Detour {
       patcher: UnsafeCell::new(arch::Patcher::new(
         target,
-        detour,
+        relay.as_ptr().add(entry) as *const (),
         trampoline.prolog_size(),
+        trampoline_code.as_ptr() as *const (),
       )?),
       trampoline: trampoline_code,
-      enabled: AtomicBool::default(),
+      target,
+      enabled,
+      fast_toggle,
       relay,
     })
   }
@@ -82,6 +199,39 @@ impl Detour {
     self.toggle(false)
   }
 
+  /// Disables the detour, or if the target no longer contains its patch (e.g
+  /// its library has been unloaded, or reloaded), marks it as disabled without
+  /// restoring it.
+  pub unsafe fn detach(&self) -> Result<()> {
+    if self.is_patch_intact() {
+      self.disable()
+    } else {
+      self.enabled.store(false, Ordering::SeqCst);
+      Ok(())
+    }
+  }
+
+  /// Returns whether `target` is patched by the detour.
+  pub unsafe fn is_patching(&self, target: *const ()) -> bool {
+    arch::meta::skip_jmps(target) == self.target && self.is_patch_intact()
+  }
+
+  /// Changes the detour, regardless of whether the detour is enabled or not.
+  ///
+  /// The address is atomically updated in the relay; the target is not
+  /// modified.
+  pub unsafe fn set_detour(&self, detour: *const ()) -> Result<()> {
+    if !util::is_executable_address(detour)? {
+      Err(Error::NotExecutable)?;
+    }
+
+    // The relay may be executed concurrently, so the slot is written atomically
+    self.relay.write(
+      arch::meta::RELAY_SLOT_OFFSET,
+      &(detour as usize).to_ne_bytes(),
+    )
+  }
+
   /// Returns whether the detour is enabled or not.
   pub fn is_enabled(&self) -> bool {
     self.enabled.load(Ordering::SeqCst)
@@ -96,31 +246,117 @@ impl Detour {
     }
   }
 
+  /// Returns whether the target is mapped, and still contains the patch.
+  unsafe fn is_patch_intact(&self) -> bool {
+    util::is_executable_address(self.target).unwrap_or(false) && (*self.patcher.get()).is_patched()
+  }
+
   /// Enables or disables the detour.
   unsafe fn toggle(&self, enabled: bool) -> Result<()> {
-    let _guard = memory::POOL.lock().unwrap();
+    if self.fast_toggle {
+      // The relay reads the flag, so the target is left untouched
+      self.enabled.store(enabled, Ordering::SeqCst);
+      return Ok(());
+    }
+
+    let _guard = memory::lock_target(self.target);
 
     if self.enabled.load(Ordering::SeqCst) == enabled {
       return Ok(());
     }
 
-    // Runtime code is by default only read-execute
-    let _handle = {
-      let area = (*self.patcher.get()).area();
-      region::protect_with_handle(area.as_ptr(), area.len(), region::Protection::READ_WRITE)
-    }?;
-
-    // Copy either the detour or the original bytes of the function
-    (*self.patcher.get()).toggle(enabled);
+    self.patch_unlocked(enabled)?;
     self.enabled.store(enabled, Ordering::SeqCst);
     Ok(())
   }
+
+  /// Patches or restores the target's prolog.
+  unsafe fn patch(&self, enabled: bool) -> Result<()> {
+    let _guard = memory::lock_target(self.target);
+    self.patch_unlocked(enabled)
+  }
+
+  /// Patches or restores the target's prolog, assuming the lock is held.
+  unsafe fn patch_unlocked(&self, enabled: bool) -> Result<()> {
+    // Runtime code is by default only read-execute, and may share its pages
+    // with code executing concurrently
+    (*self.patcher.get()).toggle(enabled)
+  }
+}
+
+/// Creates a trampoline for a target, without patching it.
+///
+/// The trampoline is equivalent to a detour's, i.e a relocated copy of the
+/// target's prolog, followed by a jump to the rest of its body.
+pub unsafe fn create_trampoline(target: *const ()) -> Result<alloc::ExecutableMemory> {
+  if !util::is_executable_address(target)? {
+    Err(Error::NotExecutable)?;
+  }
+
+  let target = arch::meta::skip_jmps(target);
+  let _guard = memory::lock_target(target);
+
+  let allocator = memory::allocator();
+  let margin = arch::meta::prolog_margin(target);
+  let trampoline = arch::Trampoline::new(target, margin)?;
+
+  match memory::allocate_pic(&allocator, trampoline.emitter(), target) {
+    // Without any memory close to the target, its operands are relocated to
+    // absolute addresses instead
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    Err(Error::OutOfMemory) => {
+      let trampoline = arch::Trampoline::with_absolute_operands(target, margin)?;
+      memory::allocate_pic_anywhere(&allocator, trampoline.emitter(), target)
+    },
+    result => result,
+  }
+}
+
+/// Creates a clone of a function, i.e a relocated copy of its entire body.
+///
+/// Unless specified, the function's size is determined by its symbol or its
+/// unwind info. Any references beyond the function are adjusted, so the
+/// clone remains independent of the function's code.
+pub unsafe fn create_clone(
+  function: *const (),
+  size: Option<usize>,
+) -> Result<alloc::ExecutableMemory> {
+  if !util::is_executable_address(function)? {
+    Err(Error::NotExecutable)?;
+  }
+
+  let function = arch::meta::skip_jmps(function);
+  let _guard = memory::lock_target(function);
+
+  let size = match size {
+    Some(size) => size,
+    #[cfg(target_os = "linux")]
+    None => arch::bounds::function_size(function).ok_or(Error::UnknownBounds)?,
+    #[cfg(not(target_os = "linux"))]
+    None => Err(Error::UnknownBounds)?,
+  };
+
+  let (emitter, max_distance) = arch::clone::builder(function, size)?;
+  memory::allocate_pic_within(&memory::allocator(), &emitter, function, max_distance)
 }
 
 impl Drop for Detour {
   /// Disables the detour, if enabled.
   fn drop(&mut self) {
-    debug_assert!(unsafe { self.disable().is_ok() });
+    let result = unsafe {
+      if self.fast_toggle {
+        // The target must no longer refer to the relay, unless it's replaced
+        self.enabled.store(false, Ordering::SeqCst);
+        if self.is_patch_intact() {
+          self.patch(false)
+        } else {
+          Ok(())
+        }
+      } else {
+        self.disable()
+      }
+    };
+    debug_assert!(result.is_ok());
   }
 }
 
@@ -138,3 +374,68 @@ impl fmt::Debug for Detour {
 
 unsafe impl Send for Detour {}
 unsafe impl Sync for Detour {}
+
+#[cfg(all(feature = "nightly", test))]
+mod benches {
+  extern crate test;
+
+  use super::*;
+  use std::thread;
+  use test::Bencher;
+
+  #[inline(never)]
+  extern "C" fn target<const N: usize>() -> usize {
+    N
+  }
+
+  #[inline(never)]
+  extern "C" fn detour() -> usize {
+    unsafe { !std::ptr::read_volatile(&0) }
+  }
+
+  macro_rules! targets {
+    ($($index:literal)*) => {
+      [$(target::<$index> as *const () as usize),*]
+    };
+  }
+
+  /// Creates, enables and drops a detour for each target, evenly distributed
+  /// across `threads`.
+  fn create_detours(threads: usize) {
+    let targets = targets!(
+      0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15
+      16 17 18 19 20 21 22 23 24 25 26 27 28 29 30 31
+      32 33 34 35 36 37 38 39 40 41 42 43 44 45 46 47
+      48 49 50 51 52 53 54 55 56 57 58 59 60 61 62 63
+    );
+
+    let handles = targets
+      .chunks(targets.len() / threads)
+      .map(|chunk| {
+        let chunk = chunk.to_vec();
+        thread::spawn(move || {
+          for target in chunk {
+            unsafe {
+              let hook = Detour::new(target as *const (), detour as *const ()).unwrap();
+              hook.enable().unwrap();
+            }
+          }
+        })
+      })
+      .collect::<Vec<_>>();
+
+    for handle in handles {
+      handle.join().unwrap();
+    }
+  }
+
+  #[bench]
+  fn create_on_one_thread(b: &mut Bencher) {
+    b.iter(|| create_detours(1));
+  }
+
+  #[bench]
+  fn create_on_four_threads(b: &mut Bencher) {
+    b.iter(|| create_detours(4));
+  }
+}
@@ -0,0 +1,180 @@
+use super::memory;
+use crate::error::Result;
+use crate::{alloc, arch};
+use std::cell::RefCell;
+use std::fmt;
+use std::mem::ManuallyDrop;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::{process, ptr};
+
+thread_local! {
+  /// The return addresses replaced by exit hooks on this thread.
+  static SHADOW_STACK: RefCell<Vec<Frame>> = RefCell::new(Vec::new());
+}
+
+/// A function invocation intercepted by an exit hook.
+struct Frame {
+  state: *const State,
+  stack_pointer: usize,
+  return_address: usize,
+}
+
+/// The shared state of an exit hook, referenced by its stubs.
+struct State {
+  callback: Box<dyn Fn(&mut arch::CpuContext) + Send + Sync>,
+  trampoline: AtomicUsize,
+  landing: AtomicUsize,
+  active: AtomicUsize,
+}
+
+/// A hook invoking a callback whenever a function returns.
+///
+/// The entry stub saves the real return address on a per-thread shadow stack
+/// and substitutes it with a landing pad. Once the function returns to the
+/// landing pad, the callback is invoked with the register context, before the
+/// thread resumes at the saved return address.
+///
+/// When an invocation returns, the shadow stack is unwound to its frame, so
+/// any frames skipped by a non-local jump (e.g `longjmp`) out of an inner
+/// invocation are discarded. Unwinding through a hooked function (e.g C++
+/// exceptions) is not supported, since the landing pad has no unwind
+/// information.
+pub struct ExitHook {
+  state: ManuallyDrop<Box<State>>,
+  entry: ManuallyDrop<alloc::ExecutableMemory>,
+  landing: ManuallyDrop<alloc::ExecutableMemory>,
+}
+
+impl ExitHook {
+  /// Creates the stubs for an exit hook, allocated close to `target`.
+  pub unsafe fn new<C>(target: *const (), callback: C) -> Result<Self>
+  where
+    C: Fn(&mut arch::CpuContext) + Send + Sync + 'static,
+  {
+    let state = Box::new(State {
+      callback: Box::new(callback),
+      trampoline: AtomicUsize::new(0),
+      landing: AtomicUsize::new(0),
+      active: AtomicUsize::new(0),
+    });
+
+    let state_ptr = &*state as *const State as *const ();
+    let allocator = memory::allocator();
+
+    let landing = memory::allocate_pic(
+      &allocator,
+      &arch::meta::return_stub_builder(state_ptr, leave as *const () as usize),
+      target,
+    )?;
+    let entry = memory::allocate_pic(
+      &allocator,
+      &arch::meta::context_stub_builder(state_ptr, enter as *const () as usize),
+      target,
+    )?;
+
+    state
+      .landing
+      .store(landing.as_ptr() as usize, Ordering::SeqCst);
+    log::debug!("exit hook entry at {:?}", entry.as_ptr());
+    log::debug!("exit hook landing at {:?}", landing.as_ptr());
+
+    Ok(ExitHook {
+      state: ManuallyDrop::new(state),
+      entry: ManuallyDrop::new(entry),
+      landing: ManuallyDrop::new(landing),
+    })
+  }
+
+  /// Returns the address of the entry stub, used as the detour.
+  pub fn entry(&self) -> *const () {
+    self.entry.as_ptr() as *const ()
+  }
+
+  /// Sets the address the entry stub forwards invocations to.
+  pub fn set_trampoline(&self, trampoline: *const ()) {
+    self
+      .state
+      .trampoline
+      .store(trampoline as usize, Ordering::SeqCst);
+  }
+}
+
+impl Drop for ExitHook {
+  /// Releases the stubs, unless a thread may still return to the landing pad.
+  fn drop(&mut self) {
+    if self.state.active.load(Ordering::SeqCst) > 0 {
+      log::debug!("leaking exit hook with pending invocations");
+      return;
+    }
+
+    unsafe {
+      ManuallyDrop::drop(&mut self.entry);
+      ManuallyDrop::drop(&mut self.landing);
+      ManuallyDrop::drop(&mut self.state);
+    }
+  }
+}
+
+impl fmt::Debug for ExitHook {
+  /// Output the location of the stubs.
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "ExitHook {{ entry: {:?}, landing: {:?} }}",
+      self.entry.as_ptr(),
+      self.landing.as_ptr()
+    )
+  }
+}
+
+/// Called upon entry to the hooked function.
+unsafe extern "C" fn enter(state: &State, context: &mut arch::CpuContext) -> usize {
+  state.active.fetch_add(1, Ordering::SeqCst);
+  SHADOW_STACK.with(|stack| {
+    stack.borrow_mut().push(Frame {
+      state,
+      stack_pointer: context.stack_pointer(),
+      return_address: context.return_address(),
+    })
+  });
+
+  context.set_return_address(state.landing.load(Ordering::SeqCst));
+  state.trampoline.load(Ordering::SeqCst)
+}
+
+/// Called once the hooked function returns to the landing pad.
+unsafe extern "C" fn leave(state: &State, context: &mut arch::CpuContext) -> usize {
+  let frame = SHADOW_STACK.with(|stack| {
+    let mut stack = stack.borrow_mut();
+
+    // The shadow stack is unwound to the frame of the returning invocation,
+    // discarding any frames above it, which were skipped by a non-local jump.
+    let index = stack.iter().rposition(|frame| {
+      ptr::eq(frame.state, state) && frame.stack_pointer == context.stack_pointer()
+    })?;
+
+    for skipped in stack.drain(index + 1..) {
+      (*skipped.state).active.fetch_sub(1, Ordering::SeqCst);
+    }
+    stack.pop()
+  });
+
+  // Without a frame, the return address is unknown
+  let frame = frame.unwrap_or_else(|| {
+    eprintln!("detour: exit hook shadow stack has no frame to return to, aborting");
+    process::abort();
+  });
+
+  context.set_return_address(frame.return_address);
+  context.hook = 0;
+  context.skip = 0;
+
+  if panic::catch_unwind(AssertUnwindSafe(|| (state.callback)(context))).is_err() {
+    eprintln!("detour: exit hook callback panicked, aborting");
+    process::abort();
+  }
+
+  state.active.fetch_sub(1, Ordering::SeqCst);
+  0
+}
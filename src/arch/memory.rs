@@ -1,21 +1,120 @@
-use crate::{alloc, arch, error::Result, pic};
+use crate::alloc::{ExecutableAllocator, ExecutableMemory, ProximityAllocator};
+use crate::error::{Error, Result};
+use crate::util::{self, StripedLock};
+use crate::{arch, pic};
 use lazy_static::lazy_static;
-use std::sync::Mutex;
+use std::sync::{Arc, MutexGuard, RwLock};
+
+/// The default minimum size of a memory pool (64 KiB).
+const POOL_GRANULARITY: usize = 0x10000;
 
 lazy_static! {
-  /// Shared allocator for all detours.
-  pub static ref POOL: Mutex<alloc::ThreadAllocator> = {
-    // Use a range of +/- 2 GB for seeking a memory block
-    Mutex::new(alloc::ThreadAllocator::new(arch::meta::DETOUR_RANGE))
-  };
+  /// Locks for disassembling and patching targets, indexed by address.
+  static ref TARGET_LOCKS: StripedLock = StripedLock::new(64);
+
+  /// The default allocator.
+  static ref DEFAULT_ALLOCATOR: Arc<ProximityAllocator> =
+    Arc::new(ProximityAllocator::new(POOL_GRANULARITY));
+
+  /// The allocator used by detours, unless specified otherwise.
+  static ref ALLOCATOR: RwLock<Arc<dyn ExecutableAllocator>> =
+    RwLock::new(DEFAULT_ALLOCATOR.clone());
+}
+
+/// Sets the minimum size of the default allocator's memory pools.
+///
+/// Trampolines and relays of nearby targets are packed into shared pools,
+/// which are allocated close to the targets and unmapped once empty. A larger
+/// granularity reduces the number of mappings when hooking many functions,
+/// whilst a smaller one reduces the memory overhead. The default is 64 KiB.
+///
+/// This only affects pools allocated after the call.
+pub fn set_pool_granularity(size: usize) {
+  DEFAULT_ALLOCATOR.set_granularity(size);
+}
+
+/// Sets whether the default allocator uses code caves (i.e padding between
+/// functions), if no memory pool can be allocated close to a target.
+///
+/// Caves are located in the target's executable region, and restored once
+/// released. This is disabled by default.
+pub fn set_code_caves(enabled: bool) {
+  DEFAULT_ALLOCATOR.set_code_caves(enabled);
+}
+
+/// Sets the allocator used by subsequently created detours.
+///
+/// Existing detours continue to use the allocator they were created with,
+/// until they are dropped.
+pub fn set_allocator(allocator: Arc<dyn ExecutableAllocator>) {
+  *util::write_lock(&ALLOCATOR) = allocator;
+}
+
+/// Returns the allocator used by detours, unless specified otherwise.
+pub fn allocator() -> Arc<dyn ExecutableAllocator> {
+  util::read_lock(&ALLOCATOR).clone()
+}
+
+/// Acquires the lock of a target, so it's not patched whilst being read or
+/// patched by another thread.
+pub fn lock_target(target: *const ()) -> MutexGuard<'static, ()> {
+  TARGET_LOCKS.lock(target as usize / arch::meta::ALIGNMENT)
 }
 
 /// Allocates PIC code at the specified address.
 pub fn allocate_pic(
-  pool: &mut alloc::ThreadAllocator,
+  allocator: &Arc<dyn ExecutableAllocator>,
+  emitter: &pic::CodeEmitter,
+  origin: *const (),
+) -> Result<ExecutableMemory> {
+  let memory = allocate(allocator, emitter, origin, arch::meta::DETOUR_RANGE)?;
+
+  // Custom allocators are not trusted to respect the range
+  let lower = (memory.as_ptr() as isize).wrapping_sub(origin as isize);
+  let upper = lower.wrapping_add(emitter.len() as isize);
+  if !arch::is_within_range(lower) || !arch::is_within_range(upper) {
+    Err(Error::OutOfMemory)?;
+  }
+
+  emit(memory, emitter)
+}
+
+/// Allocates PIC code within `max_distance` bytes of the origin.
+pub fn allocate_pic_within(
+  allocator: &Arc<dyn ExecutableAllocator>,
+  emitter: &pic::CodeEmitter,
+  origin: *const (),
+  max_distance: usize,
+) -> Result<ExecutableMemory> {
+  let memory = allocate(allocator, emitter, origin, max_distance)?;
+
+  // Custom allocators are not trusted to respect the range
+  let lower = (memory.as_ptr() as usize).wrapping_sub(origin as usize) as isize;
+  let upper = lower.wrapping_add(emitter.len() as isize);
+  if lower.unsigned_abs() > max_distance || upper.unsigned_abs() > max_distance {
+    Err(Error::OutOfMemory)?;
+  }
+
+  emit(memory, emitter)
+}
+
+/// Allocates PIC code, which does not refer to the origin relatively, at any
+/// distance from the origin (preferably close to it).
+pub fn allocate_pic_anywhere(
+  allocator: &Arc<dyn ExecutableAllocator>,
   emitter: &pic::CodeEmitter,
   origin: *const (),
-) -> Result<alloc::ExecutableMemory> {
+) -> Result<ExecutableMemory> {
+  emit(allocate(allocator, emitter, origin, usize::MAX)?, emitter)
+}
+
+/// Allocates aligned memory for an emitter's code.
+fn allocate(
+  allocator: &Arc<dyn ExecutableAllocator>,
+  emitter: &pic::CodeEmitter,
+  origin: *const (),
+  max_distance: usize,
+) -> Result<ExecutableMemory> {
   // Ensure alignment
   let size = if emitter.len() % arch::meta::ALIGNMENT != 0 {
     (emitter.len() / arch::meta::ALIGNMENT + 1) * arch::meta::ALIGNMENT
@@ -24,11 +123,19 @@ pub fn allocate_pic(
   };
   // Allocate memory close to the origin
   log::debug!("allocating {} bytes close to {:?}", size, origin);
-  let mut memory = pool.allocate(origin, size)?;
-  memory.modify(|data| {
-    // Generate code for the obtained address
-    let code = emitter.emit(data.as_ptr() as *const _);
-    data[..code.len()].copy_from_slice(code.as_slice());
-  })?;
+  ExecutableMemory::allocate(allocator.clone(), origin, size, max_distance)
+}
+
+/// Generates and writes an emitter's code for the allocated address.
+fn emit(memory: ExecutableMemory, emitter: &pic::CodeEmitter) -> Result<ExecutableMemory> {
+  let code = emitter.emit(memory.as_ptr() as *const _);
+  memory.write(0, &code)?;
+
+  // The code may reuse memory which has previously been executed
+  #[cfg(target_arch = "aarch64")]
+  unsafe {
+    arch::meta::clear_instruction_cache(&memory);
+  }
+
   Ok(memory)
 }
@@ -3,15 +3,20 @@
 /// The current implementation requires a module to expose some functionality:
 ///
 /// - A standalone `relay_builder` function.
-/// This function creates a relay for targets with large displacement, that
-/// requires special attention. An example would be detours further away than
-/// 2GB on x64. A relative jump is not enough, so the `relay_builder`
-/// generates an absolute jump that the relative jump can reach. If it's
-/// needless, `None` can   be returned.
+/// This function creates a relay that every target is redirected to, which
+/// branches to the address stored in its destination slot (located at
+/// `RELAY_SLOT_OFFSET`). This allows the detour to be changed without
+/// patching the target, and handles targets with large displacement, e.g
+/// detours further away than 2GB on x64.
 ///
 /// - A `Patcher`, modifies a target in-memory.
-/// - A `Trampoline`, generates a callable address to the target.
-pub use self::detour::Detour;
+/// - A `Trampoline`, generates a callable address to the target. It can also
+/// be built from a buffer of code, which allows code to be relocated for any
+/// architecture, regardless of the host (see `relocate`).
+pub use self::callsite::CallSite;
+pub use self::detour::{create_clone, create_trampoline, Detour};
+pub use self::memory::{set_allocator, set_code_caves, set_pool_granularity};
+pub use self::relocate::{relocate, Architecture};
 
 use cfg_if::cfg_if;
 
@@ -19,18 +24,61 @@ use cfg_if::cfg_if;
 // See: https://github.com/llvm-mirror/compiler-rt/blob/master/lib/builtins/clear_cache.c
 cfg_if! {
     if #[cfg(any(target_arch = "x86", target_arch = "x86_64"))] {
-        mod x86;
-        use self::x86::{Patcher, Trampoline, meta};
+        use self::x86::{Patcher, Trampoline, clone, meta};
+        #[cfg(all(target_arch = "x86_64", unix))]
+        pub use self::x86::CpuContext;
+        #[cfg(target_os = "linux")]
+        pub use self::x86::{Access, AccessKind, Watch};
     } else if #[cfg(any(target_arch = "aarch64"))] {
-        mod aarch64;
-        use self::aarch64::{Patcher, Trampoline, meta};
+        use self::aarch64::{Patcher, Trampoline, clone, meta};
+        pub use self::aarch64::CpuContext;
     } else {
         // TODO: Implement ARM/AARCH64/MIPS support!
     }
 }
 
+cfg_if! {
+    if #[cfg(any(all(target_arch = "x86_64", unix), target_arch = "aarch64"))] {
+        mod context;
+        mod exit;
+        pub use self::context::{ContextHook, ContextPanicPolicy};
+        pub use self::exit::ExitHook;
+    }
+}
+
+cfg_if! {
+    if #[cfg(all(
+      target_os = "linux",
+      any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")
+    ))] {
+        mod breakpoint;
+        mod signal;
+        pub use self::breakpoint::Breakpoint;
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub mod bounds;
+mod callsite;
 mod detour;
 mod memory;
+mod relocate;
+
+// Every backend is compiled, so code can be relocated for any architecture
+mod aarch64;
+mod x86;
+
+/// Returns true if the slice only contains instructions padding functions.
+///
+/// Unlike the padding of patch areas, zeroes are excluded, since they're
+/// indistinguishable from data embedded in the code (e.g constants).
+pub fn is_cave_padding(buffer: &[u8]) -> bool {
+  let size = meta::CAVE_PADDING[0].len();
+  buffer.len() % size == 0
+    && buffer
+      .chunks_exact(size)
+      .all(|instruction| meta::CAVE_PADDING.contains(&instruction))
+}
 
 /// Returns true if the displacement is within a certain range.
 pub fn is_within_range(displacement: isize) -> bool {
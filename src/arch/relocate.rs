@@ -0,0 +1,385 @@
+use super::{aarch64, x86};
+use crate::error::{Error, Result};
+use std::convert::TryFrom;
+
+/// An instruction set, which code can be relocated for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Architecture {
+  /// 32-bit x86.
+  X86,
+  /// 64-bit x86 (i.e x86-64).
+  X64,
+  /// 64-bit ARM.
+  AArch64,
+}
+
+/// Relocates code located at `from`, so it can be executed at `to`.
+///
+/// This generates a trampoline offline: the instructions in `code` are
+/// copied, with any relative operands adjusted for `to` (or rewritten to
+/// absolute addresses, if out of range), and followed by a jump to the
+/// instruction succeeding them. The code must consist of whole instructions,
+/// although relocation stops early if it returns unconditionally.
+///
+/// Neither address needs to be mapped, and the architecture may differ from
+/// the host's, e.g to generate trampolines on a build machine. On 32-bit
+/// hosts, addresses above 4 GiB are rejected with `UnsupportedAddress`.
+///
+/// # Example
+///
+/// ```rust
+/// # use detour::Result;
+/// use detour::{relocate, Architecture};
+///
+/// # fn main() -> Result<()> {
+/// // mov eax, [rip+0x10]
+/// let code = [0x8B, 0x05, 0x10, 0x00, 0x00, 0x00];
+/// let trampoline = relocate(Architecture::X64, &code, 0x1000, 0x2000)?;
+///
+/// // mov eax, [rip-0xFF0], followed by a jump to 0x1006
+/// assert_eq!(&trampoline[..6], &[0x8B, 0x05, 0x10, 0xF0, 0xFF, 0xFF]);
+/// # Ok(())
+/// # }
+/// ```
+pub fn relocate(architecture: Architecture, code: &[u8], from: u64, to: u64) -> Result<Vec<u8>> {
+  let from = usize::try_from(from).map_err(|_| Error::UnsupportedAddress)?;
+  let to = usize::try_from(to).map_err(|_| Error::UnsupportedAddress)?;
+
+  Ok(match architecture {
+    Architecture::X86 => {
+      x86::Trampoline::from_code(code, from, 32, code.len(), x86::Operands::Relative)?
+        .emitter()
+        .emit(to as *const ())
+    },
+    Architecture::X64 => {
+      // RIP relative operands out of range from `to` use absolute addresses
+      let operands = x86::Operands::LocatedAt(to);
+      x86::Trampoline::from_code(code, from, 64, code.len(), operands)?
+        .emitter()
+        .emit(to as *const ())
+    },
+    Architecture::AArch64 => aarch64::Trampoline::from_code(code, from, code.len())?
+      .emitter()
+      .emit(to as *const ()),
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::error::Error;
+  use matches::assert_matches;
+
+  /// Concatenates instructions, and trailing data, into a buffer.
+  fn code(parts: &[&[u8]]) -> Vec<u8> {
+    parts.concat()
+  }
+
+  /// Encodes AArch64 instructions.
+  fn a64(opcodes: &[u32]) -> Vec<u8> {
+    opcodes
+      .iter()
+      .flat_map(|opcode| opcode.to_le_bytes())
+      .collect()
+  }
+
+  #[test]
+  fn x64_rip_relative_operand() -> Result<()> {
+    // mov eax, [rip+0x10]
+    let trampoline = relocate(
+      Architecture::X64,
+      &[0x8B, 0x05, 0x10, 0x00, 0x00, 0x00],
+      0x1000,
+      0x2000,
+    )?;
+
+    assert_eq!(
+      trampoline,
+      code(&[
+        // mov eax, [rip-0xFF0]
+        &[0x8B, 0x05, 0x10, 0xF0, 0xFF, 0xFF],
+        // jmp [rip+0]
+        &[0xFF, 0x25, 0x00, 0x00, 0x00, 0x00],
+        &0x1006u64.to_le_bytes(),
+      ])
+    );
+    Ok(())
+  }
+
+  #[test]
+  fn x64_rip_relative_operand_out_of_range() -> Result<()> {
+    // mov eax, [rip+0x10]
+    let trampoline = relocate(
+      Architecture::X64,
+      &[0x8B, 0x05, 0x10, 0x00, 0x00, 0x00],
+      0x1000,
+      0x1_0000_0000,
+    )?;
+
+    assert_eq!(
+      trampoline,
+      code(&[
+        // lea rsp, [rsp-0x80]; push rsi; mov rsi, 0x1016
+        &[0x48, 0x8D, 0x64, 0x24, 0x80, 0x56, 0x48, 0xBE],
+        &0x1016u64.to_le_bytes(),
+        // mov eax, [rsi]; pop rsi; lea rsp, [rsp+0x80]
+        &[0x8B, 0x06, 0x5E, 0x48, 0x8D, 0xA4, 0x24, 0x80, 0x00, 0x00, 0x00],
+        // jmp [rip+0]
+        &[0xFF, 0x25, 0x00, 0x00, 0x00, 0x00],
+        &0x1006u64.to_le_bytes(),
+      ])
+    );
+    Ok(())
+  }
+
+  #[test]
+  fn x64_rip_relative_operand_out_of_range_from_destination() -> Result<()> {
+    // mov eax, [rip+0x7FFFFFF0]
+    let trampoline = relocate(
+      Architecture::X64,
+      &[0x8B, 0x05, 0xF0, 0xFF, 0xFF, 0x7F],
+      0x8000_0000,
+      0x7000_0000,
+    )?;
+
+    assert_eq!(
+      trampoline,
+      code(&[
+        // lea rsp, [rsp-0x80]; push rsi; mov rsi, 0xFFFFFFF6
+        &[0x48, 0x8D, 0x64, 0x24, 0x80, 0x56, 0x48, 0xBE],
+        &0xFFFF_FFF6u64.to_le_bytes(),
+        // mov eax, [rsi]; pop rsi; lea rsp, [rsp+0x80]
+        &[0x8B, 0x06, 0x5E, 0x48, 0x8D, 0xA4, 0x24, 0x80, 0x00, 0x00, 0x00],
+        // jmp [rip+0]
+        &[0xFF, 0x25, 0x00, 0x00, 0x00, 0x00],
+        &0x8000_0006u64.to_le_bytes(),
+      ])
+    );
+    Ok(())
+  }
+
+  #[test]
+  fn x64_rip_relative_operand_scratch_fallback() -> Result<()> {
+    // mov esi, [rip+0x10]
+    let trampoline = relocate(
+      Architecture::X64,
+      &[0x8B, 0x35, 0x10, 0x00, 0x00, 0x00],
+      0x1000,
+      0x1_0000_0000,
+    )?;
+
+    // The operand uses `rsi`, so `rdi` is used as the scratch register instead
+    assert_eq!(
+      trampoline,
+      code(&[
+        // lea rsp, [rsp-0x80]; push rdi; mov rdi, 0x1016
+        &[0x48, 0x8D, 0x64, 0x24, 0x80, 0x57, 0x48, 0xBF],
+        &0x1016u64.to_le_bytes(),
+        // mov esi, [rdi]; pop rdi; lea rsp, [rsp+0x80]
+        &[0x8B, 0x37, 0x5F, 0x48, 0x8D, 0xA4, 0x24, 0x80, 0x00, 0x00, 0x00],
+        // jmp [rip+0]
+        &[0xFF, 0x25, 0x00, 0x00, 0x00, 0x00],
+        &0x1006u64.to_le_bytes(),
+      ])
+    );
+    Ok(())
+  }
+
+  #[test]
+  fn x64_rip_relative_operand_out_of_range_unsupported() {
+    let relocate = |code: &[u8]| relocate(Architecture::X64, code, 0x1000, 0x1_0000_0000);
+
+    // mov rsp, [rip+0x10]; the stack pointer is adjusted to save the scratch
+    // register
+    assert_matches!(
+      relocate(&[0x48, 0x8B, 0x25, 0x10, 0x00, 0x00, 0x00]),
+      Err(Error::UnsupportedInstruction)
+    );
+
+    // andn esi, edi, [rip+0x10]; no instruction with a RIP-relative operand
+    // uses `rsi`, `rdi` and `rbx` at once, since only those with a VEX prefix
+    // (e.g BMI) use two registers besides it, and they're never rewritten
+    assert_matches!(
+      relocate(&[0xC4, 0xE2, 0x40, 0xF2, 0x35, 0x10, 0x00, 0x00, 0x00]),
+      Err(Error::UnsupportedInstruction)
+    );
+  }
+
+  #[test]
+  fn x64_rip_relative_operands_in_and_out_of_range() -> Result<()> {
+    // mov eax, [rip+0x10]; mov ecx, [rip+0x7FFFFFF0]
+    let trampoline = relocate(
+      Architecture::X64,
+      &[
+        0x8B, 0x05, 0x10, 0x00, 0x00, 0x00, 0x8B, 0x0D, 0xF0, 0xFF, 0xFF, 0x7F,
+      ],
+      0x8000_0000,
+      0x7000_0000,
+    )?;
+
+    assert_eq!(
+      trampoline,
+      code(&[
+        // mov eax, [rip+0x10000010]
+        &[0x8B, 0x05, 0x10, 0x00, 0x00, 0x10],
+        // lea rsp, [rsp-0x80]; push rsi; mov rsi, 0xFFFFFFFC
+        &[0x48, 0x8D, 0x64, 0x24, 0x80, 0x56, 0x48, 0xBE],
+        &0xFFFF_FFFCu64.to_le_bytes(),
+        // mov ecx, [rsi]; pop rsi; lea rsp, [rsp+0x80]
+        &[0x8B, 0x0E, 0x5E, 0x48, 0x8D, 0xA4, 0x24, 0x80, 0x00, 0x00, 0x00],
+        // jmp [rip+0]
+        &[0xFF, 0x25, 0x00, 0x00, 0x00, 0x00],
+        &0x8000_000Cu64.to_le_bytes(),
+      ])
+    );
+    Ok(())
+  }
+
+  #[test]
+  fn x64_relative_branches() -> Result<()> {
+    // call 0x1100; jz 0x1017
+    let trampoline = relocate(
+      Architecture::X64,
+      &[0xE8, 0xFB, 0x00, 0x00, 0x00, 0x74, 0x10],
+      0x1000,
+      0x8000,
+    )?;
+
+    assert_eq!(
+      trampoline,
+      code(&[
+        // call [rip+2]; jmp +8
+        &[0xFF, 0x15, 0x02, 0x00, 0x00, 0x00, 0xEB, 0x08],
+        &0x1100u64.to_le_bytes(),
+        // jnz +14; jmp [rip+0]
+        &[0x75, 0x0E, 0xFF, 0x25, 0x00, 0x00, 0x00, 0x00],
+        &0x1017u64.to_le_bytes(),
+        // jmp [rip+0]
+        &[0xFF, 0x25, 0x00, 0x00, 0x00, 0x00],
+        &0x1007u64.to_le_bytes(),
+      ])
+    );
+    Ok(())
+  }
+
+  #[test]
+  fn x86_relative_branches() -> Result<()> {
+    // push ebp; mov ebp, esp; call 0x1100
+    let trampoline = relocate(
+      Architecture::X86,
+      &[0x55, 0x89, 0xE5, 0xE8, 0xF8, 0x00, 0x00, 0x00],
+      0x1000,
+      0x2000,
+    )?;
+
+    assert_eq!(
+      trampoline,
+      code(&[
+        &[0x55, 0x89, 0xE5],
+        // call 0x1100
+        &[0xE8, 0xF8, 0xF0, 0xFF, 0xFF],
+        // jmp 0x1008
+        &[0xE9, 0xFB, 0xEF, 0xFF, 0xFF],
+      ])
+    );
+    Ok(())
+  }
+
+  #[test]
+  fn x86_relative_branches_wrap_around() -> Result<()> {
+    // push ebp; mov ebp, esp; call 0x1100
+    let trampoline = relocate(
+      Architecture::X86,
+      &[0x55, 0x89, 0xE5, 0xE8, 0xF8, 0x00, 0x00, 0x00],
+      0x1000,
+      0xF000_0000,
+    )?;
+
+    assert_eq!(
+      trampoline,
+      code(&[
+        &[0x55, 0x89, 0xE5],
+        // call 0x1100
+        &[0xE8, 0xF8, 0x10, 0x00, 0x10],
+        // jmp 0x1008
+        &[0xE9, 0xFB, 0x0F, 0x00, 0x10],
+      ])
+    );
+    Ok(())
+  }
+
+  #[test]
+  fn x86_internal_branch() -> Result<()> {
+    // test ecx, ecx; jz +1; inc eax; ret
+    let trampoline = relocate(
+      Architecture::X86,
+      &[0x85, 0xC9, 0x74, 0x01, 0x40, 0xC3],
+      0x1000,
+      0x2000,
+    )?;
+
+    // The branch remains within the prolog, and the function returns
+    assert_eq!(trampoline, [0x85, 0xC9, 0x74, 0x01, 0x40, 0xC3]);
+    Ok(())
+  }
+
+  #[test]
+  fn aarch64_prolog() -> Result<()> {
+    // stp x29, x30, [sp, #-16]!; mov x29, sp
+    let trampoline = relocate(
+      Architecture::AArch64,
+      &a64(&[0xA9BF_7BFD, 0x9100_03FD]),
+      0x1000,
+      0x2000,
+    )?;
+
+    assert_eq!(
+      trampoline,
+      code(&[
+        &a64(&[0xA9BF_7BFD, 0x9100_03FD]),
+        // ldr x17, #8; br x17
+        &a64(&[0x5800_0051, 0xD61F_0220]),
+        &0x1008u64.to_le_bytes(),
+      ])
+    );
+    Ok(())
+  }
+
+  #[test]
+  fn aarch64_pc_relative_operands() -> Result<()> {
+    // adr x0, #0x10; adrp x1, #0x5000; ldr x2, #0x20
+    let trampoline = relocate(
+      Architecture::AArch64,
+      &a64(&[0x1000_0080, 0xB000_0021, 0x5800_0102]),
+      0x1000,
+      0x10000,
+    )?;
+
+    assert_eq!(
+      trampoline,
+      code(&[
+        // adr x0, #-0xEFF0; adrp x1, #-0xA000; ldr x2, #-0xEFE0
+        &a64(&[0x10F8_8080, 0xD0FF_FFA1, 0x58F8_8102]),
+        // ldr x17, #8; br x17
+        &a64(&[0x5800_0051, 0xD61F_0220]),
+        &0x100Cu64.to_le_bytes(),
+      ])
+    );
+    Ok(())
+  }
+
+  #[test]
+  fn rejects_invalid_code() {
+    // mov eax, [rip+... (truncated)
+    assert_matches!(
+      relocate(Architecture::X64, &[0x8B, 0x05, 0x10], 0x1000, 0x2000),
+      Err(Error::InvalidCode)
+    );
+
+    // bl #0x100
+    assert_matches!(
+      relocate(Architecture::AArch64, &a64(&[0x9400_0040]), 0x1000, 0x2000),
+      Err(Error::UnsupportedInstruction)
+    );
+  }
+}
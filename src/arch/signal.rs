@@ -0,0 +1,87 @@
+//! Process-wide signal handlers, chained with previously installed ones.
+use std::cell::UnsafeCell;
+use std::mem::{self, MaybeUninit};
+use std::ptr;
+use std::sync::Once;
+
+/// The prototype of a signal handler, receiving the signal information and
+/// the thread's context.
+pub type Action = unsafe extern "C" fn(libc::c_int, *mut libc::siginfo_t, *mut libc::c_void);
+
+/// A signal handler, which forwards unrelated signals to the handler installed
+/// prior to it.
+///
+/// Once installed, the handler remains installed, since a signal may be
+/// delivered after the condition that raised it no longer applies.
+pub struct Handler {
+  install: Once,
+  previous: UnsafeCell<MaybeUninit<libc::sigaction>>,
+}
+
+impl Handler {
+  /// Creates a handler, yet to be installed.
+  pub const fn new() -> Self {
+    Handler {
+      install: Once::new(),
+      previous: UnsafeCell::new(MaybeUninit::uninit()),
+    }
+  }
+
+  /// Installs `action` as the handler of `signal`, if not already installed.
+  pub fn install(&self, signal: libc::c_int, action: Action) {
+    self.install.call_once(|| unsafe {
+      let mut handler: libc::sigaction = mem::zeroed();
+      handler.sa_sigaction = action as *const () as usize;
+      handler.sa_flags = libc::SA_SIGINFO | libc::SA_RESTART;
+      libc::sigemptyset(&mut handler.sa_mask);
+
+      let result = libc::sigaction(signal, &handler, (*self.previous.get()).as_mut_ptr());
+      assert_eq!(result, 0, "installing signal handler");
+    });
+  }
+
+  /// Forwards a signal unrelated to this handler to the previous handler.
+  pub unsafe fn forward(
+    &self,
+    signal: libc::c_int,
+    info: *mut libc::siginfo_t,
+    context: *mut libc::c_void,
+  ) {
+    let previous = &*(*self.previous.get()).as_ptr();
+
+    match previous.sa_sigaction {
+      libc::SIG_IGN => (),
+      libc::SIG_DFL => {
+        // The signal is blocked within the handler, so it's delivered, with its
+        // default disposition, once the handler returns
+        libc::sigaction(signal, previous, ptr::null_mut());
+        libc::raise(signal);
+      },
+      handler if previous.sa_flags & libc::SA_SIGINFO != 0 => {
+        let handler: Action = mem::transmute(handler);
+        handler(signal, info, context);
+      },
+      handler => {
+        let handler: extern "C" fn(libc::c_int) = mem::transmute(handler);
+        handler(signal);
+      },
+    }
+  }
+}
+
+unsafe impl Sync for Handler {}
+
+/// Writes a message to the standard error, and aborts the process.
+///
+/// Only async-signal-safe functions are used, so this may be called within a
+/// signal handler.
+pub fn abort(message: &str) -> ! {
+  unsafe {
+    libc::write(
+      libc::STDERR_FILENO,
+      message.as_ptr() as *const libc::c_void,
+      message.len(),
+    );
+    libc::abort()
+  }
+}
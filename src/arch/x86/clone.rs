@@ -0,0 +1,134 @@
+//! Relocation of whole functions (x86/x64).
+//!
+//! The function is copied as-is, so its layout, including any internal
+//! branches, is preserved. Only operands referring to code or data outside of
+//! the function are adjusted for the clone's location.
+//!
+//! Jump tables (e.g of `switch` statements) are usually located outside of
+//! the function and hold addresses within it, either absolute or relative to
+//! the table, so the clone would branch into the original function. They
+//! cannot be adjusted, so functions dispatching via them are rejected.
+use super::meta;
+use super::trampoline::disasm::{Disassembler, Instruction};
+use crate::error::{Error, Result};
+use crate::pic;
+use std::{mem, slice};
+
+/// A 32-bit displacement referring to an address outside of the function.
+struct Fixup {
+  /// The offset of the displacement within the function.
+  offset: usize,
+  /// The offset of the next instruction, which the displacement is relative
+  /// to.
+  origin: usize,
+  /// The absolute address referred to.
+  destination: usize,
+}
+
+/// Creates a clone of a function of `size` bytes.
+///
+/// Returns the clone's emitter, and the furthest distance from the function it
+/// may be located at.
+pub unsafe fn builder(function: *const (), size: usize) -> Result<(pic::CodeEmitter, usize)> {
+  let start = function as usize;
+  let bounds = start..start + size;
+
+  let mut disassembler = Disassembler::new(function);
+  let mut fixups = Vec::new();
+  let mut previous: Option<Instruction> = None;
+  let mut length = 0;
+
+  while length < size {
+    let instruction = Instruction::new(&mut disassembler).ok_or(Error::InvalidCode)?;
+    length += instruction.len();
+
+    if instruction.is_unconditional_jump()
+      && is_jump_table_dispatch(&instruction, previous.as_ref())
+    {
+      Err(Error::UnsupportedInstruction)?;
+    }
+    let instruction = &*previous.insert(instruction);
+
+    let (displacement, offset) = if let Some(displacement) = instruction.rip_operand_displacement()
+    {
+      // The displacement of a RIP relative operand follows the ModR/M byte
+      let modrm = instruction
+        .modrm_offset()
+        .ok_or(Error::UnsupportedInstruction)?;
+      (displacement, Some(modrm + 1))
+    } else if let Some(displacement) = instruction.relative_branch_displacement() {
+      // Short branches (e.g `jmp rel8`, `loop`) are not rewritten, since they
+      // would be unable to reach beyond the function
+      let is_near = instruction.relative_branch_size() == Some(mem::size_of::<u32>());
+      (
+        displacement,
+        is_near.then(|| instruction.len() - mem::size_of::<u32>()),
+      )
+    } else {
+      continue;
+    };
+
+    let destination = instruction
+      .next_instruction_address()
+      .wrapping_add(displacement as usize);
+
+    // Internal references remain valid, since the layout is preserved
+    if bounds.contains(&destination) {
+      continue;
+    }
+
+    let offset = offset.ok_or(Error::UnsupportedInstruction)?;
+    fixups.push(Fixup {
+      offset: instruction.address() - start + offset,
+      origin: instruction.next_instruction_address() - start,
+      destination,
+    });
+  }
+
+  // The clone must be close enough for every displacement to remain in range
+  let max_distance = fixups
+    .iter()
+    .map(|fixup| {
+      let displacement = fixup.destination.wrapping_sub(start + fixup.origin) as isize;
+      meta::DETOUR_RANGE
+        .saturating_sub(displacement.unsigned_abs())
+        .saturating_sub(length)
+    })
+    .min()
+    .unwrap_or(usize::MAX);
+
+  let code = slice::from_raw_parts(function as *const u8, length).to_vec();
+  let mut emitter = pic::CodeEmitter::new();
+  emitter.add_thunk(Box::new(pic::UnsafeThunk::new(
+    move |base| {
+      let mut code = code.clone();
+      for fixup in &fixups {
+        let displacement = fixup.destination.wrapping_sub(base + fixup.origin) as isize;
+        assert!(crate::arch::is_within_range(displacement));
+
+        let operand = &mut code[fixup.offset..fixup.offset + mem::size_of::<u32>()];
+        operand.copy_from_slice(&(displacement as i32).to_le_bytes());
+      }
+      code
+    },
+    length,
+  )));
+
+  Ok((emitter, max_distance))
+}
+
+/// Returns true if an indirect jump dispatches via a jump table, either of
+/// absolute addresses (i.e `jmp [table+reg*N]`), or of offsets relative to
+/// the table (i.e `add reg, table; jmp reg`).
+fn is_jump_table_dispatch(jump: &Instruction, previous: Option<&Instruction>) -> bool {
+  if jump.has_indexed_memory_operand() {
+    return true;
+  }
+
+  match (jump.first_register_operand(), previous) {
+    (Some(register), Some(previous)) => {
+      previous.is_add() && previous.first_register_operand() == Some(register)
+    },
+    _ => false,
+  }
+}
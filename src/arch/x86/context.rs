@@ -0,0 +1,330 @@
+use std::mem;
+
+/// The register state of a thread entering or leaving a hooked function.
+///
+/// Only the registers used for passing arguments and return values in the
+/// System V ABI are captured.
+#[repr(C)]
+#[derive(Debug, Clone)]
+pub struct CpuContext {
+  /// Integer argument registers, in the order they are assigned by the ABI.
+  pub rdi: u64,
+  pub rsi: u64,
+  pub rdx: u64,
+  pub rcx: u64,
+  pub r8: u64,
+  pub r9: u64,
+  /// The integer return value (or vector register count for variadics).
+  pub rax: u64,
+  /// The static chain pointer.
+  pub r10: u64,
+  /// Vector argument and return registers (`xmm0`-`xmm7`).
+  pub xmm: [u128; 8],
+  /// The stack pointer upon entry to the function (i.e at the return address).
+  pub rsp: u64,
+  /// The address the function returns to.
+  pub return_address: u64,
+  /// The state of the context hook that captured the context, if any.
+  pub(crate) hook: usize,
+  /// Whether the original function should be skipped.
+  pub(crate) skip: usize,
+}
+
+impl CpuContext {
+  /// Returns the stack pointer upon entry to the function.
+  pub fn stack_pointer(&self) -> usize {
+    self.rsp as usize
+  }
+
+  /// Returns the address the function returns to.
+  pub fn return_address(&self) -> usize {
+    self.return_address as usize
+  }
+
+  /// Sets the address the function returns to.
+  pub fn set_return_address(&mut self, address: usize) {
+    self.return_address = address as u64;
+  }
+
+  /// Returns the integer return value register (`rax`).
+  pub fn return_value(&self) -> u64 {
+    self.rax
+  }
+
+  /// Sets the integer return value register (`rax`).
+  pub fn set_return_value(&mut self, value: u64) {
+    self.rax = value;
+  }
+
+  /// Returns the floating point return value register (`xmm0`).
+  pub fn float_return_value(&self) -> f64 {
+    f64::from_bits(self.xmm[0] as u64)
+  }
+
+  /// Sets the floating point return value register (`xmm0`).
+  pub fn set_float_return_value(&mut self, value: f64) {
+    self.xmm[0] = u128::from(value.to_bits());
+  }
+
+  /// Returns the N-th integer argument.
+  ///
+  /// Arguments beyond the sixth are read from the stack, assuming that all
+  /// preceding stack arguments are integers as well.
+  pub unsafe fn arg(&self, index: usize) -> u64 {
+    match index {
+      0 => self.rdi,
+      1 => self.rsi,
+      2 => self.rdx,
+      3 => self.rcx,
+      4 => self.r8,
+      5 => self.r9,
+      _ => *self.stack_argument(index - 6),
+    }
+  }
+
+  /// Overwrites the N-th integer argument.
+  ///
+  /// Arguments beyond the sixth are written to the stack, assuming that all
+  /// preceding stack arguments are integers as well.
+  pub unsafe fn set_arg(&mut self, index: usize, value: u64) {
+    match index {
+      0 => self.rdi = value,
+      1 => self.rsi = value,
+      2 => self.rdx = value,
+      3 => self.rcx = value,
+      4 => self.r8 = value,
+      5 => self.r9 = value,
+      _ => *self.stack_argument(index - 6) = value,
+    }
+  }
+
+  /// Returns the N-th floating point argument.
+  ///
+  /// Arguments beyond the eighth are read from the stack, assuming that all
+  /// preceding stack arguments are floating point values as well.
+  pub unsafe fn float_arg(&self, index: usize) -> f64 {
+    match self.xmm.get(index) {
+      Some(&value) => f64::from_bits(value as u64),
+      None => f64::from_bits(*self.stack_argument(index - 8)),
+    }
+  }
+
+  /// Overwrites the N-th floating point argument.
+  ///
+  /// Arguments beyond the eighth are written to the stack, assuming that all
+  /// preceding stack arguments are floating point values as well.
+  pub unsafe fn set_float_arg(&mut self, index: usize, value: f64) {
+    match self.xmm.get_mut(index) {
+      Some(register) => *register = u128::from(value.to_bits()),
+      None => *self.stack_argument(index - 8) = value.to_bits(),
+    }
+  }
+
+  /// Returns a pointer to the N-th argument passed on the stack.
+  pub fn stack_argument(&self, index: usize) -> *mut u64 {
+    // The first stack argument is located after the return address
+    (self.rsp as *mut u64).wrapping_add(index + 1)
+  }
+}
+
+/// The stack space reserved for the context (keeps the stack 16-byte aligned).
+const FRAME_SIZE: u32 = (mem::size_of::<CpuContext>() + 8) as u32;
+
+/// Offsets of the general purpose registers, in `CpuContext` order, paired
+/// with their register number.
+const REGISTERS: [(u8, u32); 8] = [
+  (7, 0),   // rdi
+  (6, 8),   // rsi
+  (2, 16),  // rdx
+  (1, 24),  // rcx
+  (8, 32),  // r8
+  (9, 40),  // r9
+  (0, 48),  // rax
+  (10, 56), // r10
+];
+const XMM_OFFSET: u32 = 64;
+const RSP_OFFSET: u32 = 192;
+const RETURN_ADDRESS_OFFSET: u32 = 200;
+
+/// Generates a stub which captures the thread's context and invokes a
+/// handler with it.
+///
+/// The handler is called as `extern "C" fn(state, &mut CpuContext) -> usize`.
+/// Once it returns, the (possibly modified) context is restored. If the
+/// returned address is non-zero the stub jumps to it, otherwise it returns to
+/// `CpuContext::return_address`.
+///
+/// If `returning` is set, the stub is entered by a `ret` instead of a `call`
+/// and reserves a slot for the return address before capturing the context.
+pub fn stub(state: usize, handler: usize, returning: bool) -> Vec<u8> {
+  let mut code = Vec::new();
+
+  if returning {
+    // push rax (a placeholder for the return address)
+    code.push(0x50);
+  }
+
+  // sub rsp, FRAME_SIZE
+  code.extend(&[0x48, 0x81, 0xEC]);
+  code.extend(&FRAME_SIZE.to_le_bytes());
+
+  for &(register, offset) in REGISTERS.iter() {
+    code.extend(&store(register, offset));
+  }
+
+  for index in 0..8 {
+    // movdqu [rsp+offset], xmmN
+    code.extend(&movdqu(0x7F, index, XMM_OFFSET + u32::from(index) * 16));
+  }
+
+  // lea rax, [rsp+FRAME_SIZE]
+  code.extend(&[0x48, 0x8D, 0x84, 0x24]);
+  code.extend(&FRAME_SIZE.to_le_bytes());
+  code.extend(&store(0, RSP_OFFSET));
+  // mov rax, [rax]
+  code.extend(&[0x48, 0x8B, 0x00]);
+  code.extend(&store(0, RETURN_ADDRESS_OFFSET));
+
+  // mov rdi, state
+  code.extend(&[0x48, 0xBF]);
+  code.extend(&state.to_le_bytes());
+  // mov rsi, rsp
+  code.extend(&[0x48, 0x89, 0xE6]);
+  // mov rax, handler
+  code.extend(&[0x48, 0xB8]);
+  code.extend(&handler.to_le_bytes());
+  // call rax
+  code.extend(&[0xFF, 0xD0]);
+  // mov r11, rax
+  code.extend(&[0x49, 0x89, 0xC3]);
+
+  // Write back the (possibly modified) return address
+  code.extend(&load(0, RETURN_ADDRESS_OFFSET));
+  code.extend(&store(0, FRAME_SIZE));
+
+  for index in 0..8 {
+    // movdqu xmmN, [rsp+offset]
+    code.extend(&movdqu(0x6F, index, XMM_OFFSET + u32::from(index) * 16));
+  }
+
+  for &(register, offset) in REGISTERS.iter() {
+    code.extend(&load(register, offset));
+  }
+
+  // add rsp, FRAME_SIZE
+  code.extend(&[0x48, 0x81, 0xC4]);
+  code.extend(&FRAME_SIZE.to_le_bytes());
+
+  // test r11, r11
+  code.extend(&[0x4D, 0x85, 0xDB]);
+  // jz +3
+  code.extend(&[0x74, 0x03]);
+  // jmp r11
+  code.extend(&[0x41, 0xFF, 0xE3]);
+  // ret
+  code.push(0xC3);
+  code
+}
+
+/// Encodes `mov [rsp+offset], reg`.
+fn store(register: u8, offset: u32) -> Vec<u8> {
+  stack_operand(0x89, register, offset)
+}
+
+/// Encodes `mov reg, [rsp+offset]`.
+fn load(register: u8, offset: u32) -> Vec<u8> {
+  stack_operand(0x8B, register, offset)
+}
+
+/// Encodes a 64-bit operation with a `[rsp+disp32]` memory operand.
+fn stack_operand(opcode: u8, register: u8, offset: u32) -> Vec<u8> {
+  let rex = 0x48 | ((register >> 3) << 2);
+  let mut code = vec![rex, opcode, 0x84 | ((register & 7) << 3), 0x24];
+  code.extend(&offset.to_le_bytes());
+  code
+}
+
+/// Encodes `movdqu` with a `[rsp+disp32]` memory operand.
+fn movdqu(opcode: u8, register: u8, offset: u32) -> Vec<u8> {
+  let mut code = vec![0xF3, 0x0F, opcode, 0x84 | (register << 3), 0x24];
+  code.extend(&offset.to_le_bytes());
+  code
+}
+
+/// The amount of stack slots copied when invoking a function with a context.
+const STACK_SLOTS: u8 = 16;
+
+/// Generates a function which invokes another function with a context.
+///
+/// The generated function is called as `extern "C" fn(&mut CpuContext,
+/// function)`. The argument registers are loaded from the context, the stack
+/// arguments are copied from the context's stack, and the return value
+/// registers are written back to the context.
+pub fn invoker() -> Vec<u8> {
+  let stack_size = u32::from(STACK_SLOTS) * 8 + 8;
+  let mut code = vec![
+    0x55, // push rbp
+    0x48, 0x89, 0xE5, // mov rbp, rsp
+    0x53, // push rbx
+    0x48, 0x89, 0xFB, // mov rbx, rdi
+    0x49, 0x89, 0xF3, // mov r11, rsi
+  ];
+
+  // sub rsp, stack_size
+  code.extend(&[0x48, 0x81, 0xEC]);
+  code.extend(&stack_size.to_le_bytes());
+
+  // Copy the stack arguments, located after the return address
+  code.extend(&context_operand(0x8B, 6, RSP_OFFSET)); // mov rsi, [rbx+rsp]
+  code.extend(&[0x48, 0x83, 0xC6, 0x08]); // add rsi, 8
+  code.extend(&[0x48, 0x89, 0xE7]); // mov rdi, rsp
+  code.extend(&[0xB9, STACK_SLOTS, 0x00, 0x00, 0x00]); // mov ecx, STACK_SLOTS
+  code.extend(&[0xF3, 0x48, 0xA5]); // rep movsq
+
+  for index in 0..8 {
+    // movdqu xmmN, [rbx+offset]
+    code.extend(&context_movdqu(
+      0x6F,
+      index,
+      XMM_OFFSET + u32::from(index) * 16,
+    ));
+  }
+
+  for &(register, offset) in REGISTERS.iter() {
+    code.extend(&context_operand(0x8B, register, offset));
+  }
+
+  // call r11
+  code.extend(&[0x41, 0xFF, 0xD3]);
+
+  // Store the return value registers (rax, rdx, xmm0 & xmm1)
+  code.extend(&context_operand(0x89, 0, REGISTERS[6].1));
+  code.extend(&context_operand(0x89, 2, REGISTERS[2].1));
+  code.extend(&context_movdqu(0x7F, 0, XMM_OFFSET));
+  code.extend(&context_movdqu(0x7F, 1, XMM_OFFSET + 16));
+
+  // add rsp, stack_size
+  code.extend(&[0x48, 0x81, 0xC4]);
+  code.extend(&stack_size.to_le_bytes());
+  code.extend(&[
+    0x5B, // pop rbx
+    0x5D, // pop rbp
+    0xC3, // ret
+  ]);
+  code
+}
+
+/// Encodes a 64-bit operation with a `[rbx+disp32]` memory operand.
+fn context_operand(opcode: u8, register: u8, offset: u32) -> Vec<u8> {
+  let rex = 0x48 | ((register >> 3) << 2);
+  let mut code = vec![rex, opcode, 0x83 | ((register & 7) << 3)];
+  code.extend(&offset.to_le_bytes());
+  code
+}
+
+/// Encodes `movdqu` with a `[rbx+disp32]` memory operand.
+fn context_movdqu(opcode: u8, register: u8, offset: u32) -> Vec<u8> {
+  let mut code = vec![0xF3, 0x0F, opcode, 0x83 | (register << 3)];
+  code.extend(&offset.to_le_bytes());
+  code
+}
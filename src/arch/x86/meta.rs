@@ -1,29 +1,126 @@
+#[cfg(all(target_arch = "x86_64", unix))]
+use super::context;
 use super::thunk;
-use crate::{error::Result, pic};
+use super::trampoline::disasm::{Disassembler, Instruction};
+use crate::error::{Error, Result};
+use crate::pic;
 use std::mem;
+use std::sync::atomic::AtomicBool;
 
 /// The furthest distance between a target and its detour (2 GiB).
 pub const DETOUR_RANGE: usize = 0x8000_0000;
 pub const ALIGNMENT: usize = 8;
 
+/// A breakpoint instruction (`int3`).
+pub const BREAKPOINT: &[u8] = &[0xCC];
+
+/// The instructions padding functions which are never executed (`int3`),
+/// usable as caves.
+pub const CAVE_PADDING: &[&[u8]] = &[&[0xCC]];
+
 /// Returns the preferred prolog size for the target.
 pub fn prolog_margin(_target: *const ()) -> usize {
   mem::size_of::<thunk::x86::JumpRel>()
 }
 
+/// Returns the prolog size required to patch the target with an absolute
+/// jump, used if no memory is available within `DETOUR_RANGE`.
+#[cfg(target_arch = "x86_64")]
+pub fn absolute_prolog_margin(_target: *const ()) -> usize {
+  mem::size_of::<thunk::x64::JumpAbs>()
+}
+
 pub unsafe fn skip_jmps(target: *const ()) -> *const () {
   target
 }
 
-/// Creates a relay; required for destinations further away than 2GB (on x64).
-pub fn relay_builder(target: *const (), detour: *const ()) -> Result<Option<pic::CodeEmitter>> {
-  let displacement = (target as isize).wrapping_sub(detour as isize);
+/// Returns the length and destination of a call instruction, which is either
+/// relative (`call rel32`) or loads its destination (e.g `call [rip+x]`).
+///
+/// The call must be at least as long as a `call rel32`, which replaces it.
+pub unsafe fn decode_call(address: *const ()) -> Result<(usize, *const ())> {
+  let instruction = Instruction::new(&mut Disassembler::new(address)).ok_or(Error::InvalidCode)?;
 
-  if cfg!(target_arch = "x86_64") && !crate::arch::is_within_range(displacement) {
-    let mut emitter = pic::CodeEmitter::new();
-    emitter.add_thunk(thunk::jmp(detour as usize));
-    Ok(Some(emitter))
-  } else {
-    Ok(None)
+  if !instruction.is_call() || instruction.len() < mem::size_of::<thunk::x86::JumpRel>() {
+    Err(Error::UnsupportedInstruction)?;
   }
+
+  let destination = match instruction.relative_branch_displacement() {
+    Some(displacement) => instruction
+      .next_instruction_address()
+      .wrapping_add(displacement as usize),
+    None => {
+      let slot = instruction
+        .static_memory_operand_address()
+        .ok_or(Error::UnsupportedInstruction)?;
+      // The pointer is not required to be aligned
+      (slot as *const usize).read_unaligned()
+    },
+  };
+
+  Ok((instruction.len(), destination as *const ()))
+}
+
+/// The offset of the destination slot within a relay.
+pub const RELAY_SLOT_OFFSET: usize = 8;
+
+/// The offset of the entry within a relay.
+pub const RELAY_ENTRY_OFFSET: usize = 0;
+
+/// Creates a relay, jumping to the destination stored in its slot.
+///
+/// This allows the destination to be changed, and to be further away than
+/// 2GB (on x64).
+pub fn relay_builder(_target: *const (), detour: *const ()) -> Result<pic::CodeEmitter> {
+  let mut emitter = pic::CodeEmitter::new();
+  emitter.add_thunk(thunk::jmp_slot(detour as usize));
+  Ok(emitter)
+}
+
+/// The offset of the entry within a switching relay.
+pub const SWITCH_RELAY_ENTRY_OFFSET: usize = RELAY_SLOT_OFFSET + mem::size_of::<usize>();
+
+/// Creates a relay that jumps to the destination stored in its slot if `flag`
+/// is set, otherwise to the trampoline.
+///
+/// The target must be redirected to the relay's entry, rather than its start.
+pub fn switch_relay_builder(
+  _target: *const (),
+  detour: *const (),
+  trampoline: *const (),
+  flag: *const AtomicBool,
+) -> Result<pic::CodeEmitter> {
+  let mut emitter = pic::CodeEmitter::new();
+  emitter.add_thunk(thunk::jmp_slot(detour as usize));
+  emitter.add_thunk(thunk::jmp_switch(
+    flag as usize,
+    -(SWITCH_RELAY_ENTRY_OFFSET as i8),
+    trampoline as usize,
+  ));
+  Ok(emitter)
+}
+
+/// Creates a stub that captures the thread context and invokes `handler`.
+#[cfg(all(target_arch = "x86_64", unix))]
+pub fn context_stub_builder(state: *const (), handler: usize) -> pic::CodeEmitter {
+  let mut emitter = pic::CodeEmitter::new();
+  emitter.add_thunk(Box::new(context::stub(state as usize, handler, false)));
+  emitter
+}
+
+/// Creates a stub that functions return to, which captures the thread context
+/// and invokes `handler`.
+#[cfg(all(target_arch = "x86_64", unix))]
+pub fn return_stub_builder(state: *const (), handler: usize) -> pic::CodeEmitter {
+  let mut emitter = pic::CodeEmitter::new();
+  emitter.add_thunk(Box::new(context::stub(state as usize, handler, true)));
+  emitter
+}
+
+/// Creates a function that invokes another function with a thread context.
+#[cfg(all(target_arch = "x86_64", unix))]
+pub fn invoker_builder() -> pic::CodeEmitter {
+  let mut emitter = pic::CodeEmitter::new();
+  emitter.add_thunk(Box::new(context::invoker()));
+  emitter
 }
@@ -1,16 +1,47 @@
 #![cfg_attr(test, allow(named_asm_labels))]
-pub use self::patcher::Patcher;
-pub use self::trampoline::Trampoline;
+// Only relocation is used on other hosts
+#![cfg_attr(
+  not(any(target_arch = "x86", target_arch = "x86_64")),
+  allow(dead_code)
+)]
+pub use self::trampoline::{Operands, Trampoline};
+
+use cfg_if::cfg_if;
+
+cfg_if! {
+    if #[cfg(any(target_arch = "x86", target_arch = "x86_64"))] {
+        #[cfg(all(target_arch = "x86_64", unix))]
+        pub use self::context::CpuContext;
+        pub use self::patcher::Patcher;
+        #[cfg(target_os = "linux")]
+        pub use self::watch::{Access, AccessKind, Watch};
+
+        pub mod clone;
+        #[cfg(all(target_arch = "x86_64", unix))]
+        mod context;
+        pub mod meta;
+        mod patcher;
+        #[cfg(target_os = "linux")]
+        mod poke;
+        #[cfg(target_os = "linux")]
+        mod watch;
+    }
+}
 
-pub mod meta;
-mod patcher;
 mod thunk;
 mod trampoline;
 
+/// The mode of the host, in bits.
+const HOST_BITS: u8 = (std::mem::size_of::<usize>() * 8) as u8;
+
 // TODO: Add test for targets further away than DETOUR_RANGE
 // TODO: Add test for unsupported branches
 // TODO: Add test for negative branch displacements
-#[cfg(all(feature = "nightly", test))]
+#[cfg(all(
+  feature = "nightly",
+  test,
+  any(target_arch = "x86", target_arch = "x86_64")
+))]
 mod tests {
   use crate::error::{Error, Result};
   use crate::RawDetour;
@@ -154,6 +185,524 @@ mod tests {
     unsafe { detour_test(rip_relative_prolog_ret49, 49) }
   }
 
+  #[test]
+  fn detour_code_cave() -> Result<()> {
+    use crate::CodeCaveAllocator;
+    use std::slice;
+    use std::sync::Arc;
+
+    #[naked]
+    unsafe extern "C" fn padded_ret8() -> i32 {
+      asm!(
+        "
+            mov eax, 8
+            ret
+            .fill 96, 1, 0xCC",
+        options(noreturn)
+      );
+    }
+
+    unsafe {
+      let padding = slice::from_raw_parts((padded_ret8 as usize + 6) as *const u8, 96);
+      let original = padding.to_vec();
+
+      let allocator = Arc::new(CodeCaveAllocator::new());
+      let hook =
+        RawDetour::with_allocator(padded_ret8 as *const (), ret10 as *const (), allocator)?;
+
+      // The trampoline is located within the function's padding
+      let trampoline = hook.trampoline() as *const () as *const u8;
+      assert!(padding.as_ptr_range().contains(&trampoline));
+
+      hook.enable()?;
+      assert_eq!(padded_ret8(), 10);
+      let original_fn: CRet = mem::transmute(hook.trampoline());
+      assert_eq!(original_fn(), 8);
+
+      // The padding is restored once the caves are released
+      mem::drop(hook);
+      assert_eq!(padded_ret8(), 8);
+      assert_eq!(padding, &original[..]);
+    }
+    Ok(())
+  }
+
+  #[test]
+  fn code_caves_shared_and_excluding_zeroes_and_nops() -> Result<()> {
+    use super::meta::DETOUR_RANGE;
+    use crate::{CodeCaveAllocator, ExecutableAllocator};
+
+    #[naked]
+    unsafe extern "C" fn padded_ret8() -> i32 {
+      asm!(
+        "
+            mov eax, 8
+            ret
+            .fill 58, 1, 0xCC
+            .fill 64, 1, 0
+            .fill 64, 1, 0x90",
+        options(noreturn)
+      );
+    }
+
+    let zeroes = (padded_ret8 as usize + 64)..(padded_ret8 as usize + 128);
+    let nops = zeroes.end..(zeroes.end + 64);
+    let origin = (zeroes.start + 32) as *const ();
+
+    // Separate allocators share the registry of allocated caves
+    let allocators = [CodeCaveAllocator::new(), CodeCaveAllocator::new()];
+    let blocks = allocators
+      .iter()
+      .map(|allocator| allocator.allocate_near(origin, 16, DETOUR_RANGE))
+      .collect::<Result<Vec<_>>>()?;
+
+    let ranges = blocks
+      .iter()
+      .map(|block| block.as_ptr() as usize..block.as_ptr() as usize + block.len())
+      .collect::<Vec<_>>();
+    assert!(ranges[0].end <= ranges[1].start || ranges[1].end <= ranges[0].start);
+
+    // The zeroes may be data, and the nops may be executed, so they're never
+    // used, despite being as close
+    for range in &ranges {
+      assert!(range.end <= zeroes.start || zeroes.end <= range.start);
+      assert!(range.end <= nops.start || nops.end <= range.start);
+    }
+
+    for (allocator, block) in allocators.iter().zip(blocks) {
+      unsafe { allocator.release(block) };
+    }
+    Ok(())
+  }
+
+  #[test]
+  #[cfg(target_arch = "x86_64")]
+  fn detour_absolute_rip_relative() -> Result<()> {
+    #[naked]
+    unsafe extern "C" fn rip_relative_ret195() -> i32 {
+      asm!(
+        "
+            xor eax, eax
+            mov al, [rip+0x6]
+            nop
+            nop
+            nop
+            nop
+            nop
+            nop
+            ret",
+        options(noreturn)
+      );
+    }
+
+    unsafe { detour_absolute_test(rip_relative_ret195, 195) }
+  }
+
+  #[test]
+  #[cfg(target_arch = "x86_64")]
+  fn detour_absolute_indirect_jump() -> Result<()> {
+    static DESTINATION: CRet = ret7;
+
+    #[naked]
+    unsafe extern "C" fn indirect_jump_ret7() -> i32 {
+      asm!(
+        "
+            jmp qword ptr [rip+{}]
+            nop
+            nop
+            nop
+            nop
+            nop
+            nop
+            nop
+            nop",
+        sym DESTINATION,
+        options(noreturn)
+      );
+    }
+
+    unsafe extern "C" fn ret7() -> i32 {
+      7
+    }
+
+    unsafe { detour_absolute_test(indirect_jump_ret7, 7) }
+  }
+
+  /// Detours a C function using an allocator without any memory within
+  /// `DETOUR_RANGE`, and asserts its return value.
+  #[cfg(target_arch = "x86_64")]
+  unsafe fn detour_absolute_test(target: CRet, result: i32) -> Result<()> {
+    use super::meta::DETOUR_RANGE;
+    use crate::{ExecutableAllocator, ExecutableBlock, ProximityAllocator};
+    use std::sync::Arc;
+
+    struct DistantAllocator(ProximityAllocator);
+
+    unsafe impl ExecutableAllocator for DistantAllocator {
+      fn allocate_near(
+        &self,
+        origin: *const (),
+        size: usize,
+        max_distance: usize,
+      ) -> Result<ExecutableBlock> {
+        if max_distance <= DETOUR_RANGE {
+          Err(Error::OutOfMemory)?;
+        }
+
+        let origin = (origin as usize + DETOUR_RANGE * 8) as *const ();
+        self.0.allocate_near(origin, size, DETOUR_RANGE)
+      }
+
+      unsafe fn release(&self, block: ExecutableBlock) {
+        self.0.release(block)
+      }
+    }
+
+    let allocator = Arc::new(DistantAllocator(ProximityAllocator::new(0x10000)));
+    let hook = RawDetour::with_allocator(target as *const (), ret10 as *const (), allocator)?;
+
+    let distance = (hook.trampoline() as *const () as isize - target as *const () as isize).abs();
+    assert!(distance as usize > DETOUR_RANGE);
+
+    assert_eq!(target(), result);
+    hook.enable()?;
+    {
+      assert_eq!(target(), 10);
+      let original: CRet = mem::transmute(hook.trampoline());
+      assert_eq!(original(), result);
+    }
+    hook.disable()?;
+    assert_eq!(target(), result);
+    Ok(())
+  }
+
+  #[test]
+  fn detour_live_patching() -> Result<()> {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+
+    #[naked]
+    unsafe extern "C" fn mov_ret42() -> i32 {
+      asm!("mov eax, 42", "ret", options(noreturn));
+    }
+
+    let hook = unsafe { RawDetour::new(mov_ret42 as *const (), ret10 as *const ())? };
+    let running = Arc::new(AtomicBool::new(true));
+
+    // The prolog is a single instruction, so no thread may ever observe a
+    // partially written jump
+    let handles = (0..4)
+      .map(|_| {
+        let running = running.clone();
+        thread::spawn(move || {
+          while running.load(Ordering::SeqCst) {
+            let result = unsafe { mov_ret42() };
+            assert!(result == 42 || result == 10);
+          }
+        })
+      })
+      .collect::<Vec<_>>();
+
+    for _ in 0..1000 {
+      unsafe {
+        hook.enable()?;
+        hook.disable()?;
+      }
+    }
+
+    running.store(false, Ordering::SeqCst);
+    for handle in handles {
+      handle.join().unwrap();
+    }
+    Ok(())
+  }
+
+  #[test]
+  #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+  fn detour_live_patching_prolog() -> Result<()> {
+    use crate::arch::breakpoint;
+    use crate::util;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::{slice, thread};
+
+    #[naked]
+    unsafe extern "C" fn push_ret42() -> i32 {
+      asm!(
+        "push rbp",
+        "mov rbp, rsp",
+        "mov eax, 42",
+        "pop rbp",
+        "ret",
+        options(noreturn)
+      );
+    }
+
+    let target = push_ret42 as *const u8;
+    let hook = unsafe { RawDetour::new(target as *const (), ret10 as *const ())? };
+    let original = unsafe { slice::from_raw_parts(target.add(1), 4).to_vec() };
+    let running = Arc::new(AtomicBool::new(true));
+
+    // Emulates the state of a live patch after its first step. Threads entering
+    // the target trap on the breakpoint, and are redirected to the trampoline,
+    // regardless of the (torn) bytes written after it.
+    unsafe {
+      breakpoint::register(target as *const (), hook.trampoline())?;
+      util::write_code(target, super::meta::BREAKPOINT)?;
+    }
+
+    let handles = (0..4)
+      .map(|_| {
+        let running = running.clone();
+        thread::spawn(move || {
+          while running.load(Ordering::SeqCst) {
+            assert_eq!(unsafe { push_ret42() }, 42);
+          }
+        })
+      })
+      .collect::<Vec<_>>();
+
+    // Only threads entering at the breakpoint are guaranteed to be redirected.
+    // The `mov rbp, rsp` following it is overwritten with a partial jump, so
+    // a thread which executed `push rbp` before the breakpoint was written
+    // (and e.g. was preempted) would resume in the middle of it. Such a thread
+    // is not emulated here, since it would crash the test (`ud2`).
+    for _ in 0..1000 {
+      unsafe {
+        util::write_code(target.add(1), &[0x0F, 0x0B, 0x0F, 0x0B])?;
+        util::write_code(target.add(1), &original)?;
+      }
+    }
+
+    running.store(false, Ordering::SeqCst);
+    for handle in handles {
+      handle.join().unwrap();
+    }
+
+    unsafe {
+      util::write_code(target, &[0x55])?;
+      breakpoint::unregister(target as *const ());
+      assert_eq!(push_ret42(), 42);
+    }
+    Ok(())
+  }
+
+  #[test]
+  #[cfg(target_os = "linux")]
+  fn detour_breakpoint() -> Result<()> {
+    use crate::BreakpointDetour;
+
+    #[naked]
+    unsafe extern "C" fn xor_ret0() -> i32 {
+      asm!("xor eax, eax", "ret", "ud2", options(noreturn));
+    }
+
+    unsafe {
+      let target = xor_ret0 as *const u8;
+      let original = std::slice::from_raw_parts(target, 3).to_vec();
+      let hook = BreakpointDetour::new(target as *const (), ret10 as *const ())?;
+
+      // Only the first byte is replaced, with an `int3`
+      hook.enable()?;
+      assert_eq!(*target, 0xCC);
+      assert_eq!(std::slice::from_raw_parts(target.add(1), 2), &original[1..]);
+      assert_eq!(xor_ret0(), 10);
+
+      let trampoline: CRet = mem::transmute(hook.trampoline());
+      assert_eq!(trampoline(), 0);
+
+      hook.disable()?;
+      assert_eq!(std::slice::from_raw_parts(target, 3), &original[..]);
+      assert_eq!(xor_ret0(), 0);
+    }
+    Ok(())
+  }
+
+  #[test]
+  fn detour_call_site() -> Result<()> {
+    use crate::CallSiteDetour;
+
+    #[naked]
+    unsafe extern "C" fn call_ret5() -> i32 {
+      asm!("call {}", "ret", sym ret5, options(noreturn));
+    }
+
+    unsafe extern "C" fn ret5() -> i32 {
+      5
+    }
+
+    unsafe {
+      let hook = CallSiteDetour::new(call_ret5 as *const (), ret10 as *const ())?;
+      assert_eq!(hook.original() as *const (), ret5 as *const ());
+
+      // Only the caller is affected
+      hook.enable()?;
+      assert_eq!(call_ret5(), 10);
+      assert_eq!(ret5(), 5);
+
+      let original: CRet = mem::transmute(hook.original());
+      assert_eq!(original(), 5);
+
+      hook.disable()?;
+      assert_eq!(call_ret5(), 5);
+    }
+    Ok(())
+  }
+
+  #[test]
+  #[cfg(target_arch = "x86_64")]
+  fn detour_call_site_indirect() -> Result<()> {
+    use crate::CallSiteDetour;
+    static DESTINATION: CRet = ret7;
+
+    #[naked]
+    unsafe extern "C" fn indirect_call_ret7() -> i32 {
+      asm!(
+        "call qword ptr [rip+{}]",
+        "ret",
+        sym DESTINATION,
+        options(noreturn)
+      );
+    }
+
+    unsafe extern "C" fn ret7() -> i32 {
+      7
+    }
+
+    unsafe {
+      let call = indirect_call_ret7 as *const u8;
+      let original = std::slice::from_raw_parts(call, 6).to_vec();
+      let hook = CallSiteDetour::new(call as *const (), ret10 as *const ())?;
+      assert_eq!(hook.original() as *const (), ret7 as *const ());
+
+      // The relative call is preceded by a nop, preserving the return address
+      hook.enable()?;
+      assert_eq!(&std::slice::from_raw_parts(call, 2), &[0x90, 0xE8]);
+      assert_eq!(indirect_call_ret7(), 10);
+
+      hook.disable()?;
+      assert_eq!(std::slice::from_raw_parts(call, 6), &original[..]);
+      assert_eq!(indirect_call_ret7(), 7);
+    }
+    Ok(())
+  }
+
+  #[test]
+  fn detour_call_site_unsupported() {
+    use crate::CallSiteDetour;
+
+    #[naked]
+    unsafe extern "C" fn xor_ret0() -> i32 {
+      asm!("xor eax, eax", "ret", options(noreturn));
+    }
+
+    let error =
+      unsafe { CallSiteDetour::new(xor_ret0 as *const (), ret10 as *const ()) }.unwrap_err();
+    assert_matches!(error, Error::UnsupportedInstruction);
+  }
+
+  #[test]
+  #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+  fn clone_jump_table() {
+    use crate::FunctionClone;
+
+    #[naked]
+    unsafe extern "C" fn switch_ret0() -> i32 {
+      asm!(
+        "
+            lea rdx, [rip + 2f]
+            movsxd rax, dword ptr [rdx + rdi * 4]
+            add rax, rdx
+            jmp rax
+            2:
+            .long 3f - 2b
+            3:
+            xor eax, eax
+            ret",
+        options(noreturn)
+      );
+    }
+
+    // The table's offsets are relative to the original function
+    let error = unsafe { FunctionClone::<CRet>::with_size(switch_ret0, 16) }.unwrap_err();
+    assert_matches!(error, Error::UnsupportedInstruction);
+  }
+
+  #[test]
+  #[cfg(all(target_arch = "x86_64", unix))]
+  fn exit_hook_longjmp() -> Result<()> {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static OUTER_RETURNS: AtomicUsize = AtomicUsize::new(0);
+    static INNER_RETURNS: AtomicUsize = AtomicUsize::new(0);
+
+    type JumpFn = unsafe extern "C" fn(u64) -> u64;
+
+    /// Returns 1 if `inner` jumped back, otherwise its return value.
+    #[naked]
+    unsafe extern "C" fn outer(_jump: u64) -> u64 {
+      asm!(
+        "
+            push rbx
+            mov rsi, rsp
+            lea rdx, [rip + 2f]
+            call {}
+            pop rbx
+            ret
+            2:
+            mov eax, 1
+            pop rbx
+            ret",
+        sym inner,
+        options(noreturn)
+      );
+    }
+
+    /// Either returns 2, or jumps to `resume` with the caller's stack pointer.
+    #[naked]
+    unsafe extern "C" fn inner(_jump: u64, _stack: usize, _resume: usize) -> u64 {
+      asm!(
+        "
+            test rdi, rdi
+            jnz 2f
+            mov eax, 2
+            ret
+            2:
+            mov rsp, rsi
+            jmp rdx",
+        options(noreturn)
+      );
+    }
+
+    unsafe {
+      let outer_hook = RawDetour::with_exit_hook(outer as *const (), |_| {
+        OUTER_RETURNS.fetch_add(1, Ordering::SeqCst);
+      })?;
+      let inner_hook = RawDetour::with_exit_hook(inner as *const (), |_| {
+        INNER_RETURNS.fetch_add(1, Ordering::SeqCst);
+      })?;
+
+      outer_hook.enable()?;
+      inner_hook.enable()?;
+
+      // The inner frame is skipped, whilst the outer frame returns via its hook
+      let outer: JumpFn = outer;
+      assert_eq!(outer(1), 1);
+      assert_eq!(OUTER_RETURNS.load(Ordering::SeqCst), 1);
+      assert_eq!(INNER_RETURNS.load(Ordering::SeqCst), 0);
+
+      // The skipped frame no longer affects subsequent invocations
+      assert_eq!(outer(0), 2);
+      assert_eq!(OUTER_RETURNS.load(Ordering::SeqCst), 2);
+      assert_eq!(INNER_RETURNS.load(Ordering::SeqCst), 1);
+
+      inner_hook.disable()?;
+      outer_hook.disable()?;
+    }
+    Ok(())
+  }
+
   /// Default detour target.
   unsafe extern "C" fn ret10() -> i32 {
     10
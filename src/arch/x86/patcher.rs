@@ -1,12 +1,19 @@
-use super::thunk;
+#[cfg(target_os = "linux")]
+use super::poke;
+use super::{thunk, HOST_BITS};
 use crate::error::{Error, Result};
-use crate::{pic, util};
+use crate::{arch, pic, util};
+use cfg_if::cfg_if;
 use std::{mem, slice};
 
 pub struct Patcher {
   patch_area: &'static mut [u8],
   original_prolog: Vec<u8>,
   detour_prolog: Vec<u8>,
+  #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+  entry: usize,
+  #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+  trampoline: *const (),
 }
 
 impl Patcher {
@@ -17,34 +24,100 @@ impl Patcher {
   /// * `target` - An address that should be hooked.
   /// * `detour` - An address that the target should be redirected to.
   /// * `prolog_size` - The available inline space for the hook.
-  pub unsafe fn new(target: *const (), detour: *const (), prolog_size: usize) -> Result<Patcher> {
-    // Calculate the patch area (i.e if a short or long jump should be used)
-    let patch_area = Self::patch_area(target, prolog_size)?;
-    let emitter = Self::hook_template(detour, patch_area);
+  /// * `trampoline` - An address equivalent to the original target, executed by
+  ///   threads entering the target whilst it's being patched.
+  pub unsafe fn new(
+    target: *const (),
+    detour: *const (),
+    prolog_size: usize,
+    trampoline: *const (),
+  ) -> Result<Patcher> {
+    let (patch_area, emitter) = if Self::is_within_reach(target, detour) {
+      // Calculate the patch area (i.e if a short or long jump should be used)
+      let patch_area = Self::patch_area(target, prolog_size)?;
+      let emitter = Self::hook_template(detour, patch_area);
+      (patch_area, emitter)
+    } else {
+      // ... otherwise an absolute jump is required
+      Self::absolute_patch(target, detour, prolog_size)?
+    };
 
     let patch_address = patch_area.as_ptr() as *const ();
     let original_prolog = patch_area.to_vec();
 
     Ok(Patcher {
       detour_prolog: emitter.emit(patch_address),
+      entry: target as usize - patch_address as usize,
       original_prolog,
       patch_area,
+      trampoline,
+    })
+  }
+
+  /// Creates a patcher for a call instruction, redirecting it to `destination`.
+  ///
+  /// # Arguments
+  ///
+  /// * `call` - The address of the call instruction.
+  /// * `call_size` - The size of the call instruction.
+  /// * `destination` - An address, within reach of a relative call.
+  /// * `trampoline` - An address equivalent to the original call, executed by
+  ///   threads reaching the call whilst it's being patched.
+  pub unsafe fn with_call(
+    call: *const (),
+    call_size: usize,
+    destination: *const (),
+    trampoline: *const (),
+  ) -> Result<Patcher> {
+    // Leftover bytes (e.g of a `call [rip+x]`) are padded with preceding nops,
+    // so the return address is the same as the original call's
+    let padding = call_size - mem::size_of::<thunk::x86::JumpRel>();
+    if !Self::is_within_reach((call as usize + padding) as *const (), destination) {
+      Err(Error::NoPatchArea)?;
+    }
+
+    let mut emitter = pic::CodeEmitter::new();
+    for _ in 0..padding {
+      emitter.add_thunk(thunk::x86::nop());
+    }
+    emitter.add_thunk(thunk::x86::call_rel32(destination as usize, HOST_BITS));
+
+    let patch_area = slice::from_raw_parts_mut(call as *mut u8, call_size);
+    Ok(Patcher {
+      detour_prolog: emitter.emit(call),
+      original_prolog: patch_area.to_vec(),
+      entry: 0,
+      patch_area,
+      trampoline,
     })
   }
 
-  /// Returns the target's patch area.
-  pub fn area(&self) -> &[u8] {
-    self.patch_area
+  /// Returns whether the patch area contains the detour's patch.
+  pub fn is_patched(&self) -> bool {
+    *self.patch_area == *self.detour_prolog
   }
 
   /// Either patches or unpatches the function.
-  pub unsafe fn toggle(&mut self, enable: bool) {
+  ///
+  /// On Linux, this installs the process-wide `SIGTRAP` handler of
+  /// breakpoints (see `poke`).
+  pub unsafe fn toggle(&mut self, enable: bool) -> Result<()> {
     // Copy either the detour or the original bytes of the function
-    self.patch_area.copy_from_slice(if enable {
+    let code = if enable {
       &self.detour_prolog
     } else {
       &self.original_prolog
-    });
+    };
+
+    cfg_if! {
+      if #[cfg(target_os = "linux")] {
+        // The target may be executed concurrently, so it's patched via a
+        // breakpoint which redirects to the trampoline
+        poke::write_code(self.patch_area.as_ptr(), code, self.entry, self.trampoline)
+      } else {
+        util::write_code(self.patch_area.as_ptr(), code)
+      }
+    }
   }
 
   /// Returns the patch area for a function, consisting of a long jump and
@@ -84,12 +157,53 @@ impl Patcher {
     }
   }
 
+  /// Returns the patch area and code for an absolute jump, which reaches any
+  /// address but requires a larger prolog (x64).
+  #[cfg(target_arch = "x86_64")]
+  unsafe fn absolute_patch(
+    target: *const (),
+    detour: *const (),
+    prolog_size: usize,
+  ) -> Result<(&'static mut [u8], pic::CodeEmitter)> {
+    let jump_abs_size = mem::size_of::<thunk::x64::JumpAbs>();
+
+    if !Self::is_patchable(target, prolog_size, jump_abs_size) {
+      Err(Error::NoPatchArea)?;
+    }
+
+    let mut emitter = pic::CodeEmitter::new();
+    emitter.add_thunk(thunk::x64::jmp_abs(detour as usize));
+
+    let patch_area = slice::from_raw_parts_mut(target as *mut u8, jump_abs_size);
+    Ok((patch_area, emitter))
+  }
+
+  /// Relative jumps reach any address on x86.
+  #[cfg(target_arch = "x86")]
+  unsafe fn absolute_patch(
+    _target: *const (),
+    _detour: *const (),
+    _prolog_size: usize,
+  ) -> Result<(&'static mut [u8], pic::CodeEmitter)> {
+    unreachable!("detour out of reach on x86")
+  }
+
+  /// Returns whether a relative jump from the target reaches the detour.
+  fn is_within_reach(target: *const (), detour: *const ()) -> bool {
+    let displacement = (detour as isize).wrapping_sub(target as isize);
+    let jump_rel32_size = mem::size_of::<thunk::x86::JumpRel>() as isize;
+
+    // The jump either starts at the target, or ends at it (i.e a hot patch)
+    arch::is_within_range(displacement)
+      && arch::is_within_range(displacement.wrapping_sub(jump_rel32_size))
+  }
+
   /// Creates a redirect code template for the targetted patch area.
   fn hook_template(detour: *const (), patch_area: &[u8]) -> pic::CodeEmitter {
     let mut emitter = pic::CodeEmitter::new();
 
     // Both hot patch and normal detours use a relative long jump
-    emitter.add_thunk(thunk::x86::jmp_rel32(detour as usize));
+    emitter.add_thunk(thunk::x86::jmp_rel32(detour as usize, HOST_BITS));
 
     // The hot patch relies on a small jump to get to the long jump
     let jump_rel32_size = mem::size_of::<thunk::x86::JumpRel>();
@@ -125,7 +239,7 @@ impl Patcher {
   }
 
   /// Returns true if the slice only contains code padding.
-  fn is_code_padding(buffer: &[u8]) -> bool {
+  pub fn is_code_padding(buffer: &[u8]) -> bool {
     const PADDING: [u8; 3] = [0x00, 0x90, 0xCC];
     buffer.iter().all(|code| PADDING.contains(code))
   }
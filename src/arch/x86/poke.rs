@@ -0,0 +1,86 @@
+//! Live patching of code, which may be executed concurrently.
+//!
+//! Instructions longer than a byte cannot be written atomically, so code is
+//! modified using a breakpoint, similar to Linux's `text_poke_bp`:
+//!
+//! 1. An `int3` is written to the entry of the patched code.
+//! 2. The remaining bytes are written.
+//! 3. The `int3` is replaced with the first byte of the new code.
+//!
+//! The cores are synchronized after each step. Any thread executing the entry
+//! in the meantime traps, and is redirected to a destination equivalent to the
+//! original code (i.e the trampoline), so threads entering the code never
+//! observe a partial write.
+//!
+//! This does not apply to threads already executing within the code. If it
+//! consists of several instructions (e.g `push rbp; mov rbp, rsp`), a thread
+//! which executed the first one before the breakpoint was written, may resume
+//! in the middle of the new code. Unlike `text_poke_bp`, which only replaces
+//! single instructions, patching a multi-instruction prolog is therefore only
+//! safe if no thread is suspended within it.
+use crate::arch::breakpoint;
+use crate::error::Result;
+use crate::util;
+use std::sync::Once;
+
+const MEMBARRIER_CMD_PRIVATE_EXPEDITED_SYNC_CORE: libc::c_int = 1 << 5;
+const MEMBARRIER_CMD_REGISTER_PRIVATE_EXPEDITED_SYNC_CORE: libc::c_int = 1 << 6;
+
+/// Writes code to an area, whilst it may be executed concurrently.
+///
+/// Execution must only enter the area at `entry` (an offset within it), where
+/// threads are redirected to `destination` while the area is being written.
+pub unsafe fn write_code(
+  area: *const u8,
+  code: &[u8],
+  entry: usize,
+  destination: *const (),
+) -> Result<()> {
+  let address = area.add(entry);
+  let original = *address;
+
+  breakpoint::register(address as *const (), destination)?;
+
+  let result = util::write_code(address, super::meta::BREAKPOINT).and_then(|()| {
+    sync_cores();
+
+    // The bytes surrounding the breakpoint are no longer executed
+    let result = util::write_code(area, &code[..entry])
+      .and_then(|()| util::write_code(address.add(1), &code[entry + 1..]));
+    if result.is_err() {
+      util::write_code(address, &[original])?;
+      return result;
+    }
+
+    sync_cores();
+    util::write_code(address, &code[entry..=entry])
+  });
+
+  sync_cores();
+  breakpoint::unregister(address as *const ());
+  result
+}
+
+/// Ensures that all cores execute the modified code, instead of any
+/// previously fetched instructions.
+///
+/// If unsupported by the kernel, the cores are still synchronized when the
+/// page protection is restored (due to the TLB shootdown).
+fn sync_cores() {
+  static REGISTER: Once = Once::new();
+
+  unsafe {
+    REGISTER.call_once(|| {
+      libc::syscall(
+        libc::SYS_membarrier,
+        MEMBARRIER_CMD_REGISTER_PRIVATE_EXPEDITED_SYNC_CORE,
+        0,
+      );
+    });
+    libc::syscall(
+      libc::SYS_membarrier,
+      MEMBARRIER_CMD_PRIVATE_EXPEDITED_SYNC_CORE,
+      0,
+    );
+  }
+}
@@ -11,7 +11,7 @@ struct CallAbs {
   dummy1: u8,
   dummy2: u8,
   // destination
-  address: usize,
+  address: u64,
 }
 
 pub fn call_abs(destination: usize) -> Box<dyn Thunkable> {
@@ -21,7 +21,7 @@ pub fn call_abs(destination: usize) -> Box<dyn Thunkable> {
     dummy0: 0x0_0000_0002,
     dummy1: 0xEB,
     dummy2: 0x08,
-    address: destination,
+    address: destination as u64,
   };
 
   let slice: [u8; 16] = unsafe { mem::transmute(code) };
@@ -29,13 +29,13 @@ pub fn call_abs(destination: usize) -> Box<dyn Thunkable> {
 }
 
 #[repr(packed)]
-struct JumpAbs {
+pub struct JumpAbs {
   // jmp +6
   opcode0: u8,
   opcode1: u8,
   dummy0: u32,
   // destination
-  address: usize,
+  address: u64,
 }
 
 pub fn jmp_abs(destination: usize) -> Box<dyn Thunkable> {
@@ -43,13 +43,132 @@ pub fn jmp_abs(destination: usize) -> Box<dyn Thunkable> {
     opcode0: 0xFF,
     opcode1: 0x25,
     dummy0: 0x0_0000_0000,
-    address: destination,
+    address: destination as u64,
   };
 
   let slice: [u8; 14] = unsafe { mem::transmute(code) };
   Box::new(slice.to_vec())
 }
 
+#[repr(packed)]
+struct JumpIndirectAbs {
+  // push rax
+  opcode0: u8,
+  // mov rax, pointer
+  opcode1: u8,
+  opcode2: u8,
+  pointer: u64,
+  // mov rax, [rax]
+  opcode3: u8,
+  opcode4: u8,
+  dummy0: u8,
+  // xchg [rsp], rax
+  opcode5: u8,
+  opcode6: u8,
+  dummy1: u8,
+  dummy2: u8,
+  // ret
+  opcode7: u8,
+}
+
+/// Constructs a jump to the destination stored at `pointer`, preserving all
+/// registers.
+pub fn jmp_indirect_abs(pointer: usize) -> Box<dyn Thunkable> {
+  let code = JumpIndirectAbs {
+    opcode0: 0x50,
+    opcode1: 0x48,
+    opcode2: 0xB8,
+    pointer: pointer as u64,
+    opcode3: 0x48,
+    opcode4: 0x8B,
+    dummy0: 0x00,
+    opcode5: 0x48,
+    opcode6: 0x87,
+    dummy1: 0x04,
+    dummy2: 0x24,
+    opcode7: 0xC3,
+  };
+
+  let slice: [u8; 19] = unsafe { mem::transmute(code) };
+  Box::new(slice.to_vec())
+}
+
+#[repr(packed)]
+struct JumpSlot {
+  // jmp [rip+2]
+  opcode0: u8,
+  opcode1: u8,
+  dummy0: u32,
+  // xchg ax, ax (padding)
+  dummy1: u8,
+  dummy2: u8,
+  // destination (aligned)
+  address: u64,
+}
+
+pub fn jmp_slot(destination: usize) -> Box<dyn Thunkable> {
+  let code = JumpSlot {
+    opcode0: 0xFF,
+    opcode1: 0x25,
+    dummy0: 0x0_0000_0002,
+    dummy1: 0x66,
+    dummy2: 0x90,
+    address: destination as u64,
+  };
+
+  let slice: [u8; 16] = unsafe { mem::transmute(code) };
+  Box::new(slice.to_vec())
+}
+
+#[repr(packed)]
+struct JumpSwitch {
+  // push rax
+  opcode0: u8,
+  // mov rax, flag
+  opcode1: u8,
+  opcode2: u8,
+  flag: u64,
+  // cmp byte [rax], 0
+  opcode3: u8,
+  opcode4: u8,
+  dummy0: u8,
+  // pop rax
+  opcode5: u8,
+  // jne enabled
+  opcode6: u8,
+  operand: i8,
+  // jmp [rip+0]
+  opcode7: u8,
+  opcode8: u8,
+  dummy1: u32,
+  // disabled destination
+  address: u64,
+}
+
+/// Constructs a jump to either `enabled` (relative to the thunk) or
+/// `disabled`, depending on whether the byte at `flag` is set.
+pub fn jmp_switch(flag: usize, enabled: i8, disabled: usize) -> Box<dyn Thunkable> {
+  let code = JumpSwitch {
+    opcode0: 0x50,
+    opcode1: 0x48,
+    opcode2: 0xB8,
+    flag: flag as u64,
+    opcode3: 0x80,
+    opcode4: 0x38,
+    dummy0: 0x00,
+    opcode5: 0x58,
+    opcode6: 0x75,
+    operand: enabled - 17,
+    opcode7: 0xFF,
+    opcode8: 0x25,
+    dummy1: 0x0000_0000,
+    address: disabled as u64,
+  };
+
+  let slice: [u8; 31] = unsafe { mem::transmute(code) };
+  Box::new(slice.to_vec())
+}
+
 #[repr(packed)]
 struct JccAbs {
   // jxx + 16
@@ -59,7 +178,7 @@ struct JccAbs {
   dummy2: u8,
   dummy3: u32,
   // destination
-  address: usize,
+  address: u64,
 }
 
 pub fn jcc_abs(destination: usize, condition: u8) -> Box<dyn Thunkable> {
@@ -70,7 +189,7 @@ pub fn jcc_abs(destination: usize, condition: u8) -> Box<dyn Thunkable> {
     dummy1: 0xFF,
     dummy2: 0x25,
     dummy3: 0x0000_0000,
-    address: destination,
+    address: destination as u64,
   };
 
   let slice: [u8; 16] = unsafe { mem::transmute(code) };
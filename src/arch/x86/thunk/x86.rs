@@ -1,5 +1,6 @@
 use crate::pic::{FixedThunk, Thunkable};
 use generic_array::{typenum, GenericArray};
+use std::convert::TryFrom;
 use std::mem;
 
 #[repr(packed)]
@@ -8,15 +9,15 @@ pub struct JumpRel {
   operand: u32,
 }
 
-/// Constructs either a relative jump or call.
-fn relative32(destination: usize, is_jump: bool) -> Box<dyn Thunkable> {
+/// Constructs either a relative jump or call, in either 32 or 64-bit mode.
+fn relative32(destination: usize, is_jump: bool, bits: u8) -> Box<dyn Thunkable> {
   const CALL: u8 = 0xE8;
   const JMP: u8 = 0xE9;
 
   Box::new(FixedThunk::<typenum::U5>::new(move |source| {
     let code = JumpRel {
       opcode: if is_jump { JMP } else { CALL },
-      operand: calculate_displacement(source, destination, mem::size_of::<JumpRel>()),
+      operand: calculate_displacement(source, destination, mem::size_of::<JumpRel>(), bits),
     };
 
     let slice: [u8; 5] = unsafe { mem::transmute(code) };
@@ -30,13 +31,13 @@ pub fn nop() -> Box<dyn Thunkable> {
 }
 
 /// Constructs a relative call operation.
-pub fn call_rel32(destination: usize) -> Box<dyn Thunkable> {
-  relative32(destination, false)
+pub fn call_rel32(destination: usize, bits: u8) -> Box<dyn Thunkable> {
+  relative32(destination, false, bits)
 }
 
 /// Constructs a relative jump operation.
-pub fn jmp_rel32(destination: usize) -> Box<dyn Thunkable> {
-  relative32(destination, true)
+pub fn jmp_rel32(destination: usize, bits: u8) -> Box<dyn Thunkable> {
+  relative32(destination, true, bits)
 }
 
 #[repr(packed)]
@@ -47,12 +48,12 @@ struct JccRel {
 }
 
 /// Constructs a conditional relative jump operation.
-pub fn jcc_rel32(destination: usize, condition: u8) -> Box<dyn Thunkable> {
+pub fn jcc_rel32(destination: usize, condition: u8, bits: u8) -> Box<dyn Thunkable> {
   Box::new(FixedThunk::<typenum::U6>::new(move |source| {
     let code = JccRel {
       opcode0: 0x0F,
       opcode1: 0x80 | condition,
-      operand: calculate_displacement(source, destination, mem::size_of::<JccRel>()),
+      operand: calculate_displacement(source, destination, mem::size_of::<JccRel>(), bits),
     };
 
     let slice: [u8; 6] = unsafe { mem::transmute(code) };
@@ -60,6 +61,71 @@ pub fn jcc_rel32(destination: usize, condition: u8) -> Box<dyn Thunkable> {
   }))
 }
 
+#[repr(packed)]
+struct JumpSlot {
+  // jmp [slot]
+  opcode0: u8,
+  opcode1: u8,
+  operand: u32,
+  // xchg ax, ax (padding)
+  dummy0: u8,
+  dummy1: u8,
+  // destination (aligned)
+  address: u32,
+}
+
+/// Constructs an indirect jump, via a trailing destination slot.
+pub fn jmp_slot(destination: usize) -> Box<dyn Thunkable> {
+  Box::new(FixedThunk::<typenum::U12>::new(move |source| {
+    let code = JumpSlot {
+      opcode0: 0xFF,
+      opcode1: 0x25,
+      operand: (source + 8) as u32,
+      dummy0: 0x66,
+      dummy1: 0x90,
+      address: destination as u32,
+    };
+
+    let slice: [u8; 12] = unsafe { mem::transmute(code) };
+    GenericArray::clone_from_slice(&slice)
+  }))
+}
+
+#[repr(packed)]
+struct JumpSwitch {
+  // cmp byte [flag], 0
+  opcode0: u8,
+  opcode1: u8,
+  flag: u32,
+  dummy0: u8,
+  // jne enabled
+  opcode2: u8,
+  operand: i8,
+  // jmp disabled
+  opcode3: u8,
+  displacement: u32,
+}
+
+/// Constructs a jump to either `enabled` (relative to the thunk) or
+/// `disabled`, depending on whether the byte at `flag` is set.
+pub fn jmp_switch(flag: usize, enabled: i8, disabled: usize) -> Box<dyn Thunkable> {
+  Box::new(FixedThunk::<typenum::U14>::new(move |source| {
+    let code = JumpSwitch {
+      opcode0: 0x80,
+      opcode1: 0x3D,
+      flag: flag as u32,
+      dummy0: 0x00,
+      opcode2: 0x75,
+      operand: enabled - 9,
+      opcode3: 0xE9,
+      displacement: calculate_displacement(source + 9, disabled, 5, 32),
+    };
+
+    let slice: [u8; 14] = unsafe { mem::transmute(code) };
+    GenericArray::clone_from_slice(&slice)
+  }))
+}
+
 #[repr(packed)]
 pub struct JumpShort {
   opcode: u8,
@@ -79,15 +145,23 @@ pub fn jmp_rel8(displacement: i8) -> Box<dyn Thunkable> {
   }))
 }
 
-/// Calculates the relative displacement for an instruction.
-fn calculate_displacement(source: usize, destination: usize, instruction_size: usize) -> u32 {
+/// Calculates the relative displacement for an instruction, in either 32 or
+/// 64-bit mode.
+fn calculate_displacement(
+  source: usize,
+  destination: usize,
+  instruction_size: usize,
+  bits: u8,
+) -> u32 {
   let displacement =
     (destination as isize).wrapping_sub(source as isize + instruction_size as isize);
 
   // Ensure that the detour can be reached with a relative jump (+/- 2GB).
-  // This only needs to be asserted on x64, since it wraps around on x86.
-  #[cfg(target_arch = "x86_64")]
-  assert!(crate::arch::is_within_range(displacement));
+  // This only needs to be asserted in 64-bit mode, since it wraps around in
+  // 32-bit mode.
+  if bits == 64 {
+    assert!(i32::try_from(displacement).is_ok());
+  }
 
   displacement as u32
 }
@@ -1,19 +1,19 @@
 //! The underlying disassembler should be opaque to the outside.
-use std::slice;
+use std::marker::PhantomData;
+use std::{mem, slice};
 
 /// A x86/x64 disassembler.
-pub struct Disassembler(udis::ud);
+pub struct Disassembler<'a>(udis::ud, PhantomData<&'a [u8]>);
 
-impl Disassembler {
-  /// Creates a default x86 disassembler.
-  pub fn new(target: *const ()) -> Disassembler {
+impl Disassembler<'static> {
+  /// Creates a disassembler for the host's mode, which reads code directly
+  /// from memory.
+  pub fn new(target: *const ()) -> Self {
     unsafe {
-      let mut ud = ::std::mem::zeroed();
-      udis::ud_init(&mut ud);
+      let mut ud = Self::init(target as usize, (mem::size_of::<usize>() * 8) as u8);
       udis::ud_set_user_opaque_data(&mut ud, target as *mut _);
       udis::ud_set_input_hook(&mut ud, Some(Self::udis_read_address));
-      udis::ud_set_mode(&mut ud, (::std::mem::size_of::<usize>() * 8) as u8);
-      Disassembler(ud)
+      Disassembler(ud, PhantomData)
     }
   }
 
@@ -26,27 +26,84 @@ impl Disassembler {
   }
 }
 
+impl<'a> Disassembler<'a> {
+  /// Creates a disassembler for a buffer of code, located at `address`, in
+  /// either 32 or 64-bit mode.
+  pub fn with_code(code: &'a [u8], address: usize, bits: u8) -> Self {
+    unsafe {
+      let mut ud = Self::init(address, bits);
+      udis::ud_set_input_buffer(&mut ud, code.as_ptr(), code.len());
+      Disassembler(ud, PhantomData)
+    }
+  }
+
+  /// Decodes the next instruction, returning the size of its explicit memory
+  /// operand in bytes, if applicable.
+  ///
+  /// Unlike `Instruction::new`, the operands are read in place without
+  /// allocating, so this may be used within a signal handler.
+  pub fn next_memory_operand_size(&mut self) -> Option<usize> {
+    unsafe {
+      if udis::ud_disassemble(&mut self.0) == 0 || self.0.inp_end != 0 {
+        return None;
+      }
+    }
+
+    self
+      .0
+      .operand
+      .iter()
+      .find(|op| op.otype == udis::ud_type::UD_OP_MEM)
+      .map(|op| usize::from(op.size) / 8)
+  }
+
+  /// Initializes a disassembler without any input.
+  unsafe fn init(address: usize, bits: u8) -> udis::ud {
+    let mut ud = mem::zeroed();
+    udis::ud_init(&mut ud);
+    udis::ud_set_mode(&mut ud, bits);
+    udis::ud_set_pc(&mut ud, address as u64);
+    ud
+  }
+}
+
 /// Safe wrapper around an instruction.
 pub struct Instruction {
   address: usize,
   mnemonic: udis::ud_mnemonic_code,
   operands: Vec<udis::ud_operand>,
-  bytes: &'static [u8],
+  bytes: Vec<u8>,
+  modrm_offset: Option<usize>,
+  has_vex_prefix: bool,
+  has_address_size_prefix: bool,
+  is_64bit: bool,
 }
 
 impl Instruction {
-  /// Disassembles a new instruction at the specified address.
-  pub unsafe fn new(disasm: &mut Disassembler, address: *const ()) -> Option<Self> {
-    let instruction_bytes = udis::ud_disassemble(&mut disasm.0) as usize;
-    if instruction_bytes > 0 {
+  /// Disassembles the next instruction.
+  ///
+  /// Returns `None` if the input is exhausted, or ends within the instruction.
+  pub fn new(disasm: &mut Disassembler) -> Option<Self> {
+    unsafe {
+      let instruction_bytes = udis::ud_disassemble(&mut disasm.0) as usize;
+      if instruction_bytes == 0 || disasm.0.inp_end != 0 {
+        return None;
+      }
+
       Some(Instruction {
-        address: address as usize,
+        address: udis::ud_insn_off(&disasm.0) as usize,
         mnemonic: udis::ud_insn_mnemonic(&disasm.0),
         operands: disasm.0.operand.to_vec(),
-        bytes: slice::from_raw_parts(address as *const _, instruction_bytes),
+        bytes: slice::from_raw_parts(udis::ud_insn_ptr(&disasm.0), instruction_bytes).to_vec(),
+        modrm_offset: if disasm.0.have_modrm != 0 {
+          Some(disasm.0.modrm_offset as usize)
+        } else {
+          None
+        },
+        has_vex_prefix: disasm.0.vex_op != 0,
+        has_address_size_prefix: disasm.0.pfx_adr != 0,
+        is_64bit: disasm.0.dis_mode == 64,
       })
-    } else {
-      None
     }
   }
 
@@ -75,6 +132,16 @@ impl Instruction {
     }
   }
 
+  /// Returns the size of the instructions relative branch offset in bytes, if
+  /// applicable.
+  pub fn relative_branch_size(&self) -> Option<usize> {
+    self
+      .operands
+      .iter()
+      .find(|op| op.otype == udis::ud_type::UD_OP_JIMM)
+      .map(|op| usize::from(op.size) / 8)
+  }
+
   /// Returns the instructions RIP operand displacement if applicable.
   pub fn rip_operand_displacement(&self) -> Option<isize> {
     unsafe {
@@ -87,6 +154,87 @@ impl Instruction {
     }
   }
 
+  /// Returns the address referenced by the instruction's memory operand, if
+  /// it's static (i.e RIP-relative or absolute).
+  pub fn static_memory_operand_address(&self) -> Option<usize> {
+    unsafe {
+      let op = self
+        .operands
+        .iter()
+        .find(|op| op.otype == udis::ud_type::UD_OP_MEM && op.index == udis::ud_type::UD_NONE)?;
+
+      match op.base {
+        udis::ud_type::UD_R_RIP => Some(
+          self
+            .next_instruction_address()
+            .wrapping_add(op.lval.sdword as usize),
+        ),
+        udis::ud_type::UD_NONE if op.offset == 32 => Some(op.lval.sdword as usize),
+        _ => None,
+      }
+    }
+  }
+
+  /// Returns the offset of the instruction's ModR/M byte, if applicable.
+  pub fn modrm_offset(&self) -> Option<usize> {
+    self.modrm_offset
+  }
+
+  /// Returns the offset of the instruction's REX prefix, if applicable.
+  pub fn rex_offset(&self) -> Option<usize> {
+    const LEGACY_PREFIXES: [u8; 11] = [
+      0x26, 0x2E, 0x36, 0x3E, 0x64, 0x65, 0x66, 0x67, 0xF0, 0xF2, 0xF3,
+    ];
+
+    // The REX prefix must immediately precede the opcode
+    let offset = self
+      .bytes
+      .iter()
+      .position(|byte| !LEGACY_PREFIXES.contains(byte))?;
+    if self.is_64bit && (0x40..=0x4F).contains(&self.bytes[offset]) {
+      Some(offset)
+    } else {
+      None
+    }
+  }
+
+  /// Returns true if the instruction has a VEX prefix.
+  pub fn has_vex_prefix(&self) -> bool {
+    self.has_vex_prefix
+  }
+
+  /// Returns true if the instruction overrides the address size.
+  pub fn has_address_size_prefix(&self) -> bool {
+    self.has_address_size_prefix
+  }
+
+  /// Returns the register of the instruction's first operand, if applicable.
+  pub fn first_register_operand(&self) -> Option<udis::ud_type> {
+    self
+      .operands
+      .first()
+      .filter(|op| op.otype == udis::ud_type::UD_OP_REG)
+      .map(|op| op.base)
+  }
+
+  /// Returns true if the instruction has a memory operand with an index
+  /// register (e.g `[rax+rcx*8]`).
+  pub fn has_indexed_memory_operand(&self) -> bool {
+    self
+      .operands
+      .iter()
+      .any(|op| op.otype == udis::ud_type::UD_OP_MEM && op.index != udis::ud_type::UD_NONE)
+  }
+
+  /// Returns true if any of the instruction's register operands is one of
+  /// `registers`.
+  pub fn uses_registers(&self, registers: &[udis::ud_type]) -> bool {
+    self
+      .operands
+      .iter()
+      .any(|op| op.otype == udis::ud_type::UD_OP_REG && registers.contains(&op.base))
+  }
+
   /// Returns true if this instruction any type of a loop.
   pub fn is_loop(&self) -> bool {
     match self.mnemonic {
@@ -104,19 +252,30 @@ impl Instruction {
     self.mnemonic == udis::ud_mnemonic_code::UD_Ijmp
   }
 
+  /// Returns true if this instruction is an addition.
+  pub fn is_add(&self) -> bool {
+    self.mnemonic == udis::ud_mnemonic_code::UD_Iadd
+  }
+
   /// Returns true if this instruction is a function call.
   pub fn is_call(&self) -> bool {
     self.mnemonic == udis::ud_mnemonic_code::UD_Icall
   }
 
+  /// Returns true if the instruction is unknown to the disassembler, in which
+  /// case its operands are unknown as well.
+  pub fn is_unknown(&self) -> bool {
+    self.mnemonic == udis::ud_mnemonic_code::UD_Iinvalid
+  }
+
   /// Returns true if this instruction is a return.
   pub fn is_return(&self) -> bool {
     self.mnemonic == udis::ud_mnemonic_code::UD_Iret
   }
 
   /// Returns the instruction's bytes.
-  pub unsafe fn as_slice(&self) -> &[u8] {
-    self.bytes
+  pub fn as_slice(&self) -> &[u8] {
+    &self.bytes
   }
 
   /// Returns the size of the instruction in bytes.
@@ -1,10 +1,14 @@
 use self::disasm::*;
-use crate::arch::x86::thunk;
+use crate::arch::x86::{thunk, HOST_BITS};
 use crate::error::{Error, Result};
 use crate::pic;
-use std::mem;
+use std::convert::TryFrom;
+use std::{mem, slice};
 
-mod disasm;
+pub mod disasm;
+
+/// The maximum length of an instruction.
+const MAX_INSTRUCTION_SIZE: usize = 15;
 
 /// A trampoline generator (x86/x64).
 pub struct Trampoline {
@@ -15,7 +19,47 @@ pub struct Trampoline {
 impl Trampoline {
   /// Constructs a new trampoline for an address.
   pub unsafe fn new(target: *const (), margin: usize) -> Result<Trampoline> {
-    Builder::new(target, margin).build()
+    Self::from_code(
+      Self::prolog(target, margin),
+      target as usize,
+      HOST_BITS,
+      margin,
+      Operands::Relative,
+    )
+  }
+
+  /// Constructs a new trampoline for an address, whose RIP relative operands
+  /// are rewritten to absolute addresses.
+  ///
+  /// The trampoline may therefore be located at any distance from the target.
+  #[cfg(target_arch = "x86_64")]
+  pub unsafe fn with_absolute_operands(target: *const (), margin: usize) -> Result<Trampoline> {
+    Self::from_code(
+      Self::prolog(target, margin),
+      target as usize,
+      HOST_BITS,
+      margin,
+      Operands::Absolute,
+    )
+  }
+
+  /// Constructs a trampoline for a buffer of code, located at `address`, in
+  /// either 32 or 64-bit mode.
+  ///
+  /// The code does not need to be mapped, nor belong to the host's mode.
+  pub fn from_code(
+    code: &[u8],
+    address: usize,
+    bits: u8,
+    margin: usize,
+    operands: Operands,
+  ) -> Result<Trampoline> {
+    Builder::new(code, address, bits, margin, operands).build()
+  }
+
+  /// Returns the target's code, which may be disassembled for the margin.
+  unsafe fn prolog(target: *const (), margin: usize) -> &'static [u8] {
+    slice::from_raw_parts(target as *const u8, margin + MAX_INSTRUCTION_SIZE)
   }
 
   /// Returns a reference to the trampoline's code emitter.
@@ -29,10 +73,23 @@ impl Trampoline {
   }
 }
 
+/// How RIP relative operands are adapted to the trampoline's location.
+#[derive(Debug, Clone, Copy)]
+pub enum Operands {
+  /// Displacements are adjusted, so the trampoline must be located within
+  /// ±2 GiB of the operands.
+  Relative,
+  /// Operands are rewritten to absolute addresses.
+  Absolute,
+  /// Displacements are adjusted for a trampoline located at an address, and
+  /// operands out of its range are rewritten to absolute addresses.
+  LocatedAt(usize),
+}
+
 /// A trampoline builder.
-struct Builder {
+struct Builder<'a> {
   /// Disassembler for x86/x64.
-  disassembler: Disassembler,
+  disassembler: Disassembler<'a>,
   /// Target destination for a potential internal branch.
   branch_address: Option<usize>,
   /// Total amount of bytes disassembled.
@@ -41,18 +98,24 @@ struct Builder {
   margin: usize,
   /// Whether disassembling has finished or not.
   finished: bool,
-  /// The target the trampoline is adapted for.
-  target: *const (),
+  /// How RIP relative operands are adapted.
+  operands: Operands,
+  /// Whether the code is disassembled in 64-bit mode.
+  is_64bit: bool,
+  /// The address the trampoline is adapted for.
+  target: usize,
 }
 
-impl Builder {
+impl<'a> Builder<'a> {
   /// Returns a trampoline builder.
-  pub fn new(target: *const (), margin: usize) -> Self {
+  pub fn new(code: &'a [u8], target: usize, bits: u8, margin: usize, operands: Operands) -> Self {
     Builder {
-      disassembler: Disassembler::new(target),
+      disassembler: Disassembler::with_code(code, target, bits),
       branch_address: None,
       total_bytes_disassembled: 0,
       finished: false,
+      is_64bit: bits == 64,
+      operands,
       target,
       margin,
     }
@@ -61,12 +124,12 @@ impl Builder {
   /// Creates a trampoline with the supplied settings.
   ///
   /// Margins larger than five bytes may lead to undefined behavior.
-  pub unsafe fn build(mut self) -> Result<Trampoline> {
+  pub fn build(mut self) -> Result<Trampoline> {
     let mut emitter = pic::CodeEmitter::new();
 
     while !self.finished {
       let instruction = self.next_instruction()?;
-      let thunk = self.process_instruction(&instruction)?;
+      let thunk = self.process_instruction(&instruction, emitter.len())?;
 
       // If the trampoline displacement is larger than the target
       // function, all instructions will be displaced, and if there is
@@ -80,7 +143,7 @@ impl Builder {
       // Determine whether enough bytes for the margin has been disassembled
       if self.total_bytes_disassembled >= self.margin && !self.finished {
         // Add a jump to the first instruction after the prolog
-        emitter.add_thunk(thunk::jmp(instruction.next_instruction_address()));
+        emitter.add_thunk(self.jmp(instruction.next_instruction_address()));
         self.finished = true;
       }
     }
@@ -92,11 +155,9 @@ impl Builder {
   }
 
   /// Disassembles the next instruction and returns its properties.
-  unsafe fn next_instruction(&mut self) -> Result<Instruction> {
-    let instruction_address = self.target as usize + self.total_bytes_disassembled;
-
+  fn next_instruction(&mut self) -> Result<Instruction> {
     // Disassemble the next instruction
-    match Instruction::new(&mut self.disassembler, instruction_address as *const _) {
+    match Instruction::new(&mut self.disassembler) {
       None => Err(Error::InvalidCode)?,
       Some(instruction) => {
         // Keep track of the total amount of bytes
@@ -107,12 +168,21 @@ impl Builder {
   }
 
   /// Returns an instruction after analysing and potentially modifies it.
-  unsafe fn process_instruction(
+  ///
+  /// The `offset` is the instruction's position within the trampoline.
+  fn process_instruction(
     &mut self,
     instruction: &Instruction,
+    offset: usize,
   ) -> Result<Box<dyn pic::Thunkable>> {
+    // The instruction may have position-dependant operands (e.g a RIP relative
+    // operand of a BMI instruction), so it cannot be copied as is
+    if instruction.is_unknown() {
+      Err(Error::UnsupportedInstruction)?;
+    }
+
     if let Some(displacement) = instruction.rip_operand_displacement() {
-      return self.handle_rip_relative_instruction(instruction, displacement);
+      return self.handle_rip_relative_instruction(instruction, displacement, offset);
     } else if let Some(displacement) = instruction.relative_branch_displacement() {
       return self.handle_relative_branch(instruction, displacement);
     } else if instruction.is_return() {
@@ -134,10 +204,11 @@ impl Builder {
   /// mov eax, [rip+0x10]   ; the displacement before relocation
   /// mov eax, [rip+0x4892] ; theoretical adjustment after relocation
   /// ```
-  unsafe fn handle_rip_relative_instruction(
+  fn handle_rip_relative_instruction(
     &mut self,
     instruction: &Instruction,
     displacement: isize,
+    offset: usize,
   ) -> Result<Box<dyn pic::Thunkable>> {
     // If the instruction is an unconditional jump, processing stops here
     self.finished = instruction.is_unconditional_jump();
@@ -147,36 +218,135 @@ impl Builder {
       return Ok(Box::new(instruction.as_slice().to_vec()));
     }
 
+    let absolute = match self.operands {
+      Operands::Relative => false,
+      Operands::Absolute => true,
+      Operands::LocatedAt(address) => {
+        let relocated_address = address.wrapping_add(offset) as isize;
+        let adjusted_displacement = (instruction.address() as isize)
+          .wrapping_sub(relocated_address)
+          .wrapping_add(displacement);
+        i32::try_from(adjusted_displacement).is_err()
+      },
+    };
+
+    if absolute {
+      return Self::handle_absolute_rip_instruction(instruction, displacement);
+    }
+
     // These need to be captured by the closure
     let instruction_address = instruction.address() as isize;
     let instruction_bytes = instruction.as_slice().to_vec();
 
-    Ok(Box::new(pic::UnsafeThunk::new(
-      move |offset| {
-        let mut bytes = instruction_bytes.clone();
+    Ok(Box::new(unsafe {
+      pic::UnsafeThunk::new(
+        move |offset| {
+          let mut bytes = instruction_bytes.clone();
 
-        // Calculate the new relative displacement for the operand. The
-        // instruction is relative so the offset (i.e where the trampoline is
-        // allocated), must be within a range of +/- 2GB.
-        let adjusted_displacement = instruction_address
-          .wrapping_sub(offset as isize)
-          .wrapping_add(displacement);
-        assert!(crate::arch::is_within_range(adjusted_displacement));
+          // Calculate the new relative displacement for the operand. The
+          // instruction is relative so the offset (i.e where the trampoline is
+          // allocated), must be within a range of +/- 2GB.
+          let adjusted_displacement = instruction_address
+            .wrapping_sub(offset as isize)
+            .wrapping_add(displacement);
+          assert!(i32::try_from(adjusted_displacement).is_ok());
+
+          // The displacement value is placed at (instruction - disp32)
+          let index = instruction_bytes.len() - mem::size_of::<u32>();
+
+          // Write the adjusted displacement offset to the operand
+          let as_bytes = (adjusted_displacement as u32).to_le_bytes();
+          bytes[index..instruction_bytes.len()].copy_from_slice(&as_bytes);
+          bytes
+        },
+        instruction.len(),
+      )
+    }))
+  }
+
+  /// Rewrites an instruction's RIP relative operand to an absolute address,
+  /// which is loaded into a scratch register. An example would be:
+  ///
+  /// ```asm
+  /// mov eax, [rip+0x10]       ; the operand before relocation
+  ///
+  /// lea rsp, [rsp-0x80]       ; preserve the red zone
+  /// push rsi
+  /// mov rsi, 0x7FF0DEADBEEF   ; the operand's absolute address
+  /// mov eax, [rsi]
+  /// pop rsi
+  /// lea rsp, [rsp+0x80]
+  /// ```
+  fn handle_absolute_rip_instruction(
+    instruction: &Instruction,
+    displacement: isize,
+  ) -> Result<Box<dyn pic::Thunkable>> {
+    use udis::ud_type::*;
 
-        // The displacement value is placed at (instruction - disp32)
-        let index = instruction_bytes.len() - mem::size_of::<u32>();
+    let address = instruction
+      .next_instruction_address()
+      .wrapping_add(displacement as usize);
 
-        // Write the adjusted displacement offset to the operand
-        let as_bytes: [u8; 4] = mem::transmute(adjusted_displacement as u32);
-        bytes[index..instruction_bytes.len()].copy_from_slice(&as_bytes);
-        bytes
+    let bytes = instruction.as_slice();
+    let modrm_offset = match instruction.modrm_offset() {
+      Some(offset) if !instruction.has_vex_prefix() && !instruction.has_address_size_prefix() => {
+        offset
       },
-      instruction.len(),
-    )))
+      _ => Err(Error::UnsupportedInstruction)?,
+    };
+
+    let opcode = bytes[modrm_offset - 1];
+    let extension = (bytes[modrm_offset] >> 3) & 0b111;
+
+    // Indirect jumps (e.g import thunks) load their destination
+    if opcode == 0xFF && extension == 4 {
+      return Ok(thunk::x64::jmp_indirect_abs(address));
+    }
+
+    // Far branches, and instructions using the stack pointer, conflict with
+    // the scratch register being saved on the stack (e.g `call [rip+0x10]`).
+    if opcode == 0x8F
+      || (opcode == 0xFF && extension >= 2)
+      || instruction.uses_registers(&[UD_R_RSP, UD_R_ESP, UD_R_SP, UD_R_SPL])
+    {
+      Err(Error::UnsupportedInstruction)?;
+    }
+
+    // Use a scratch register which is not an operand of the instruction
+    let scratch = [
+      (6, [UD_R_RSI, UD_R_ESI, UD_R_SI, UD_R_SIL]),
+      (7, [UD_R_RDI, UD_R_EDI, UD_R_DI, UD_R_DIL]),
+      (3, [UD_R_RBX, UD_R_EBX, UD_R_BX, UD_R_BL]),
+    ]
+    .iter()
+    .find(|(_, registers)| !instruction.uses_registers(registers))
+    .map(|(scratch, _)| *scratch)
+    .ok_or(Error::UnsupportedInstruction)?;
+
+    // lea rsp, [rsp-0x80]
+    let mut code = vec![0x48, 0x8D, 0x64, 0x24, 0x80];
+    // push scratch; mov scratch, address
+    code.extend_from_slice(&[0x50 | scratch, 0x48, 0xB8 | scratch]);
+    code.extend_from_slice(&address.to_le_bytes());
+
+    // The operand is replaced by the scratch register (i.e `mod` = 0), and its
+    // displacement is removed, whilst any trailing immediate is kept.
+    let start = code.len();
+    code.extend_from_slice(&bytes[..=modrm_offset]);
+    code[start + modrm_offset] = (bytes[modrm_offset] & 0b0011_1000) | scratch;
+    if let Some(rex_offset) = instruction.rex_offset() {
+      // The scratch register is not an extended register (i.e REX.B)
+      code[start + rex_offset] &= !0b0001;
+    }
+    code.extend_from_slice(&bytes[modrm_offset + 1 + mem::size_of::<u32>()..]);
+
+    // pop scratch; lea rsp, [rsp+0x80]
+    code.extend_from_slice(&[0x58 | scratch, 0x48, 0x8D, 0xA4, 0x24, 0x80, 0, 0, 0]);
+    Ok(Box::new(code))
   }
 
   /// Processes relative branches (e.g `call`, `loop`, `jne`).
-  unsafe fn handle_relative_branch(
+  fn handle_relative_branch(
     &mut self,
     instruction: &Instruction,
     displacement: isize,
@@ -188,10 +358,10 @@ impl Builder {
 
     if instruction.is_call() {
       // Calls are not an issue since they return to the original address
-      return Ok(thunk::call(destination_address_abs));
+      return Ok(self.call(destination_address_abs));
     }
 
-    let prolog_range = (self.target as usize)..(self.target as usize + self.margin);
+    let prolog_range = self.target..(self.target + self.margin);
 
     // If the relative jump is internal, and short enough to
     // fit within the copied function prolog (i.e `margin`),
@@ -207,7 +377,7 @@ impl Builder {
       // If the function is not in a branch, and it unconditionally jumps
       // a distance larger than the prolog, it's the same as if it terminates.
       self.finished = !self.is_instruction_in_branch(instruction);
-      Ok(thunk::jmp(destination_address_abs))
+      Ok(self.jmp(destination_address_abs))
     } else {
       // Conditional jumps (Jcc)
       // To extract the condition, the primary opcode is required. Short
@@ -220,7 +390,34 @@ impl Builder {
 
       // Extract the condition (i.e 0x74 is [jz rel8] ⟶ 0x74 & 0x0F == 4)
       let condition = primary_opcode & 0x0F;
-      Ok(thunk::jcc(destination_address_abs, condition))
+      Ok(self.jcc(destination_address_abs, condition))
+    }
+  }
+
+  /// Constructs a call, which reaches any address in the mode.
+  fn call(&self, destination: usize) -> Box<dyn pic::Thunkable> {
+    if self.is_64bit {
+      thunk::x64::call_abs(destination)
+    } else {
+      thunk::x86::call_rel32(destination, 32)
+    }
+  }
+
+  /// Constructs a jump, which reaches any address in the mode.
+  fn jmp(&self, destination: usize) -> Box<dyn pic::Thunkable> {
+    if self.is_64bit {
+      thunk::x64::jmp_abs(destination)
+    } else {
+      thunk::x86::jmp_rel32(destination, 32)
+    }
+  }
+
+  /// Constructs a conditional jump, which reaches any address in the mode.
+  fn jcc(&self, destination: usize, condition: u8) -> Box<dyn pic::Thunkable> {
+    if self.is_64bit {
+      thunk::x64::jcc_abs(destination, condition)
+    } else {
+      thunk::x86::jcc_rel32(destination, condition, 32)
     }
   }
 
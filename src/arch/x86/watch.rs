@@ -0,0 +1,392 @@
+//! Watches of memory accesses, using page protection.
+//!
+//! The pages containing a watched range are made inaccessible, so any access
+//! raises a `SIGSEGV`. The handler reports the access, restores the pages'
+//! protection and single-steps the faulting instruction using the trap flag.
+//! Once the instruction has executed, the `SIGTRAP` handler makes the pages
+//! inaccessible again.
+use super::trampoline::disasm::Disassembler;
+use crate::arch::signal;
+use crate::error::{Error, Result};
+use crate::util;
+use lazy_static::lazy_static;
+use std::cell::Cell;
+use std::ops::Range;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::{cmp, fmt, ptr, thread};
+
+/// The maximum number of simultaneous watches.
+const CAPACITY: usize = 64;
+
+/// The flag of the page fault error code set for writes.
+const PF_WRITE: libc::greg_t = 1 << 1;
+
+/// The trap flag, raising a `SIGTRAP` after each instruction.
+const TRAP_FLAG: libc::greg_t = 1 << 8;
+
+/// The signal code of traps caused by the trap flag.
+const TRAP_TRACE: libc::c_int = 2;
+
+#[cfg(target_arch = "x86_64")]
+const REG_IP: libc::c_int = libc::REG_RIP;
+#[cfg(target_arch = "x86")]
+const REG_IP: libc::c_int = libc::REG_EIP;
+
+lazy_static! {
+  /// Serializes modifications of the slots.
+  static ref REGISTRY_LOCK: Mutex<()> = Mutex::new(());
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_SLOT: AtomicPtr<State> = AtomicPtr::new(ptr::null_mut());
+
+/// The registered watches.
+///
+/// These are read by the signal handler, so they're lock-free.
+static SLOTS: [AtomicPtr<State>; CAPACITY] = [EMPTY_SLOT; CAPACITY];
+
+/// The number of threads currently reading the slots within a handler.
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+
+/// The `SIGSEGV` handler of watches.
+static FAULT_HANDLER: signal::Handler = signal::Handler::new();
+
+/// The `SIGTRAP` handler of watches.
+static STEP_HANDLER: signal::Handler = signal::Handler::new();
+
+thread_local! {
+  /// The watch whose instruction is being single-stepped on this thread.
+  static STEPPING: Cell<*const State> = const { Cell::new(ptr::null()) };
+}
+
+/// The kind of a memory access.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessKind {
+  Read,
+  Write,
+}
+
+/// A memory access observed by a watch.
+#[derive(Debug, Clone, Copy)]
+pub struct Access {
+  kind: AccessKind,
+  address: *const (),
+  instruction: *const (),
+  size: Option<usize>,
+}
+
+impl Access {
+  /// Returns whether the memory was read or written.
+  pub fn kind(&self) -> AccessKind {
+    self.kind
+  }
+
+  /// Returns the accessed address.
+  pub fn address(&self) -> *const () {
+    self.address
+  }
+
+  /// Returns the address of the accessing instruction.
+  pub fn instruction(&self) -> *const () {
+    self.instruction
+  }
+
+  /// Returns the size of the access in bytes, if it could be decoded (i.e
+  /// the instruction has an explicit memory operand).
+  pub fn size(&self) -> Option<usize> {
+    self.size
+  }
+}
+
+/// The shared state of a watch, referenced by the signal handlers.
+struct State {
+  callback: Box<dyn Fn(&Access) + Send + Sync>,
+  range: Range<usize>,
+  pages: Range<usize>,
+  /// The original protection of the pages, by region.
+  protection: Vec<(Range<usize>, region::Protection)>,
+  enabled: AtomicBool,
+  /// The number of threads single-stepping an instruction.
+  steps: AtomicUsize,
+  /// The number of threads finishing a single-step.
+  completing: AtomicUsize,
+}
+
+impl State {
+  /// Sets the protection of the pages, or restores their original protection.
+  unsafe fn protect(&self, protection: Option<region::Protection>) {
+    for (range, original) in &self.protection {
+      let protection = protection.unwrap_or(*original);
+      let _ = region::protect(range.start as *const u8, range.len(), protection);
+    }
+  }
+
+  /// Returns the original protection of a page.
+  fn protection_at(&self, address: usize) -> region::Protection {
+    self
+      .protection
+      .iter()
+      .find(|(range, _)| range.contains(&address))
+      .map_or(region::Protection::NONE, |(_, protection)| *protection)
+  }
+
+  /// Waits until no thread is single-stepping an instruction.
+  fn wait_for_steps(&self) {
+    while self.steps.load(Ordering::SeqCst) > 0 || self.completing.load(Ordering::SeqCst) > 0 {
+      thread::yield_now();
+    }
+  }
+}
+
+/// A watch of reads and writes to a range of memory.
+pub struct Watch {
+  state: Box<State>,
+  lock: Mutex<()>,
+}
+
+impl Watch {
+  /// Constructs a watch of `size` bytes at `address`, invoking `callback`
+  /// for each access.
+  pub unsafe fn new<C>(address: *const (), size: usize, callback: C) -> Result<Self>
+  where
+    C: Fn(&Access) + Send + Sync + 'static,
+  {
+    if size == 0 {
+      Err(region::Error::InvalidParameter("size"))?;
+    }
+
+    // Code cannot be watched, since the handlers may be executing it
+    if util::is_executable_address(address)? {
+      Err(region::Error::InvalidParameter("address"))?;
+    }
+
+    let page_size = region::page::size();
+    let start = address as usize & !(page_size - 1);
+    let end = (address as usize + size + page_size - 1) & !(page_size - 1);
+
+    let protection = region::query_range(start as *const u8, end - start)?
+      .map(|region| {
+        let region = region?;
+        let range = region.as_range();
+        let range = cmp::max(range.start, start)..cmp::min(range.end, end);
+        Ok((range, region.protection()))
+      })
+      .collect::<region::Result<Vec<_>>>()?;
+
+    let state = Box::new(State {
+      callback: Box::new(callback),
+      range: address as usize..address as usize + size,
+      pages: start..end,
+      protection,
+      enabled: AtomicBool::new(false),
+      steps: AtomicUsize::new(0),
+      completing: AtomicUsize::new(0),
+    });
+
+    register(&state)?;
+    Ok(Watch {
+      state,
+      lock: Mutex::new(()),
+    })
+  }
+
+  /// Enables the watch.
+  pub unsafe fn enable(&self) -> Result<()> {
+    let _guard = util::lock(&self.lock);
+    if !self.state.enabled.swap(true, Ordering::SeqCst) {
+      self.state.protect(Some(region::Protection::NONE));
+    }
+    Ok(())
+  }
+
+  /// Disables the watch.
+  pub unsafe fn disable(&self) -> Result<()> {
+    let _guard = util::lock(&self.lock);
+    if self.state.enabled.swap(false, Ordering::SeqCst) {
+      // A thread finishing a single-step may still make the pages inaccessible
+      self.state.wait_for_steps();
+      self.state.protect(None);
+    }
+    Ok(())
+  }
+
+  /// Returns whether the watch is enabled or not.
+  pub fn is_enabled(&self) -> bool {
+    self.state.enabled.load(Ordering::SeqCst)
+  }
+}
+
+impl Drop for Watch {
+  /// Disables the watch, once no thread refers to it.
+  fn drop(&mut self) {
+    unsafe {
+      let _ = self.disable();
+      unregister(&self.state);
+    }
+
+    while ACTIVE.load(Ordering::SeqCst) > 0 {
+      thread::yield_now();
+    }
+    self.state.wait_for_steps();
+  }
+}
+
+impl fmt::Debug for Watch {
+  /// Output the watched range, and whether the watch is enabled or not.
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "Watch {{ enabled: {}, range: {:#x}..{:#x} }}",
+      self.is_enabled(),
+      self.state.range.start,
+      self.state.range.end
+    )
+  }
+}
+
+unsafe impl Send for Watch {}
+unsafe impl Sync for Watch {}
+
+/// Registers a watch, whose pages must not be watched already.
+fn register(state: &State) -> Result<()> {
+  FAULT_HANDLER.install(libc::SIGSEGV, handle_fault);
+  STEP_HANDLER.install(libc::SIGTRAP, handle_step);
+
+  let _guard = util::lock(&REGISTRY_LOCK);
+  let overlaps = SLOTS.iter().any(|slot| {
+    let other = slot.load(Ordering::SeqCst);
+    !other.is_null() && unsafe { overlaps(&(*other).pages, &state.pages) }
+  });
+
+  if overlaps {
+    Err(Error::AlreadyInitialized)?;
+  }
+
+  SLOTS
+    .iter()
+    .find(|slot| slot.load(Ordering::SeqCst).is_null())
+    .ok_or(Error::OutOfMemory)?
+    .store(state as *const State as *mut State, Ordering::SeqCst);
+  Ok(())
+}
+
+/// Unregisters a watch.
+fn unregister(state: &State) {
+  let _guard = util::lock(&REGISTRY_LOCK);
+  let state = state as *const State as *mut State;
+  if let Some(slot) = SLOTS
+    .iter()
+    .find(|slot| slot.load(Ordering::SeqCst) == state)
+  {
+    slot.store(ptr::null_mut(), Ordering::SeqCst);
+  }
+}
+
+/// Returns whether two ranges overlap.
+fn overlaps(lhs: &Range<usize>, rhs: &Range<usize>) -> bool {
+  lhs.start < rhs.end && rhs.start < lhs.end
+}
+
+/// Returns the watch whose pages contain an address.
+///
+/// The watch remains valid whilst `ACTIVE` is incremented.
+unsafe fn find(address: usize) -> Option<&'static State> {
+  SLOTS
+    .iter()
+    .map(|slot| slot.load(Ordering::SeqCst))
+    .filter(|state| !state.is_null())
+    .map(|state| &*state)
+    .find(|state| state.pages.contains(&address))
+}
+
+/// Reports an access to a watched page, and single-steps the instruction.
+unsafe extern "C" fn handle_fault(
+  signal: libc::c_int,
+  info: *mut libc::siginfo_t,
+  context: *mut libc::c_void,
+) {
+  ACTIVE.fetch_add(1, Ordering::SeqCst);
+  let handled = handle_access(&*info, &mut *(context as *mut libc::ucontext_t));
+  ACTIVE.fetch_sub(1, Ordering::SeqCst);
+
+  if !handled {
+    FAULT_HANDLER.forward(signal, info, context);
+  }
+}
+
+/// Handles a page fault, returning whether it was caused by a watch.
+unsafe fn handle_access(info: &libc::siginfo_t, context: &mut libc::ucontext_t) -> bool {
+  let address = info.si_addr() as usize;
+  let state = match find(address) {
+    Some(state) => state,
+    None => return false,
+  };
+
+  let registers = &mut context.uc_mcontext.gregs;
+  let kind = if registers[libc::REG_ERR as usize] & PF_WRITE != 0 {
+    AccessKind::Write
+  } else {
+    AccessKind::Read
+  };
+
+  // Accesses not permitted by the original protection are genuine faults
+  let required = match kind {
+    AccessKind::Read => region::Protection::READ,
+    AccessKind::Write => region::Protection::WRITE,
+  };
+  if !state.protection_at(address).contains(required) {
+    return false;
+  }
+
+  let instruction = registers[REG_IP as usize] as usize as *const ();
+  if state.enabled.load(Ordering::SeqCst) && state.range.contains(&address) {
+    let access = Access {
+      kind,
+      address: address as *const (),
+      instruction,
+      size: Disassembler::new(instruction).next_memory_operand_size(),
+    };
+
+    if panic::catch_unwind(AssertUnwindSafe(|| (state.callback)(&access))).is_err() {
+      signal::abort("detour: watch callback panicked, aborting\n");
+    }
+  }
+
+  // The instruction may fault again if another thread protected the pages
+  // meanwhile, in which case it's already single-stepping
+  if STEPPING.with(Cell::get).is_null() {
+    state.steps.fetch_add(1, Ordering::SeqCst);
+    STEPPING.with(|stepping| stepping.set(state));
+  }
+
+  state.protect(None);
+  registers[libc::REG_EFL as usize] |= TRAP_FLAG;
+  true
+}
+
+/// Makes the pages of a watch inaccessible again, once the faulting
+/// instruction has executed.
+unsafe extern "C" fn handle_step(
+  signal: libc::c_int,
+  info: *mut libc::siginfo_t,
+  context: *mut libc::c_void,
+) {
+  let state = STEPPING.with(Cell::get);
+  if (*info).si_code != TRAP_TRACE || state.is_null() {
+    return STEP_HANDLER.forward(signal, info, context);
+  }
+
+  let context = &mut *(context as *mut libc::ucontext_t);
+  context.uc_mcontext.gregs[libc::REG_EFL as usize] &= !TRAP_FLAG;
+  STEPPING.with(|stepping| stepping.set(ptr::null()));
+
+  // The pages remain accessible whilst other threads are single-stepping
+  let state = &*state;
+  state.completing.fetch_add(1, Ordering::SeqCst);
+  if state.steps.fetch_sub(1, Ordering::SeqCst) == 1 && state.enabled.load(Ordering::SeqCst) {
+    state.protect(Some(region::Protection::NONE));
+  }
+  state.completing.fetch_sub(1, Ordering::SeqCst);
+}
@@ -0,0 +1,83 @@
+use crate::arch::Breakpoint;
+use crate::error::Result;
+
+/// A breakpoint-based detour.
+///
+/// Instead of a jump, the target's first instruction is replaced by a
+/// breakpoint (`int3` on x86, `brk` on aarch64). Threads executing it are
+/// redirected to the detour by a process-wide `SIGTRAP` handler, which
+/// forwards any unrelated traps to the previously installed handler.
+///
+/// This allows hooking functions too short to contain a jump, at the cost of
+/// a signal for each invocation. The trampoline consists of the relocated
+/// first instruction, followed by a jump to the rest of the function.
+///
+/// This is only available on Linux (`x86`, `x86_64` & `aarch64`).
+///
+/// # Example
+///
+/// ```rust
+/// # use detour::Result;
+/// use detour::BreakpointDetour;
+/// use std::mem;
+///
+/// #[inline(never)]
+/// extern "C" fn add5(val: i32) -> i32 {
+///   unsafe { std::ptr::read_volatile(&val) + 5 }
+/// }
+///
+/// #[inline(never)]
+/// extern "C" fn add10(val: i32) -> i32 {
+///   unsafe { std::ptr::read_volatile(&val) + 10 }
+/// }
+///
+/// # fn main() -> Result<()> {
+/// let hook = unsafe { BreakpointDetour::new(add5 as *const (), add10 as *const ())? };
+///
+/// unsafe { hook.enable()? };
+/// let original: extern "C" fn(i32) -> i32 = unsafe { mem::transmute(hook.trampoline()) };
+///
+/// assert_eq!(add5(5), 15);
+/// assert_eq!(original(5), 10);
+///
+/// unsafe { hook.disable()? };
+/// assert_eq!(add5(5), 10);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct BreakpointDetour(Breakpoint);
+
+impl BreakpointDetour {
+  /// Constructs a new breakpoint detour.
+  ///
+  /// The hook is disabled by default.
+  pub unsafe fn new(target: *const (), detour: *const ()) -> Result<Self> {
+    Breakpoint::new(target, detour).map(BreakpointDetour)
+  }
+
+  /// Enables the detour.
+  pub unsafe fn enable(&self) -> Result<()> {
+    self.0.enable()
+  }
+
+  /// Disables the detour.
+  pub unsafe fn disable(&self) -> Result<()> {
+    self.0.disable()
+  }
+
+  /// Changes the detour, regardless of whether the hook is enabled or not.
+  pub unsafe fn set_detour(&self, detour: *const ()) -> Result<()> {
+    self.0.set_detour(detour)
+  }
+
+  /// Returns whether the detour is enabled or not.
+  pub fn is_enabled(&self) -> bool {
+    self.0.is_enabled()
+  }
+
+  /// Returns a reference to the generated trampoline.
+  pub fn trampoline(&self) -> &() {
+    self.0.trampoline()
+  }
+}
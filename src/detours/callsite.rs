@@ -0,0 +1,96 @@
+use crate::arch::CallSite;
+use crate::error::Result;
+
+/// A detour of a single call site.
+///
+/// Instead of patching the callee's prolog, which affects every caller, only
+/// the call instruction at the specified address is redirected. Supported
+/// calls are relative calls (`call rel32`) and calls through a static
+/// pointer (`call [rip+x]` on x64, `call [addr]` on x86) on x86, and `bl` on
+/// aarch64. If the detour is out of the call's reach, the call is redirected
+/// via a nearby relay.
+///
+/// The call's original destination is available through
+/// [original](#method.original). For calls through a pointer, it's the
+/// pointer's value at the time the detour was created.
+///
+/// # Example
+///
+/// ```rust
+/// # use detour::Result;
+/// use detour::CallSiteDetour;
+/// use std::mem;
+///
+/// extern "C" fn add5(val: i32) -> i32 {
+///   val + 5
+/// }
+///
+/// extern "C" fn add10(val: i32) -> i32 {
+///   val + 10
+/// }
+///
+/// # #[cfg(target_arch = "x86_64")]
+/// # fn main() -> Result<()> {
+/// # // `push rax; call [rip+2]; pop rcx; ret`, followed by the address of `add5`
+/// # let mut memory = region::alloc(17, region::Protection::READ_WRITE).unwrap();
+/// # let code = memory.as_mut_ptr::<u8>();
+/// # unsafe {
+/// #   let caller = [0x50, 0xFF, 0x15, 0x02, 0x00, 0x00, 0x00, 0x59, 0xC3];
+/// #   code.copy_from_nonoverlapping(caller.as_ptr(), caller.len());
+/// #   (code.add(caller.len()) as *mut usize).write_unaligned(add5 as *const () as usize);
+/// #   region::protect(code, 17, region::Protection::READ_EXECUTE).unwrap();
+/// # }
+/// # let call_add5: extern "C" fn(i32) -> i32 = unsafe { mem::transmute(code) };
+/// // The address of the call to `add5` within `call_add5`, e.g found by
+/// // disassembling it
+/// let call_site = (call_add5 as usize + 1) as *const ();
+/// let hook = unsafe { CallSiteDetour::new(call_site, add10 as *const ())? };
+///
+/// // Only this call site is redirected
+/// unsafe { hook.enable()? };
+/// assert_eq!(call_add5(5), 15);
+/// assert_eq!(add5(5), 10);
+///
+/// // The callee can still be invoked directly
+/// let original: extern "C" fn(i32) -> i32 = unsafe { mem::transmute(hook.original()) };
+/// assert_eq!(original(5), 10);
+///
+/// unsafe { hook.disable()? };
+/// assert_eq!(call_add5(5), 10);
+/// # Ok(())
+/// # }
+/// # #[cfg(not(target_arch = "x86_64"))]
+/// # fn main() {}
+/// ```
+#[derive(Debug)]
+pub struct CallSiteDetour(CallSite);
+
+impl CallSiteDetour {
+  /// Constructs a new call site detour, redirecting the call instruction at
+  /// `call` to `detour`.
+  ///
+  /// The hook is disabled by default.
+  pub unsafe fn new(call: *const (), detour: *const ()) -> Result<Self> {
+    CallSite::new(call, detour).map(CallSiteDetour)
+  }
+
+  /// Enables the detour.
+  pub unsafe fn enable(&self) -> Result<()> {
+    self.0.enable()
+  }
+
+  /// Disables the detour.
+  pub unsafe fn disable(&self) -> Result<()> {
+    self.0.disable()
+  }
+
+  /// Returns whether the detour is enabled or not.
+  pub fn is_enabled(&self) -> bool {
+    self.0.is_enabled()
+  }
+
+  /// Returns a reference to the call's original destination.
+  pub fn original(&self) -> &() {
+    self.0.original()
+  }
+}
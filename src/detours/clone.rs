@@ -0,0 +1,116 @@
+use crate::alloc::ExecutableMemory;
+use crate::arch;
+use crate::error::Result;
+use crate::Function;
+use std::fmt;
+use std::marker::PhantomData;
+
+/// A type-safe clone of an entire function.
+///
+/// The function's body is copied close to it, and any relative references to
+/// code or data outside of it (e.g calls, branches and RIP-relative operands)
+/// are adjusted for the copy's location. Since the layout is preserved, the
+/// clone remains independent of the original, which may subsequently be
+/// hooked or modified without affecting it, e.g to compare a patched function
+/// against a pristine copy.
+///
+/// Jump tables located outside of the function (e.g those of `switch`
+/// statements) would still refer to the original, so functions dispatching
+/// via them are rejected with `UnsupportedInstruction` on x86. Indirect
+/// branches which are computed otherwise may still reach the original.
+///
+/// Due to being generated by a macro, the `FunctionClone::call` method is not
+/// exposed in the documentation.
+/// It accepts the same arguments as `T`, and shares its result type:
+///
+/// ```c
+/// /// Calls the clone of the function.
+/// fn call(&self, T::Arguments) -> T::Output
+/// ```
+///
+/// # Example
+///
+/// ```rust
+/// # use detour::Result;
+/// use detour::{FunctionClone, GenericDetour};
+///
+/// #[inline(never)]
+/// fn add5(val: i32) -> i32 {
+///   val + 5
+/// }
+///
+/// fn add10(val: i32) -> i32 {
+///   val + 10
+/// }
+///
+/// # fn main() -> Result<()> {
+/// # if cfg!(not(target_os = "linux")) { return Ok(()); }
+/// let clone = unsafe { FunctionClone::<fn(i32) -> i32>::create(add5)? };
+/// assert_eq!(clone.call(5), 10);
+///
+/// // The clone is unaffected by modifications of the original
+/// let hook = unsafe { GenericDetour::<fn(i32) -> i32>::new(add5, add10)? };
+/// unsafe { hook.enable()? };
+///
+/// assert_eq!(add5(5), 15);
+/// assert_eq!(clone.call(5), 10);
+/// # Ok(())
+/// # }
+/// ```
+pub struct FunctionClone<T: Function> {
+  phantom: PhantomData<T>,
+  code: ExecutableMemory,
+}
+
+impl<T: Function> FunctionClone<T> {
+  /// Creates a clone of a function, whose size is determined by its symbol or
+  /// its unwind info (Linux only).
+  ///
+  /// Returns `UnknownBounds` if neither is available; use `with_size` instead.
+  pub unsafe fn create(function: T) -> Result<Self> {
+    Self::build(function, None)
+  }
+
+  /// Creates a clone of a function spanning `size` bytes.
+  ///
+  /// The size must cover the function's entire body, including any data
+  /// embedded in it (e.g jump tables), but not beyond it.
+  pub unsafe fn with_size(function: T, size: usize) -> Result<Self> {
+    Self::build(function, Some(size))
+  }
+
+  unsafe fn build(function: T, size: Option<usize>) -> Result<Self> {
+    arch::create_clone(function.to_ptr(), size).map(|code| FunctionClone {
+      phantom: PhantomData,
+      code,
+    })
+  }
+
+  /// Returns the clone as a function.
+  ///
+  /// This is required for C-variadic functions, which cannot be forwarded
+  /// using `call`. It is unsafe since the function is not bound to the
+  /// clone's lifetime, and must not be called once it has been dropped.
+  pub unsafe fn function(&self) -> T {
+    T::from_ptr(self.code.as_ptr() as *const ())
+  }
+
+  /// Returns a reference to the clone's code.
+  pub(crate) fn trampoline(&self) -> &() {
+    unsafe {
+      (self.code.as_ptr() as *const ())
+        .as_ref()
+        .expect("clone should not be null")
+    }
+  }
+}
+
+impl<T: Function> fmt::Debug for FunctionClone<T> {
+  /// Output the address of the clone.
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "FunctionClone {{ code: {:?} }}", self.code.as_ptr())
+  }
+}
+
+unsafe impl<T: Function> Send for FunctionClone<T> {}
+unsafe impl<T: Function> Sync for FunctionClone<T> {}
@@ -0,0 +1,96 @@
+use crate::arch::{ContextHook, ContextPanicPolicy, Detour};
+use crate::error::Result;
+use crate::CpuContext;
+
+/// A register-level detour.
+///
+/// Instead of replacing the target with another function, the callback is
+/// invoked with the register context of each invocation. This allows hooking
+/// functions whose prototype is unknown. The callback may inspect or
+/// overwrite the arguments, which are forwarded to the original function once
+/// it returns. Alternatively it may skip the original function by calling
+/// [CpuContext::skip](./struct.CpuContext.html#method.skip), or call it
+/// directly with
+/// [CpuContext::call_original](./struct.CpuContext.html#method.call_original).
+///
+/// This is only available on `x86_64` (System V) and `aarch64`.
+///
+/// # Example
+///
+/// ```rust
+/// # use detour::Result;
+/// use detour::ContextDetour;
+///
+/// #[inline(never)]
+/// extern "C" fn add5(val: i32) -> i32 {
+///   unsafe { std::ptr::read_volatile(&val) + 5 }
+/// }
+///
+/// # fn main() -> Result<()> {
+/// let hook = unsafe {
+///   ContextDetour::new(add5 as *const (), |context| {
+///     let value = context.arg(0) as i32;
+///     if value == 0 {
+///       context.set_return_value(-1i32 as u64);
+///       context.skip();
+///     } else {
+///       context.set_arg(0, (value * 2) as u64);
+///     }
+///   })?
+/// };
+///
+/// unsafe { hook.enable()? };
+/// assert_eq!(add5(5), 15);
+/// assert_eq!(add5(0), -1);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct ContextDetour {
+  detour: Detour,
+  hook: ContextHook,
+}
+
+impl ContextDetour {
+  /// Constructs a new context detour, invoking `callback` whenever the target
+  /// is called.
+  ///
+  /// The hook is disabled by default. The trampoline calls the original
+  /// function without invoking the callback.
+  pub unsafe fn new<C>(target: *const (), callback: C) -> Result<Self>
+  where
+    C: Fn(&mut CpuContext) + Send + Sync + 'static,
+  {
+    let hook = ContextHook::new(target, callback)?;
+    let detour = Detour::new(target, hook.entry())?;
+    hook.set_trampoline(detour.trampoline() as *const ());
+    Ok(ContextDetour { detour, hook })
+  }
+
+  /// Enables the detour.
+  pub unsafe fn enable(&self) -> Result<()> {
+    self.detour.enable()
+  }
+
+  /// Disables the detour.
+  pub unsafe fn disable(&self) -> Result<()> {
+    self.detour.disable()
+  }
+
+  /// Returns whether the detour is enabled or not.
+  pub fn is_enabled(&self) -> bool {
+    self.detour.is_enabled()
+  }
+
+  /// Changes how a panic within the callback is handled.
+  ///
+  /// By default the process is aborted.
+  pub fn set_panic_policy(&self, policy: ContextPanicPolicy) {
+    self.hook.set_panic_policy(policy);
+  }
+
+  /// Returns a reference to the generated trampoline.
+  pub fn trampoline(&self) -> &() {
+    self.detour.trampoline()
+  }
+}
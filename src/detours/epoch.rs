@@ -0,0 +1,228 @@
+//! Deferred reclamation of values shared with detoured invocations.
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+use std::{mem, ptr};
+
+/// An epoch based reclamation domain.
+///
+/// A thread registers itself in the current epoch before loading any value
+/// protected by the domain. A replaced value is retired along with the epoch
+/// it was replaced in, and is released once the epoch has advanced twice.
+/// Since the epoch only advances once every thread registered in the previous
+/// epoch has left, no thread can still reference the value by then.
+///
+/// Values are released by whichever thread observes them as unreachable, so
+/// retired values never accumulate beyond the threads currently executing.
+pub struct Domain {
+  epoch: AtomicUsize,
+  readers: [AtomicUsize; 2],
+  retired: AtomicPtr<Retired>,
+}
+
+/// A value awaiting release.
+struct Retired {
+  value: *mut (),
+  drop: unsafe fn(*mut ()),
+  epoch: usize,
+  next: *mut Retired,
+}
+
+impl Domain {
+  /// Creates an empty domain.
+  pub const fn new() -> Self {
+    Domain {
+      epoch: AtomicUsize::new(0),
+      readers: [AtomicUsize::new(0), AtomicUsize::new(0)],
+      retired: AtomicPtr::new(ptr::null_mut()),
+    }
+  }
+
+  /// Registers the calling thread, allowing it to load protected values until
+  /// the guard is dropped.
+  pub fn enter(&self) -> Guard<'_> {
+    loop {
+      let epoch = self.epoch.load(Ordering::SeqCst);
+      self.readers[epoch % 2].fetch_add(1, Ordering::SeqCst);
+
+      // The epoch may have advanced before the thread was accounted for
+      if self.epoch.load(Ordering::SeqCst) == epoch {
+        return Guard {
+          domain: self,
+          epoch,
+        };
+      }
+      self.leave(epoch);
+    }
+  }
+
+  /// Retires a value, which has been replaced, to be released by `drop` once
+  /// no thread may reference it.
+  pub unsafe fn retire(&self, value: *mut (), drop: unsafe fn(*mut ())) {
+    // The epoch must be loaded after the value has been replaced
+    let node = Box::into_raw(Box::new(Retired {
+      value,
+      drop,
+      epoch: self.epoch.load(Ordering::SeqCst),
+      next: ptr::null_mut(),
+    }));
+
+    self.push(node, node);
+    self.reclaim();
+  }
+
+  /// Releases the retired values which are no longer reachable.
+  pub fn reclaim(&self) {
+    // Each advancement requires the threads of the preceding epoch to leave
+    self.try_advance();
+    let epoch = self.try_advance();
+
+    // The list is detached, so concurrent reclaimers never share a node
+    let mut node = self.retired.swap(ptr::null_mut(), Ordering::SeqCst);
+    let mut pending: (*mut Retired, *mut Retired) = (ptr::null_mut(), ptr::null_mut());
+
+    while !node.is_null() {
+      unsafe {
+        let next = (*node).next;
+        if (*node).epoch + 2 <= epoch {
+          release(Box::from_raw(node));
+        } else {
+          (*node).next = pending.0;
+          if pending.1.is_null() {
+            pending.1 = node;
+          }
+          pending.0 = node;
+        }
+        node = next;
+      }
+    }
+
+    if !pending.0.is_null() {
+      unsafe { self.push(pending.0, pending.1) };
+    }
+  }
+
+  /// Advances the epoch, unless a thread is still registered in the
+  /// preceding one, and returns the current epoch.
+  fn try_advance(&self) -> usize {
+    let epoch = self.epoch.load(Ordering::SeqCst);
+    if self.readers[(epoch + 1) % 2].load(Ordering::SeqCst) == 0 {
+      let _ = self
+        .epoch
+        .compare_exchange(epoch, epoch + 1, Ordering::SeqCst, Ordering::SeqCst);
+    }
+    self.epoch.load(Ordering::SeqCst)
+  }
+
+  /// Unregisters a thread from an epoch.
+  fn leave(&self, epoch: usize) {
+    if self.readers[epoch % 2].fetch_sub(1, Ordering::SeqCst) == 1
+      && !self.retired.load(Ordering::SeqCst).is_null()
+    {
+      self.reclaim();
+    }
+  }
+
+  /// Prepends a chain of nodes to the retired list.
+  unsafe fn push(&self, head: *mut Retired, tail: *mut Retired) {
+    let mut retired = self.retired.load(Ordering::SeqCst);
+    loop {
+      (*tail).next = retired;
+      match self
+        .retired
+        .compare_exchange(retired, head, Ordering::SeqCst, Ordering::SeqCst)
+      {
+        Ok(_) => break,
+        Err(current) => retired = current,
+      }
+    }
+  }
+}
+
+impl Drop for Domain {
+  fn drop(&mut self) {
+    let mut node = mem::replace(self.retired.get_mut(), ptr::null_mut());
+    while !node.is_null() {
+      let retired = unsafe { Box::from_raw(node) };
+      node = retired.next;
+      release(retired);
+    }
+  }
+}
+
+/// A thread registered in a domain.
+pub struct Guard<'a> {
+  domain: &'a Domain,
+  epoch: usize,
+}
+
+impl<'a> Drop for Guard<'a> {
+  fn drop(&mut self) {
+    self.domain.leave(self.epoch);
+  }
+}
+
+/// Releases a retired value.
+///
+/// This may be called whilst returning from a detour, so a panic in the
+/// value's destructor is contained, leaking whatever remains of it.
+fn release(retired: Box<Retired>) {
+  let _ = panic::catch_unwind(AssertUnwindSafe(|| unsafe {
+    (retired.drop)(retired.value)
+  }));
+}
+
+/// Releases a value allocated as a `Box<T>`.
+pub unsafe fn drop_box<T>(value: *mut ()) {
+  mem::drop(Box::from_raw(value as *mut T));
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::sync::atomic::AtomicBool;
+  use std::sync::Arc;
+
+  /// Flags itself once dropped.
+  struct Flag(Arc<AtomicBool>);
+
+  impl Drop for Flag {
+    fn drop(&mut self) {
+      self.0.store(true, Ordering::SeqCst);
+    }
+  }
+
+  fn retire_flag(domain: &Domain) -> Arc<AtomicBool> {
+    let dropped = Arc::new(AtomicBool::new(false));
+    let value = Box::into_raw(Box::new(Flag(dropped.clone())));
+    unsafe { domain.retire(value as *mut (), drop_box::<Flag>) };
+    dropped
+  }
+
+  #[test]
+  fn retired_values_outlive_readers() {
+    let domain = Domain::new();
+    let guard = domain.enter();
+
+    let dropped = retire_flag(&domain);
+    assert!(!dropped.load(Ordering::SeqCst));
+
+    mem::drop(guard);
+    assert!(dropped.load(Ordering::SeqCst));
+  }
+
+  #[test]
+  fn overlapping_readers_do_not_prevent_release() {
+    let domain = Domain::new();
+    let mut guard = domain.enter();
+
+    let dropped = retire_flag(&domain);
+    for _ in 0..4 {
+      // A new reader always enters before the previous one leaves
+      let next = domain.enter();
+      mem::drop(mem::replace(&mut guard, next));
+    }
+
+    assert!(dropped.load(Ordering::SeqCst));
+    mem::drop(guard);
+  }
+}
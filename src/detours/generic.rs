@@ -1,7 +1,8 @@
 use crate::arch::Detour;
 use crate::error::Result;
-use crate::{Function, HookableWith};
+use crate::{ExecutableAllocator, Function, HookableWith};
 use std::marker::PhantomData;
+use std::sync::Arc;
 
 /// A type-safe detour.
 ///
@@ -65,6 +66,40 @@ impl<T: Function> GenericDetour<T> {
     })
   }
 
+  /// Create a new hook, optimized for frequent toggling.
+  ///
+  /// See [RawDetour::with_fast_toggle](./struct.RawDetour.html#method.
+  /// with_fast_toggle).
+  pub unsafe fn with_fast_toggle<D>(target: T, detour: D) -> Result<Self>
+  where
+    T: HookableWith<D>,
+    D: Function,
+  {
+    Detour::with_fast_toggle(target.to_ptr(), detour.to_ptr()).map(|detour| GenericDetour {
+      phantom: PhantomData,
+      detour,
+    })
+  }
+
+  /// Create a new hook, whose trampoline and relay are allocated by
+  /// `allocator` instead of the global allocator.
+  pub unsafe fn with_allocator<D>(
+    target: T,
+    detour: D,
+    allocator: Arc<dyn ExecutableAllocator>,
+  ) -> Result<Self>
+  where
+    T: HookableWith<D>,
+    D: Function,
+  {
+    Detour::with_allocator(target.to_ptr(), detour.to_ptr(), allocator).map(|detour| {
+      GenericDetour {
+        phantom: PhantomData,
+        detour,
+      }
+    })
+  }
+
   /// Enables the detour.
   pub unsafe fn enable(&self) -> Result<()> {
     self.detour.enable()
@@ -75,11 +110,42 @@ impl<T: Function> GenericDetour<T> {
     self.detour.disable()
   }
 
+  /// Changes the detour, regardless of whether the hook is enabled or not.
+  ///
+  /// The target is not re-patched; the destination is atomically updated.
+  pub unsafe fn set_detour<D>(&self, detour: D) -> Result<()>
+  where
+    T: HookableWith<D>,
+    D: Function,
+  {
+    self.detour.set_detour(detour.to_ptr())
+  }
+
+  /// Disables the detour, without restoring the target if it no longer
+  /// contains the patch.
+  pub(crate) unsafe fn detach(&self) -> Result<()> {
+    self.detour.detach()
+  }
+
+  /// Returns whether `target` is patched by the detour.
+  pub(crate) unsafe fn is_patching(&self, target: T) -> bool {
+    self.detour.is_patching(target.to_ptr())
+  }
+
   /// Returns whether the detour is enabled or not.
   pub fn is_enabled(&self) -> bool {
     self.detour.is_enabled()
   }
 
+  /// Returns the original function, callable via the trampoline.
+  ///
+  /// This is required for C-variadic functions, which cannot be forwarded
+  /// using `call`. It is unsafe since the function is not bound to the
+  /// detour's lifetime, and must not be called once it has been dropped.
+  pub unsafe fn original(&self) -> T {
+    T::from_ptr(self.trampoline() as *const ())
+  }
+
   /// Returns a reference to the generated trampoline.
   pub(crate) fn trampoline(&self) -> &() {
     self.detour.trampoline()
@@ -1,14 +1,46 @@
 use cfg_if::cfg_if;
 
+mod callsite;
+mod clone;
 mod generic;
 mod raw;
+mod trampoline;
 
+pub use self::callsite::*;
+pub use self::clone::*;
 pub use self::generic::*;
 pub use self::raw::*;
+pub use self::trampoline::*;
+
+#[cfg(all(
+  target_os = "linux",
+  any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")
+))]
+mod breakpoint;
+#[cfg(all(
+  target_os = "linux",
+  any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")
+))]
+pub use self::breakpoint::*;
+
+#[cfg(all(target_os = "linux", any(target_arch = "x86", target_arch = "x86_64")))]
+mod watch;
+#[cfg(all(target_os = "linux", any(target_arch = "x86", target_arch = "x86_64")))]
+pub use self::watch::*;
+
+#[cfg(any(all(target_arch = "x86_64", unix), target_arch = "aarch64"))]
+mod context;
+#[cfg(any(all(target_arch = "x86_64", unix), target_arch = "aarch64"))]
+pub use self::context::*;
 
 cfg_if! {
     if #[cfg(feature = "nightly")] {
+        mod epoch;
+        mod policy;
+        mod probe;
         mod statik;
+        pub use self::policy::PanicPolicy;
+        pub use self::probe::*;
         pub use self::statik::*;
     } else {
     }
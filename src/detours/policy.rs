@@ -0,0 +1,87 @@
+use crate::Function;
+
+/// Describes how a panic within a static detour or probe is handled.
+///
+/// Since the detour may be called by foreign code, the panic is caught before
+/// reaching the detoured function's caller, unless the function's ABI permits
+/// unwinding and the panic is set to propagate.
+///
+/// # Example
+///
+/// ```rust
+/// use std::error::Error;
+/// use detour::{static_detour, PanicPolicy};
+///
+/// static_detour! {
+///   static Test: extern "C" fn(i32) -> i32;
+/// }
+///
+/// extern "C" fn add5(val: i32) -> i32 {
+///   val + 5
+/// }
+///
+/// fn main() -> Result<(), Box<dyn Error>> {
+///   unsafe { Test.initialize(add5, |_| panic!("unexpected call"))? };
+///   Test.set_panic_policy(PanicPolicy::fallback(-1));
+///
+///   unsafe { Test.enable()? };
+///   assert_eq!(add5(1), -1);
+///   Ok(())
+/// }
+/// ```
+pub struct PanicPolicy<T: Function>(Policy<T>);
+
+/// The handling of a panic.
+pub(super) enum Policy<T: Function> {
+  Abort,
+  Propagate,
+  CallOriginal(fn(&T::Arguments) -> T::Arguments),
+  Fallback(Box<dyn Fn() -> T::Output + Send + Sync>),
+}
+
+impl<T: Function> PanicPolicy<T> {
+  /// Aborts the process with a diagnostic naming the detour.
+  ///
+  /// This is the default for functions whose ABI does not permit unwinding.
+  pub fn abort() -> Self {
+    PanicPolicy(Policy::Abort)
+  }
+
+  /// Resumes unwinding into the detoured function's caller.
+  ///
+  /// This is the default for functions whose ABI permits unwinding (e.g
+  /// `Rust` or `C-unwind`). For any other function the process is aborted.
+  ///
+  /// The compiler may assume that a function which cannot panic never
+  /// unwinds, so the caller might not expect the panic.
+  pub fn propagate() -> Self {
+    PanicPolicy(Policy::Propagate)
+  }
+
+  /// Calls the original function, with the arguments passed to the detour.
+  pub fn call_original() -> Self
+  where
+    T::Arguments: Clone,
+  {
+    PanicPolicy(Policy::CallOriginal(T::Arguments::clone))
+  }
+
+  /// Returns a copy of `value` to the caller.
+  pub fn fallback(value: T::Output) -> Self
+  where
+    T::Output: Clone + Send + Sync + 'static,
+  {
+    PanicPolicy(Policy::Fallback(Box::new(move || value.clone())))
+  }
+}
+
+impl<T: Function> Policy<T> {
+  /// Returns the policy in effect, given the one that has been set (if any).
+  pub fn resolve(policy: Option<&PanicPolicy<T>>) -> &Self {
+    policy.map(|policy| &policy.0).unwrap_or(if T::UNWINDS {
+      &Policy::Propagate
+    } else {
+      &Policy::Abort
+    })
+  }
+}
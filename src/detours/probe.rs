@@ -0,0 +1,297 @@
+use super::epoch::{self, Domain};
+use super::policy::{PanicPolicy, Policy};
+use crate::error::{Error, Result};
+use crate::traits::sealed::Invoke;
+use crate::{Function, GenericDetour};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicPtr, Ordering};
+use std::{mem, process, ptr};
+
+/// A type-safe, observation-only static detour.
+///
+/// Unlike a [StaticDetour](./struct.StaticDetour.html), a probe does not
+/// replace the target function. Whilst enabled, every invocation of the
+/// target calls the `on_enter` callback with its arguments, forwards the
+/// arguments to the original function, and calls the `on_exit` callback with
+/// the arguments and the returned value, before passing the value back to
+/// the caller. Panics within the callbacks are handled according to the
+/// probe's [PanicPolicy](./struct.PanicPolicy.html).
+///
+/// Due to being generated by a macro, the `ProbeDetour::call` method is not
+/// exposed in the documentation.
+///
+/// ```c
+/// /// Calls the original function regardless of whether it's probed or not.
+/// ///
+/// /// Panics if called when the probe has not yet been initialized.
+/// fn call(&self, T::Arguments) -> T::Output
+/// ```
+///
+/// To define a probe, use the [static_probe](./macro.static_probe.html)
+/// macro.
+///
+/// # Example
+///
+/// ```rust
+/// use std::error::Error;
+/// use std::sync::atomic::{AtomicI32, Ordering};
+/// use detour::static_probe;
+///
+/// static_probe! {
+///   static Test: fn(i32) -> i32;
+/// }
+///
+/// fn add5(val: i32) -> i32 {
+///   val + 5
+/// }
+///
+/// fn main() -> Result<(), Box<dyn Error>> {
+///   static LAST: AtomicI32 = AtomicI32::new(0);
+///
+///   unsafe { Test.initialize(add5)? };
+///   Test.on_exit(|&(val,), &output| LAST.store(val * output, Ordering::SeqCst));
+///
+///   unsafe { Test.enable()? };
+///
+///   // The function still behaves as usual, but it is observed
+///   assert_eq!(add5(1), 6);
+///   assert_eq!(LAST.load(Ordering::SeqCst), 6);
+///
+///   unsafe { Test.disable()? };
+///
+///   assert_eq!(add5(2), 7);
+///   assert_eq!(LAST.load(Ordering::SeqCst), 6);
+///   Ok(())
+/// }
+/// ```
+pub struct ProbeDetour<T: Function> {
+  enter: AtomicPtr<Enter<T>>,
+  exit: AtomicPtr<Exit<T>>,
+  domain: Domain,
+  panic_policy: AtomicPtr<PanicPolicy<T>>,
+  detour: AtomicPtr<GenericDetour<T>>,
+  name: &'static str,
+  ffi: T,
+}
+
+impl<T: Function> ProbeDetour<T> {
+  /// Create a new probe.
+  #[doc(hidden)]
+  pub const fn __new(ffi: T, name: &'static str) -> Self {
+    ProbeDetour {
+      enter: AtomicPtr::new(ptr::null_mut()),
+      exit: AtomicPtr::new(ptr::null_mut()),
+      domain: Domain::new(),
+      panic_policy: AtomicPtr::new(ptr::null_mut()),
+      detour: AtomicPtr::new(ptr::null_mut()),
+      name,
+      ffi,
+    }
+  }
+
+  /// Create a new probe for a target function.
+  ///
+  /// This method can only be called once per static instance. Multiple calls
+  /// will error with `AlreadyInitialized`.
+  ///
+  /// It returns `&self` to allow chaining initialization and activation.
+  pub unsafe fn initialize(&self, target: T) -> Result<&Self> {
+    let mut detour = Box::new(GenericDetour::new(target, self.ffi)?);
+    if self
+      .detour
+      .compare_exchange(
+        ptr::null_mut(),
+        &mut *detour,
+        Ordering::SeqCst,
+        Ordering::SeqCst,
+      )
+      .is_err()
+    {
+      Err(Error::AlreadyInitialized)?;
+    }
+
+    mem::forget(detour);
+    Ok(self)
+  }
+
+  /// Enables the probe.
+  pub unsafe fn enable(&self) -> Result<()> {
+    self
+      .detour
+      .load(Ordering::SeqCst)
+      .as_ref()
+      .ok_or(Error::NotInitialized)?
+      .enable()
+  }
+
+  /// Disables the probe.
+  pub unsafe fn disable(&self) -> Result<()> {
+    self
+      .detour
+      .load(Ordering::SeqCst)
+      .as_ref()
+      .ok_or(Error::NotInitialized)?
+      .disable()
+  }
+
+  /// Returns whether the probe is enabled or not.
+  pub fn is_enabled(&self) -> bool {
+    unsafe { self.detour.load(Ordering::SeqCst).as_ref() }
+      .map(|detour| detour.is_enabled())
+      .unwrap_or(false)
+  }
+
+  /// Sets the callback invoked before the original function is called.
+  ///
+  /// The previous callback is released once no thread is executing it.
+  pub fn on_enter<C>(&self, callback: C)
+  where
+    C: Fn(&T::Arguments) + Send + Sync + 'static,
+  {
+    let enter: Box<Enter<T>> = Box::new(Box::new(callback));
+    self.replace(&self.enter, Box::into_raw(enter));
+  }
+
+  /// Sets the callback invoked after the original function has returned.
+  ///
+  /// The arguments are cloned before being passed to the original function.
+  /// For arguments which cannot be cloned (e.g mutable references), use
+  /// [on_return](#method.on_return) instead.
+  ///
+  /// This replaces any callback set by `on_return`.
+  pub fn on_exit<C>(&self, callback: C)
+  where
+    C: Fn(&T::Arguments, &T::Output) + Send + Sync + 'static,
+    T::Arguments: Clone,
+  {
+    let exit = Box::new(Exit::WithArguments(T::Arguments::clone, Box::new(callback)));
+    self.replace(&self.exit, Box::into_raw(exit));
+  }
+
+  /// Sets the callback invoked with the value returned by the original
+  /// function.
+  ///
+  /// This replaces any callback set by `on_exit`.
+  pub fn on_return<C>(&self, callback: C)
+  where
+    C: Fn(&T::Output) + Send + Sync + 'static,
+  {
+    let exit = Box::new(Exit::Output(Box::new(callback)));
+    self.replace(&self.exit, Box::into_raw(exit));
+  }
+
+  /// Changes how a panic within the callbacks is handled.
+  ///
+  /// The policies apply as they do for a static detour, except that calling
+  /// the original function continues the invocation as usual, and that a
+  /// fallback value replaces the original function's.
+  pub fn set_panic_policy(&self, policy: PanicPolicy<T>) {
+    self.replace(&self.panic_policy, Box::into_raw(Box::new(policy)));
+  }
+
+  /// Returns a reference to the generated trampoline.
+  pub(crate) fn trampoline(&self) -> Result<&()> {
+    Ok(
+      unsafe { self.detour.load(Ordering::SeqCst).as_ref() }
+        .ok_or(Error::NotInitialized)?
+        .trampoline(),
+    )
+  }
+
+  /// Replaces a value shared with probed invocations, releasing the previous
+  /// value once no thread is executing the probe.
+  fn replace<V>(&self, slot: &AtomicPtr<V>, value: *mut V) {
+    let previous = slot.swap(value, Ordering::SeqCst);
+    if !previous.is_null() {
+      unsafe {
+        self
+          .domain
+          .retire(previous as *mut (), epoch::drop_box::<V>)
+      };
+    }
+  }
+}
+
+impl<T: Invoke> ProbeDetour<T> {
+  /// Observes an invocation and forwards it to the original function.
+  #[doc(hidden)]
+  pub fn __probe(&self, arguments: T::Arguments) -> T::Output {
+    let _guard = self.domain.enter();
+    let policy = Policy::resolve(unsafe { self.panic_policy.load(Ordering::SeqCst).as_ref() });
+
+    if let Some(enter) = unsafe { self.enter.load(Ordering::SeqCst).as_ref() } {
+      if let Some(fallback) = self.observe(policy, || enter(&arguments)) {
+        return fallback;
+      }
+    }
+
+    let original: T =
+      unsafe { T::from_ptr(self.trampoline().expect("retrieving probe trampoline") as *const ()) };
+
+    match unsafe { self.exit.load(Ordering::SeqCst).as_ref() } {
+      Some(Exit::WithArguments(clone, exit)) => {
+        let output = unsafe { original.invoke(clone(&arguments)) };
+        self
+          .observe(policy, || exit(&arguments, &output))
+          .unwrap_or(output)
+      },
+      Some(Exit::Output(exit)) => {
+        let output = unsafe { original.invoke(arguments) };
+        self.observe(policy, || exit(&output)).unwrap_or(output)
+      },
+      None => unsafe { original.invoke(arguments) },
+    }
+  }
+
+  /// Invokes a callback, handling any panic according to the policy.
+  ///
+  /// Returns the value to return to the caller instead, if any.
+  fn observe<C: FnOnce()>(&self, policy: &Policy<T>, callback: C) -> Option<T::Output> {
+    if let Policy::Propagate = policy {
+      if T::UNWINDS {
+        callback();
+        return None;
+      }
+    }
+
+    match panic::catch_unwind(AssertUnwindSafe(callback)) {
+      Ok(()) => None,
+      Err(_) => match policy {
+        Policy::CallOriginal(_) => None,
+        Policy::Fallback(fallback) => Some(fallback()),
+        _ => {
+          eprintln!("detour: probe `{}` panicked, aborting", self.name);
+          process::abort();
+        },
+      },
+    }
+  }
+}
+
+impl<T: Function> Drop for ProbeDetour<T> {
+  fn drop(&mut self) {
+    self.replace(&self.enter, ptr::null_mut());
+    self.replace(&self.exit, ptr::null_mut());
+    self.replace(&self.panic_policy, ptr::null_mut());
+
+    let previous = self.detour.swap(ptr::null_mut(), Ordering::Relaxed);
+    if !previous.is_null() {
+      mem::drop(unsafe { Box::from_raw(previous) });
+    }
+  }
+}
+
+/// A callback invoked before the original function is called.
+type Enter<T> = Box<dyn Fn(&<T as Function>::Arguments) + Send + Sync>;
+
+/// A callback invoked after the original function has returned.
+enum Exit<T: Function> {
+  WithArguments(fn(&T::Arguments) -> T::Arguments, Return<T>),
+  Output(Output<T>),
+}
+
+/// A callback invoked with the returned value.
+type Output<T> = Box<dyn Fn(&<T as Function>::Output) + Send + Sync>;
+
+/// A callback invoked with both the arguments and the returned value.
+type Return<T> = Box<dyn Fn(&<T as Function>::Arguments, &<T as Function>::Output) + Send + Sync>;
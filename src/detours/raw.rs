@@ -1,5 +1,7 @@
 use crate::arch::Detour;
 use crate::error::Result;
+use crate::ExecutableAllocator;
+use std::sync::Arc;
 
 /// A raw detour.
 ///
@@ -38,7 +40,12 @@ use crate::error::Result;
 /// # }
 /// ```
 #[derive(Debug)]
-pub struct RawDetour(Detour);
+pub struct RawDetour {
+  detour: Detour,
+  #[cfg(any(all(target_arch = "x86_64", unix), target_arch = "aarch64"))]
+  #[allow(dead_code)]
+  exit_hook: Option<crate::arch::ExitHook>,
+}
 
 // TODO: stop all threads in target during patch?
 impl RawDetour {
@@ -50,26 +57,118 @@ impl RawDetour {
   /// function might for example get inlined in which case it is impossible to
   /// hook at runtime.
   pub unsafe fn new(target: *const (), detour: *const ()) -> Result<Self> {
-    Detour::new(target, detour).map(RawDetour)
+    Detour::new(target, detour).map(|detour| RawDetour {
+      detour,
+      #[cfg(any(all(target_arch = "x86_64", unix), target_arch = "aarch64"))]
+      exit_hook: None,
+    })
+  }
+
+  /// Constructs a new inline detour patcher, optimized for frequent toggling.
+  ///
+  /// The target is patched for the lifetime of the detour, and invocations are
+  /// forwarded to either the detour or the trampoline depending on whether it
+  /// is enabled. Enabling or disabling the detour is therefore a single atomic
+  /// store, at the cost of an indirection when invoking the target.
+  pub unsafe fn with_fast_toggle(target: *const (), detour: *const ()) -> Result<Self> {
+    Detour::with_fast_toggle(target, detour).map(|detour| RawDetour {
+      detour,
+      #[cfg(any(all(target_arch = "x86_64", unix), target_arch = "aarch64"))]
+      exit_hook: None,
+    })
+  }
+
+  /// Constructs a new inline detour patcher, whose trampoline and relay are
+  /// allocated by `allocator` instead of the global allocator.
+  pub unsafe fn with_allocator(
+    target: *const (),
+    detour: *const (),
+    allocator: Arc<dyn ExecutableAllocator>,
+  ) -> Result<Self> {
+    Detour::with_allocator(target, detour, allocator).map(|detour| RawDetour {
+      detour,
+      #[cfg(any(all(target_arch = "x86_64", unix), target_arch = "aarch64"))]
+      exit_hook: None,
+    })
+  }
+
+  /// Constructs a new exit hook, invoking `callback` whenever the target
+  /// returns.
+  ///
+  /// The target is not replaced; invocations are forwarded to the original
+  /// function, and once it returns the callback is invoked with the register
+  /// context, allowing the return value to be inspected or modified. The
+  /// trampoline calls the original function without invoking the callback.
+  ///
+  /// While hooked, the return address of an active invocation is substituted
+  /// with a landing pad. Non-local jumps (e.g `longjmp`) out of the target are
+  /// supported, but unwinding through it (e.g C++ exceptions) is not.
+  ///
+  /// This is only available on `x86_64` (System V) and `aarch64`.
+  ///
+  /// # Example
+  ///
+  /// ```rust
+  /// # use detour::Result;
+  /// use detour::RawDetour;
+  ///
+  /// #[inline(never)]
+  /// extern "C" fn add5(val: i32) -> i32 {
+  ///   unsafe { std::ptr::read_volatile(&val) + 5 }
+  /// }
+  ///
+  /// # fn main() -> Result<()> {
+  /// let hook = unsafe {
+  ///   RawDetour::with_exit_hook(add5 as *const (), |context| {
+  ///     let value = context.return_value();
+  ///     context.set_return_value(value * 2);
+  ///   })?
+  /// };
+  ///
+  /// unsafe { hook.enable()? };
+  /// assert_eq!(add5(5), 20);
+  /// # Ok(())
+  /// # }
+  /// ```
+  #[cfg(any(all(target_arch = "x86_64", unix), target_arch = "aarch64"))]
+  pub unsafe fn with_exit_hook<C>(target: *const (), callback: C) -> Result<Self>
+  where
+    C: Fn(&mut crate::CpuContext) + Send + Sync + 'static,
+  {
+    let exit_hook = crate::arch::ExitHook::new(target, callback)?;
+    let detour = Detour::new(target, exit_hook.entry())?;
+    exit_hook.set_trampoline(detour.trampoline() as *const ());
+
+    Ok(RawDetour {
+      detour,
+      exit_hook: Some(exit_hook),
+    })
   }
 
   /// Enables the detour.
   pub unsafe fn enable(&self) -> Result<()> {
-    self.0.enable()
+    self.detour.enable()
   }
 
   /// Disables the detour.
   pub unsafe fn disable(&self) -> Result<()> {
-    self.0.disable()
+    self.detour.disable()
+  }
+
+  /// Changes the detour, regardless of whether the hook is enabled or not.
+  ///
+  /// The target is not re-patched; the destination is atomically updated.
+  pub unsafe fn set_detour(&self, detour: *const ()) -> Result<()> {
+    self.detour.set_detour(detour)
   }
 
   /// Returns whether the detour is enabled or not.
   pub fn is_enabled(&self) -> bool {
-    self.0.is_enabled()
+    self.detour.is_enabled()
   }
 
   /// Returns a reference to the generated trampoline.
   pub fn trampoline(&self) -> &() {
-    self.0.trampoline()
+    self.detour.trampoline()
   }
 }
@@ -1,7 +1,11 @@
+use super::epoch::{self, Domain, Guard};
+use super::policy::{PanicPolicy, Policy};
 use crate::error::{Error, Result};
+use crate::traits::sealed::Invoke;
 use crate::{Function, GenericDetour};
+use std::panic::{self, AssertUnwindSafe};
 use std::sync::atomic::{AtomicPtr, Ordering};
-use std::{mem, ptr};
+use std::{mem, process, ptr};
 
 /// A type-safe static detour.
 ///
@@ -62,18 +66,24 @@ use std::{mem, ptr};
 /// }
 /// ```
 pub struct StaticDetour<T: Function> {
-  closure: AtomicPtr<Box<dyn Fn<T::Arguments, Output = T::Output>>>,
+  closure: AtomicPtr<Node<T>>,
+  domain: Domain,
+  panic_policy: AtomicPtr<PanicPolicy<T>>,
   detour: AtomicPtr<GenericDetour<T>>,
+  name: &'static str,
   ffi: T,
 }
 
 impl<T: Function> StaticDetour<T> {
   /// Create a new static detour.
   #[doc(hidden)]
-  pub const fn __new(ffi: T) -> Self {
+  pub const fn __new(ffi: T, name: &'static str) -> Self {
     StaticDetour {
       closure: AtomicPtr::new(ptr::null_mut()),
+      domain: Domain::new(),
+      panic_policy: AtomicPtr::new(ptr::null_mut()),
       detour: AtomicPtr::new(ptr::null_mut()),
+      name,
       ffi,
     }
   }
@@ -81,8 +91,8 @@ impl<T: Function> StaticDetour<T> {
   /// Create a new hook given a target function and a compatible detour
   /// closure.
   ///
-  /// This method can only be called once per static instance. Multiple calls
-  /// will error with `AlreadyExisting`.
+  /// This method can only be called once per static instance, unless it has
+  /// been uninitialized. Multiple calls will error with `AlreadyExisting`.
   ///
   /// It returns `&self` to allow chaining initialization and activation:
   ///
@@ -103,7 +113,7 @@ impl<T: Function> StaticDetour<T> {
   /// ```
   pub unsafe fn initialize<D>(&self, target: T, closure: D) -> Result<&Self>
   where
-    D: Fn<T::Arguments, Output = T::Output> + Send + 'static,
+    D: Fn<T::Arguments, Output = T::Output> + Send + Sync + 'static,
   {
     let mut detour = Box::new(GenericDetour::new(target, self.ffi)?);
     if self
@@ -124,6 +134,75 @@ impl<T: Function> StaticDetour<T> {
     Ok(self)
   }
 
+  /// Tears down the detour, allowing the static to be initialized again.
+  ///
+  /// The target is restored (unless it is no longer mapped), the trampoline
+  /// and relay are released, and the closure and panic policy are cleared.
+  ///
+  /// No thread may execute the target or call the original function whilst
+  /// the detour is uninitialized.
+  pub unsafe fn uninitialize(&self) -> Result<()> {
+    self
+      .detour
+      .load(Ordering::SeqCst)
+      .as_ref()
+      .ok_or(Error::NotInitialized)?
+      .detach()?;
+
+    // Other threads may still be reading the detour or calling its trampoline
+    let previous = self.detour.swap(ptr::null_mut(), Ordering::SeqCst);
+    if !previous.is_null() {
+      self
+        .domain
+        .retire(previous as *mut (), epoch::drop_box::<GenericDetour<T>>);
+    }
+
+    let previous = self.panic_policy.swap(ptr::null_mut(), Ordering::SeqCst);
+    if !previous.is_null() {
+      self
+        .domain
+        .retire(previous as *mut (), epoch::drop_box::<PanicPolicy<T>>);
+    }
+
+    self.retire(self.closure.swap(ptr::null_mut(), Ordering::SeqCst));
+    Ok(())
+  }
+
+  /// Moves the detour to a new target, retaining the closure and whether the
+  /// detour is enabled.
+  ///
+  /// This allows following a library that has been unloaded and reloaded.
+  /// The previous target is restored once the new one is detoured, unless it
+  /// no longer contains the patch (e.g it is no longer mapped). On failure,
+  /// the previous target remains detoured.
+  ///
+  /// No thread may execute either target or call the original function whilst
+  /// the detour is retargeted.
+  pub unsafe fn retarget(&self, target: T) -> Result<&Self> {
+    let previous = self
+      .detour
+      .load(Ordering::SeqCst)
+      .as_ref()
+      .ok_or(Error::NotInitialized)?;
+
+    // The patch would otherwise be relocated as part of the new trampoline
+    if previous.is_patching(target) {
+      return Ok(self);
+    }
+
+    let detour = Box::new(GenericDetour::new(target, self.ffi)?);
+    if previous.is_enabled() {
+      detour.enable()?;
+    }
+    previous.detach()?;
+
+    let previous = self.detour.swap(Box::into_raw(detour), Ordering::SeqCst);
+    self
+      .domain
+      .retire(previous as *mut (), epoch::drop_box::<GenericDetour<T>>);
+    Ok(self)
+  }
+
   /// Enables the detour.
   pub unsafe fn enable(&self) -> Result<()> {
     self
@@ -146,26 +225,63 @@ impl<T: Function> StaticDetour<T> {
 
   /// Returns whether the detour is enabled or not.
   pub fn is_enabled(&self) -> bool {
+    let _guard = self.domain.enter();
     unsafe { self.detour.load(Ordering::SeqCst).as_ref() }
       .map(|detour| detour.is_enabled())
       .unwrap_or(false)
   }
 
   /// Changes the detour, regardless of whether the hook is enabled or not.
+  ///
+  /// The closure must be `Sync`, since any thread calling the target may
+  /// invoke it. The previous closure is released once no thread is executing
+  /// it.
   pub fn set_detour<C>(&self, closure: C)
   where
-    C: Fn<T::Arguments, Output = T::Output> + Send + 'static,
+    C: Fn<T::Arguments, Output = T::Output> + Send + Sync + 'static,
   {
+    let node = Box::new(Node {
+      call: call_closure::<T, C>,
+      drop: epoch::drop_box::<Node<T, C>>,
+      closure,
+    });
+
     let previous = self
       .closure
-      .swap(Box::into_raw(Box::new(Box::new(closure))), Ordering::SeqCst);
+      .swap(Box::into_raw(node) as *mut Node<T>, Ordering::SeqCst);
+    unsafe { self.retire(previous) };
+  }
+
+  /// Changes how a panic within the detour is handled.
+  ///
+  /// A panic must not unwind into the detoured function's caller unless its
+  /// ABI permits it, since it may consist of foreign frames. By default the
+  /// panic propagates if the ABI permits unwinding (e.g `Rust` or `C-unwind`),
+  /// otherwise the process is aborted.
+  ///
+  /// The previous policy is released once no thread is executing the detour.
+  pub fn set_panic_policy(&self, policy: PanicPolicy<T>) {
+    let previous = self
+      .panic_policy
+      .swap(Box::into_raw(Box::new(policy)), Ordering::SeqCst);
     if !previous.is_null() {
-      mem::drop(unsafe { Box::from_raw(previous) });
+      unsafe {
+        self
+          .domain
+          .retire(previous as *mut (), epoch::drop_box::<PanicPolicy<T>>)
+      };
     }
   }
 
-  /// Returns a reference to the generated trampoline.
-  pub(crate) fn trampoline(&self) -> Result<&()> {
+  /// Registers the calling thread, keeping the current detour alive until the
+  /// guard is dropped.
+  pub(crate) fn enter(&self) -> Guard<'_> {
+    self.domain.enter()
+  }
+
+  /// Returns a reference to the generated trampoline, which remains valid
+  /// whilst the guard is held.
+  pub(crate) fn trampoline<'a>(&'a self, _guard: &'a Guard<'_>) -> Result<&'a ()> {
     Ok(
       unsafe { self.detour.load(Ordering::SeqCst).as_ref() }
         .ok_or(Error::NotInitialized)?
@@ -173,21 +289,60 @@ impl<T: Function> StaticDetour<T> {
     )
   }
 
-  /// Returns a transient reference to the active detour.
+  /// Releases a replaced closure once no thread is executing it.
+  unsafe fn retire(&self, node: *mut Node<T>) {
+    if !node.is_null() {
+      self.domain.retire(node as *mut (), (*node).drop);
+    }
+  }
+}
+
+impl<T: Invoke> StaticDetour<T> {
+  /// Invokes the active detour, handling any panic according to the policy.
   #[doc(hidden)]
-  pub fn __detour(&self) -> &dyn Fn<T::Arguments, Output = T::Output> {
-    // TODO: This is not 100% thread-safe in case the thread is stopped
-    unsafe { self.closure.load(Ordering::SeqCst).as_ref() }
+  pub fn __invoke(&self, arguments: T::Arguments) -> T::Output {
+    let guard = self.domain.enter();
+    let node = unsafe { self.closure.load(Ordering::SeqCst).as_ref() }
       .ok_or(Error::NotInitialized)
-      .expect("retrieving detour closure")
+      .expect("retrieving detour closure");
+
+    let policy = Policy::resolve(unsafe { self.panic_policy.load(Ordering::SeqCst).as_ref() });
+    let backup = match policy {
+      Policy::Propagate if T::UNWINDS => return unsafe { (node.call)(node, arguments) },
+      Policy::CallOriginal(clone) => Some(clone(&arguments)),
+      _ => None,
+    };
+
+    match panic::catch_unwind(AssertUnwindSafe(|| unsafe { (node.call)(node, arguments) })) {
+      Ok(output) => output,
+      Err(_) => match (policy, backup) {
+        (Policy::CallOriginal(_), Some(arguments)) => unsafe {
+          let trampoline = self
+            .trampoline(&guard)
+            .expect("retrieving detour trampoline");
+          T::from_ptr(trampoline as *const ()).invoke(arguments)
+        },
+        (Policy::Fallback(fallback), _) => fallback(),
+        _ => {
+          eprintln!("detour: `{}` panicked, aborting", self.name);
+          process::abort();
+        },
+      },
+    }
   }
 }
 
 impl<T: Function> Drop for StaticDetour<T> {
   fn drop(&mut self) {
-    let previous = self.closure.swap(ptr::null_mut(), Ordering::Relaxed);
+    unsafe { self.retire(self.closure.swap(ptr::null_mut(), Ordering::Relaxed)) };
+
+    let previous = self.panic_policy.swap(ptr::null_mut(), Ordering::Relaxed);
     if !previous.is_null() {
-      mem::drop(unsafe { Box::from_raw(previous) });
+      unsafe {
+        self
+          .domain
+          .retire(previous as *mut (), epoch::drop_box::<PanicPolicy<T>>)
+      };
     }
 
     let previous = self.detour.swap(ptr::null_mut(), Ordering::Relaxed);
@@ -196,3 +351,23 @@ impl<T: Function> Drop for StaticDetour<T> {
     }
   }
 }
+
+/// A type-erased detour closure, referenced by a thin pointer.
+///
+/// The header is shared by all closures, allowing a `Node<T, C>` to be
+/// accessed as a `Node<T>`.
+#[repr(C)]
+struct Node<T: Function, C = ()> {
+  call: unsafe fn(*const Node<T>, T::Arguments) -> T::Output,
+  drop: unsafe fn(*mut ()),
+  closure: C,
+}
+
+/// Invokes the closure of a node.
+unsafe fn call_closure<T, C>(node: *const Node<T>, arguments: T::Arguments) -> T::Output
+where
+  T: Function,
+  C: Fn<T::Arguments, Output = T::Output>,
+{
+  (*(node as *const Node<T, C>)).closure.call(arguments)
+}
@@ -0,0 +1,95 @@
+use crate::alloc::ExecutableMemory;
+use crate::arch;
+use crate::error::Result;
+use crate::Function;
+use std::fmt;
+use std::marker::PhantomData;
+
+/// A type-safe trampoline, without any detour.
+///
+/// The trampoline is a relocated copy of the target's prolog, followed by a
+/// jump to the rest of its body, but the target itself is left untouched.
+/// This allows a function to be called as if it were unhooked, e.g before it's
+/// hooked by another framework, which may then patch its prolog.
+///
+/// Due to being generated by a macro, the `Trampoline::call` method is not
+/// exposed in the documentation.
+/// It accepts the same arguments as `T`, and shares its result type:
+///
+/// ```c
+/// /// Calls the relocated copy of the target.
+/// fn call(&self, T::Arguments) -> T::Output
+/// ```
+///
+/// # Example
+///
+/// ```rust
+/// # use detour::Result;
+/// use detour::{GenericDetour, Trampoline};
+///
+/// fn add5(val: i32) -> i32 {
+///   val + 5
+/// }
+///
+/// fn add10(val: i32) -> i32 {
+///   val + 10
+/// }
+///
+/// # fn main() -> Result<()> {
+/// let trampoline = unsafe { Trampoline::<fn(i32) -> i32>::create(add5)? };
+/// assert_eq!(trampoline.call(5), 10);
+///
+/// // The trampoline bypasses any hooks installed afterwards
+/// let hook = unsafe { GenericDetour::<fn(i32) -> i32>::new(add5, add10)? };
+/// unsafe { hook.enable()? };
+///
+/// assert_eq!(add5(5), 15);
+/// assert_eq!(trampoline.call(5), 10);
+/// # Ok(())
+/// # }
+/// ```
+pub struct Trampoline<T: Function> {
+  phantom: PhantomData<T>,
+  code: ExecutableMemory,
+}
+
+impl<T: Function> Trampoline<T> {
+  /// Creates a trampoline for a target function, without patching it.
+  ///
+  /// The target's prolog is copied once, so any subsequent modifications of it
+  /// (e.g hooks) are bypassed by the trampoline.
+  pub unsafe fn create(target: T) -> Result<Self> {
+    arch::create_trampoline(target.to_ptr()).map(|code| Trampoline {
+      phantom: PhantomData,
+      code,
+    })
+  }
+
+  /// Returns the trampoline as a function.
+  ///
+  /// This is required for C-variadic functions, which cannot be forwarded
+  /// using `call`. It is unsafe since the function is not bound to the
+  /// trampoline's lifetime, and must not be called once it has been dropped.
+  pub unsafe fn original(&self) -> T {
+    T::from_ptr(self.code.as_ptr() as *const ())
+  }
+
+  /// Returns a reference to the trampoline's code.
+  pub(crate) fn trampoline(&self) -> &() {
+    unsafe {
+      (self.code.as_ptr() as *const ())
+        .as_ref()
+        .expect("trampoline should not be null")
+    }
+  }
+}
+
+impl<T: Function> fmt::Debug for Trampoline<T> {
+  /// Output the address of the trampoline.
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "Trampoline {{ code: {:?} }}", self.code.as_ptr())
+  }
+}
+
+unsafe impl<T: Function> Send for Trampoline<T> {}
+unsafe impl<T: Function> Sync for Trampoline<T> {}
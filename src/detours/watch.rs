@@ -0,0 +1,83 @@
+use crate::arch::{Access, Watch};
+use crate::error::Result;
+
+/// A detour of memory accesses.
+///
+/// Instead of code, a range of data is watched. The pages containing it are
+/// made inaccessible, and a process-wide `SIGSEGV` handler invokes the
+/// callback for each read or write within the range. The faulting instruction
+/// is then single-stepped (using the trap flag) with the pages' original
+/// protection temporarily restored.
+///
+/// The callback is invoked from within a signal handler, so it must be
+/// async-signal-safe (e.g no allocations or locks). Neither the stack, nor the
+/// callback's state, may be located within the watched pages. Accesses to the
+/// watched pages by other threads whilst an instruction is single-stepped may
+/// not be reported.
+///
+/// This is only available on Linux (`x86` & `x86_64`).
+///
+/// # Example
+///
+/// ```rust
+/// # use detour::Result;
+/// use detour::{AccessKind, WatchDetour};
+/// use std::ptr;
+/// use std::sync::atomic::{AtomicUsize, Ordering};
+///
+/// #[repr(align(4096))]
+/// struct Page([u32; 1024]);
+///
+/// static mut PAGE: Page = Page([0; 1024]);
+/// static WRITES: AtomicUsize = AtomicUsize::new(0);
+///
+/// # fn main() -> Result<()> {
+/// let value = unsafe { ptr::addr_of_mut!(PAGE.0[1]) };
+/// let hook = unsafe {
+///   WatchDetour::new(value as *const (), 4, |access| {
+///     if access.kind() == AccessKind::Write {
+///       WRITES.fetch_add(1, Ordering::SeqCst);
+///     }
+///   })?
+/// };
+///
+/// unsafe { hook.enable()? };
+/// unsafe { ptr::write_volatile(value, 5) };
+/// assert_eq!(WRITES.load(Ordering::SeqCst), 1);
+///
+/// unsafe { hook.disable()? };
+/// unsafe { ptr::write_volatile(value, 10) };
+/// assert_eq!(WRITES.load(Ordering::SeqCst), 1);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct WatchDetour(Watch);
+
+impl WatchDetour {
+  /// Constructs a new watch of `size` bytes at `address`.
+  ///
+  /// The address must not be executable, and its pages must not be watched
+  /// already. The hook is disabled by default.
+  pub unsafe fn new<C>(address: *const (), size: usize, callback: C) -> Result<Self>
+  where
+    C: Fn(&Access) + Send + Sync + 'static,
+  {
+    Watch::new(address, size, callback).map(WatchDetour)
+  }
+
+  /// Enables the detour.
+  pub unsafe fn enable(&self) -> Result<()> {
+    self.0.enable()
+  }
+
+  /// Disables the detour.
+  pub unsafe fn disable(&self) -> Result<()> {
+    self.0.disable()
+  }
+
+  /// Returns whether the detour is enabled or not.
+  pub fn is_enabled(&self) -> bool {
+    self.0.is_enabled()
+  }
+}
@@ -25,6 +25,10 @@ pub enum Error {
   OutOfMemory,
   /// The address contains an instruction that prevents detouring.
   UnsupportedInstruction,
+  /// The bounds of the function could not be determined.
+  UnknownBounds,
+  /// The address cannot be represented on the host.
+  UnsupportedAddress,
   /// A memory operation failed.
   RegionFailure(region::Error),
 }
@@ -50,6 +54,8 @@ impl fmt::Display for Error {
       Error::AlreadyInitialized => write!(f, "Detour is already initialized"),
       Error::OutOfMemory => write!(f, "Cannot allocate memory"),
       Error::UnsupportedInstruction => write!(f, "Address contains an unsupported instruction"),
+      Error::UnknownBounds => write!(f, "Cannot determine the function's bounds"),
+      Error::UnsupportedAddress => write!(f, "Address cannot be represented on the host"),
       Error::RegionFailure(ref error) => write!(f, "{}", error),
     }
   }
@@ -1,11 +1,17 @@
 #![recursion_limit = "1024"]
 #![cfg_attr(
   feature = "nightly",
-  feature(const_fn_trait_bound, unboxed_closures, abi_thiscall)
+  feature(
+    const_fn_trait_bound,
+    unboxed_closures,
+    fn_traits,
+    abi_thiscall,
+    c_unwind
+  )
 )]
 #![cfg_attr(
   all(feature = "nightly", test),
-  feature(naked_functions, core_intrinsics, asm)
+  feature(naked_functions, core_intrinsics, asm, test)
 )]
 #![cfg_attr(test, allow(named_asm_labels))]
 
@@ -25,15 +31,32 @@
 //! - RIP relative operands.
 //! - Detects NOP-padding.
 //! - Relay for large offsets (>2GB).
+//! - Absolute jumps if no memory is available within 2GB (x64) or 4GB
+//!   (aarch64).
+//! - Single instruction, atomic patches within 128MB (aarch64).
 //! - Supports hot patching.
+//! - Patches concurrently executed targets via a breakpoint (Linux, x86/x64).
+//!
+//! On Linux (x86/x64), patching a target (e.g enabling a detour) installs a
+//! process-wide `SIGTRAP` handler, used to redirect threads executing the
+//! target while it is being written. Traps not caused by the library are
+//! forwarded to any previously installed handler. The redirection only covers
+//! threads entering the target; threads suspended within a multi-instruction
+//! prolog may still observe a partially written jump.
 //!
 //! ## Detours
 //!
-//! Three different types of detours are provided:
+//! Eight different types of detours are provided:
 //!
 //! - [Static](./struct.StaticDetour.html): A static & type-safe interface.
 //!   Thanks to its static nature it can accept a closure as its detour, but is
-//!   required to be statically defined at compile time.
+//!   required to be statically defined at compile time. Panics within the
+//!   closure are contained according to its
+//!   [PanicPolicy](./struct.PanicPolicy.html).
+//!
+//! - [Probe](./struct.ProbeDetour.html): A static & type-safe interface for
+//!   observing a function's arguments and return value. The original function
+//!   is always called, so the detour cannot alter its behavior.
 //!
 //! - [Generic](./struct.GenericDetour.html): A type-safe interface — the same
 //!   prototype is enforced for both the target and the detour. It is also
@@ -44,11 +67,36 @@
 //!   pointers. It should be avoided unless any types are references, or not
 //!   known until runtime.
 //!
+//! - [CallSite](./struct.CallSiteDetour.html): Redirects a single call
+//!   instruction instead of the callee, so other callers are unaffected.
+//!
+//! - [Context](./struct.ContextDetour.html): A register-level interface for
+//!   functions with an unknown prototype. The callback receives the thread's
+//!   register context, and may modify the arguments, skip the original
+//!   function, or call it directly (`x86_64` System V & `aarch64` only).
+//!
+//! - [Breakpoint](./struct.BreakpointDetour.html): Replaces the target's first
+//!   instruction with a breakpoint, allowing functions too short for a jump to
+//!   be hooked, at the cost of a signal per invocation (Linux only).
+//!
+//! - [Watch](./struct.WatchDetour.html): Reports reads and writes to a range of
+//!   data, by protecting its pages and single-stepping each access (Linux,
+//!   x86/x64 only).
+//!
+//! A [Trampoline](./struct.Trampoline.html) can also be created on its own,
+//! to call a function as if it were unhooked, without patching it.
+//! Similarly, a [FunctionClone](./struct.FunctionClone.html) relocates a
+//! function's entire body, so the copy is unaffected by any later
+//! modifications of the original.
+//!
+//! Trampolines can also be generated offline, for any architecture, using
+//! [relocate](./fn.relocate.html).
+//!
 //! ## Features
 //!
-//! - **nightly**: Enabled by default. Required for static detours, due to usage
-//!   of *const_fn* & *unboxed_closures*.   The feature also enables a more
-//!   extensive test suite.
+//! - **nightly**: Enabled by default. Required for static detours and probes,
+//!   due to usage of *const_fn* & *unboxed_closures*.   The feature also
+//!   enables a more extensive test suite.
 //!
 //! ## Platforms
 //!
@@ -95,6 +143,13 @@
 //! function can be called regardless whether the function is hooked or not.
 
 // Re-exports
+pub use alloc::{CodeCaveAllocator, ExecutableAllocator, ExecutableBlock, ProximityAllocator};
+pub use arch::{relocate, Architecture};
+pub use arch::{set_allocator, set_code_caves, set_pool_granularity};
+#[cfg(all(target_os = "linux", any(target_arch = "x86", target_arch = "x86_64")))]
+pub use arch::{Access, AccessKind};
+#[cfg(any(all(target_arch = "x86_64", unix), target_arch = "aarch64"))]
+pub use arch::{ContextPanicPolicy, CpuContext};
 pub use detours::*;
 pub use error::{Error, Result};
 pub use traits::{Function, HookableWith};
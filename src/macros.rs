@@ -30,86 +30,101 @@
 /// }
 /// # fn main() { }
 /// ```
+///
+/// C-variadic functions are not supported, since a closure cannot forward
+/// their variadic arguments. These may be detoured using a
+/// [GenericDetour](./struct.GenericDetour.html) instead.
+///
+/// ```compile_fail
+/// # use detour::static_detour;
+/// static_detour! {
+///   static Printf: unsafe extern "C" fn(*const u8, ...) -> i32;
+/// }
+/// # fn main() { }
+/// ```
 #[cfg(feature = "nightly")]
 #[macro_export]
 // Inspired by: https://github.com/Jascha-N/minhook-rs
 macro_rules! static_detour {
   // 1 — meta attributes
-  (@parse_attributes ($($input:tt)*) | #[$attribute:meta] $($rest:tt)*) => {
-    static_detour!(@parse_attributes ($($input)* $attribute) | $($rest)*);
+  (@parse_attributes ($kind:ident) ($($input:tt)*) | #[$attribute:meta] $($rest:tt)*) => {
+    $crate::static_detour!(@parse_attributes ($kind) ($($input)* $attribute) | $($rest)*);
   };
-  (@parse_attributes ($($input:tt)*) | $($rest:tt)+) => {
-    static_detour!(@parse_access_modifier (($($input)*)) | $($rest)*);
+  (@parse_attributes ($kind:ident) ($($input:tt)*) | $($rest:tt)+) => {
+    $crate::static_detour!(@parse_access_modifier ($kind) (($($input)*)) | $($rest)*);
   };
 
   // 2 — pub modifier (path/scope/yes/no)
-  (@parse_access_modifier ($($input:tt)*) | pub(in $vis:path) static $($rest:tt)*) => {
-    static_detour!(@parse_name ($($input)* (pub(in $vis))) | $($rest)*);
+  (@parse_access_modifier ($kind:ident) ($($input:tt)*) | pub(in $vis:path) static $($rest:tt)*) => {
+    $crate::static_detour!(@parse_name ($kind) ($($input)* (pub(in $vis))) | $($rest)*);
   };
-  (@parse_access_modifier ($($input:tt)*) | pub($vis:tt) static $($rest:tt)*) => {
-    static_detour!(@parse_name ($($input)* (pub($vis))) | $($rest)*);
+  (@parse_access_modifier ($kind:ident) ($($input:tt)*) | pub($vis:tt) static $($rest:tt)*) => {
+    $crate::static_detour!(@parse_name ($kind) ($($input)* (pub($vis))) | $($rest)*);
   };
-  (@parse_access_modifier ($($input:tt)*) | pub static $($rest:tt)*) => {
-    static_detour!(@parse_name ($($input)* (pub)) | $($rest)*);
+  (@parse_access_modifier ($kind:ident) ($($input:tt)*) | pub static $($rest:tt)*) => {
+    $crate::static_detour!(@parse_name ($kind) ($($input)* (pub)) | $($rest)*);
   };
-  (@parse_access_modifier ($($input:tt)*) | static $($rest:tt)*) => {
-    static_detour!(@parse_name ($($input)* ()) | $($rest)*);
+  (@parse_access_modifier ($kind:ident) ($($input:tt)*) | static $($rest:tt)*) => {
+    $crate::static_detour!(@parse_name ($kind) ($($input)* ()) | $($rest)*);
   };
 
   // 3 — detour name
-  (@parse_name ($($input:tt)*) | $name:ident : $($rest:tt)*) => {
-    static_detour!(@parse_unsafe ($($input)* ($name)) | $($rest)*);
+  (@parse_name ($kind:ident) ($($input:tt)*) | $name:ident : $($rest:tt)*) => {
+    $crate::static_detour!(@parse_unsafe ($kind) ($($input)* ($name)) | $($rest)*);
   };
 
   // 4 — unsafe modifier (yes/no)
-  (@parse_unsafe ($($input:tt)*) | unsafe $($rest:tt)*) => {
-    static_detour!(@parse_calling_convention ($($input)*) (unsafe) | $($rest)*);
+  (@parse_unsafe ($kind:ident) ($($input:tt)*) | unsafe $($rest:tt)*) => {
+    $crate::static_detour!(@parse_calling_convention ($kind) ($($input)*) (unsafe) | $($rest)*);
   };
-  (@parse_unsafe ($($input:tt)*) | $($rest:tt)*) => {
-    static_detour!(@parse_calling_convention ($($input)*) () | $($rest)*);
+  (@parse_unsafe ($kind:ident) ($($input:tt)*) | $($rest:tt)*) => {
+    $crate::static_detour!(@parse_calling_convention ($kind) ($($input)*) () | $($rest)*);
   };
 
   // 5 — calling convention (extern "XXX"/extern/-)
-  (@parse_calling_convention
+  (@parse_calling_convention ($kind:ident)
       ($($input:tt)*) ($($modifier:tt)*) | extern $cc:tt fn $($rest:tt)*) => {
-    static_detour!(@parse_prototype ($($input)* ($($modifier)* extern $cc)) | $($rest)*);
+    $crate::static_detour!(
+      @parse_prototype ($kind) ($($input)* ($($modifier)* extern $cc)) | $($rest)*);
   };
-  (@parse_calling_convention
+  (@parse_calling_convention ($kind:ident)
       ($($input:tt)*) ($($modifier:tt)*) | extern fn $($rest:tt)*) => {
-    static_detour!(@parse_prototype ($($input)* ($($modifier)* extern)) | $($rest)*);
+    $crate::static_detour!(@parse_prototype ($kind) ($($input)* ($($modifier)* extern)) | $($rest)*);
   };
-  (@parse_calling_convention ($($input:tt)*) ($($modifier:tt)*) | fn $($rest:tt)*) => {
-    static_detour!(@parse_prototype ($($input)* ($($modifier)*)) | $($rest)*);
+  (@parse_calling_convention ($kind:ident)
+      ($($input:tt)*) ($($modifier:tt)*) | fn $($rest:tt)*) => {
+    $crate::static_detour!(@parse_prototype ($kind) ($($input)* ($($modifier)*)) | $($rest)*);
   };
 
   // 6 — argument and return type (return/void)
-  (@parse_prototype
+  (@parse_prototype ($kind:ident)
       ($($input:tt)*) | ($($argument_type:ty),*) -> $return_type:ty ; $($rest:tt)*) => {
-    static_detour!(
-      @parse_terminator ($($input)* ($($argument_type)*) ($return_type)) | ; $($rest)*);
+    $crate::static_detour!(@parse_terminator ($kind)
+      ($($input)* ($($argument_type)*) ($return_type)) | ; $($rest)*);
   };
-  (@parse_prototype ($($input:tt)*) | ($($argument_type:ty),*) $($rest:tt)*) => {
-    static_detour!(@parse_terminator ($($input)* ($($argument_type)*) (())) | $($rest)*);
+  (@parse_prototype ($kind:ident) ($($input:tt)*) | ($($argument_type:ty),*) $($rest:tt)*) => {
+    $crate::static_detour!(@parse_terminator ($kind)
+      ($($input)* ($($argument_type)*) (())) | $($rest)*);
   };
 
   // 7 — semicolon terminator
-  (@parse_terminator ($($input:tt)*) | ; $($rest:tt)*) => {
-    static_detour!(@parse_entries ($($input)*) | $($rest)*);
+  (@parse_terminator ($kind:ident) ($($input:tt)*) | ; $($rest:tt)*) => {
+    $crate::static_detour!(@parse_entries ($kind) ($($input)*) | $($rest)*);
   };
 
   // 8 - additional detours (multiple/single)
-  (@parse_entries ($($input:tt)*) | $($rest:tt)+) => {
-    static_detour!(@aggregate $($input)*);
-    static_detour!($($rest)*);
+  (@parse_entries ($kind:ident) ($($input:tt)*) | $($rest:tt)+) => {
+    $crate::static_detour!(@aggregate ($kind) $($input)*);
+    $crate::static_detour!(@parse_attributes ($kind) () | $($rest)*);
   };
-  (@parse_entries ($($input:tt)*) | ) => {
-    static_detour!(@aggregate $($input)*);
+  (@parse_entries ($kind:ident) ($($input:tt)*) | ) => {
+    $crate::static_detour!(@aggregate ($kind) $($input)*);
   };
 
   // 9 - aggregate data for the generate function
-  (@aggregate ($($attribute:meta)*) ($($visibility:tt)*) ($name:ident)
+  (@aggregate ($kind:ident) ($($attribute:meta)*) ($($visibility:tt)*) ($name:ident)
               ($($modifier:tt)*) ($($argument_type:ty)*) ($return_type:ty)) => {
-    static_detour!(@argument_names (create_detour)(
+    $crate::static_detour!(@argument_names ($kind)(
       ($($attribute)*) ($($visibility)*) ($name)
       ($($modifier)*) ($($argument_type)*) ($return_type)
       ($($modifier)* fn ($($argument_type),*) -> $return_type)
@@ -120,7 +135,7 @@ macro_rules! static_detour {
   (@create_detour ($($argument_name:ident)*) ($($attribute:meta)*) ($($visibility:tt)*)
                   ($name:ident) ($($modifier:tt)*) ($($argument_type:ty)*)
                   ($return_type:ty) ($fn_type:ty)) => {
-    static_detour!(@generate
+    $crate::static_detour!(@generate
       #[allow(non_upper_case_globals)]
       $(#[$attribute])*
       $($visibility)* static $name: $crate::StaticDetour<$fn_type> = {
@@ -128,20 +143,40 @@ macro_rules! static_detour {
         #[allow(unused_unsafe)]
         $($modifier) * fn __ffi_detour(
             $($argument_name: $argument_type),*) -> $return_type {
-          #[allow(unused_unsafe)]
-          ($name.__detour())($($argument_name),*)
+          $name.__invoke(($($argument_name,)*))
+        }
+
+        $crate::StaticDetour::__new(__ffi_detour, stringify!($name))
+      };
+    );
+  };
+
+  // 10 - probe type implementation
+  (@create_probe ($($argument_name:ident)*) ($($attribute:meta)*) ($($visibility:tt)*)
+                 ($name:ident) ($($modifier:tt)*) ($($argument_type:ty)*)
+                 ($return_type:ty) ($fn_type:ty)) => {
+    $crate::static_detour!(@generate
+      #[allow(non_upper_case_globals)]
+      $(#[$attribute])*
+      $($visibility)* static $name: $crate::ProbeDetour<$fn_type> = {
+        #[inline(never)]
+        #[allow(unused_unsafe)]
+        $($modifier) * fn __ffi_probe(
+            $($argument_name: $argument_type),*) -> $return_type {
+          $name.__probe(($($argument_name,)*))
         }
 
-        $crate::StaticDetour::__new(__ffi_detour)
+        $crate::ProbeDetour::__new(__ffi_probe, stringify!($name))
       };
     );
   };
 
   // Associates each argument type with a dummy name.
   (@argument_names ($label:ident) ($($input:tt)*) ($($token:tt)*)) => {
-    static_detour!(@argument_names ($label) ($($input)*)(
+    $crate::static_detour!(@argument_names ($label) ($($input)*)(
       __arg_0  __arg_1  __arg_2  __arg_3  __arg_4  __arg_5  __arg_6
       __arg_7  __arg_8  __arg_9  __arg_10 __arg_11 __arg_12 __arg_13
+      __arg_14 __arg_15 __arg_16 __arg_17 __arg_18 __arg_19
     )($($token)*)());
   };
   (@argument_names
@@ -149,18 +184,42 @@ macro_rules! static_detour {
       ($($input:tt)*)
       ($hd_name:tt $($tl_name:tt)*)
       ($hd:tt $($tl:tt)*) ($($acc:tt)*)) => {
-    static_detour!(
+    $crate::static_detour!(
       @argument_names ($label) ($($input)*) ($($tl_name)*) ($($tl)*) ($($acc)* $hd_name));
   };
   (@argument_names ($label:ident) ($($input:tt)*) ($($name:tt)*) () ($($acc:tt)*)) => {
-    static_detour!(@$label ($($acc)*) $($input)*);
+    $crate::static_detour!(@$label ($($acc)*) $($input)*);
   };
 
   (@generate $item:item) => { $item };
 
   // Bootstrapper
   ($($t:tt)+) => {
-    static_detour!(@parse_attributes () | $($t)+);
+    $crate::static_detour!(@parse_attributes (create_detour) () | $($t)+);
+  };
+}
+
+/// A macro for defining static, observation-only probes.
+///
+/// This macro defines one or more [ProbeDetour](./struct.ProbeDetour.html)s,
+/// using the same syntax as [static_detour](./macro.static_detour.html).
+///
+/// # Example
+///
+/// ```rust
+/// # use detour::static_probe;
+/// static_probe! {
+///   static Foo: fn(i32) -> i32;
+///
+///   pub static PubFoo: unsafe extern "C" fn(*const u8) -> usize;
+/// }
+/// # fn main() { }
+/// ```
+#[cfg(feature = "nightly")]
+#[macro_export]
+macro_rules! static_probe {
+  ($($t:tt)+) => {
+    $crate::static_detour!(@parse_attributes (create_probe) () | $($t)+);
   };
 }
 
@@ -176,25 +235,45 @@ macro_rules! impl_hookable {
   };
 
   (@impl_all ($($nm:ident : $ty:ident),*)) => {
-    impl_hookable!(@impl_pair ($($nm : $ty),*) (                  fn($($ty),*) -> Ret));
-    impl_hookable!(@impl_pair ($($nm : $ty),*) (extern "cdecl"    fn($($ty),*) -> Ret));
-    impl_hookable!(@impl_pair ($($nm : $ty),*) (extern "stdcall"  fn($($ty),*) -> Ret));
-    impl_hookable!(@impl_pair ($($nm : $ty),*) (extern "fastcall" fn($($ty),*) -> Ret));
-    impl_hookable!(@impl_pair ($($nm : $ty),*) (extern "win64"    fn($($ty),*) -> Ret));
-    impl_hookable!(@impl_pair ($($nm : $ty),*) (extern "C"        fn($($ty),*) -> Ret));
-    impl_hookable!(@impl_pair ($($nm : $ty),*) (extern "system"   fn($($ty),*) -> Ret));
+    impl_hookable!(@impl_pair ($($nm : $ty),*) (                  fn($($ty),*) -> Ret) (true));
+    impl_hookable!(@impl_pair ($($nm : $ty),*) (extern "cdecl"    fn($($ty),*) -> Ret) (false));
+    impl_hookable!(@impl_pair ($($nm : $ty),*) (extern "stdcall"  fn($($ty),*) -> Ret) (false));
+    impl_hookable!(@impl_pair ($($nm : $ty),*) (extern "fastcall" fn($($ty),*) -> Ret) (false));
+    impl_hookable!(@impl_pair ($($nm : $ty),*) (extern "win64"    fn($($ty),*) -> Ret) (false));
+    impl_hookable!(@impl_pair ($($nm : $ty),*) (extern "C"        fn($($ty),*) -> Ret) (false));
+    impl_hookable!(@impl_pair ($($nm : $ty),*) (extern "system"   fn($($ty),*) -> Ret) (false));
+
+    #[cfg(target_arch = "x86_64")]
+    impl_hookable!(@impl_pair ($($nm : $ty),*) (extern "sysv64"   fn($($ty),*) -> Ret) (false));
+
+    #[cfg(feature = "nightly")]
+    impl_hookable!(@impl_pair ($($nm : $ty),*) (extern "thiscall" fn($($ty),*) -> Ret) (false));
+    #[cfg(feature = "nightly")]
+    impl_hookable!(@impl_pair ($($nm : $ty),*) (extern "C-unwind" fn($($ty),*) -> Ret) (true));
+    #[cfg(feature = "nightly")]
+    impl_hookable!(@impl_pair ($($nm : $ty),*) (extern "system-unwind" fn($($ty),*) -> Ret) (true));
+
+    impl_hookable!(@impl_variadic ($($nm : $ty),*));
+  };
+
+  // C-variadic functions require at least one fixed argument, and can only
+  // be defined as unsafe functions. They cannot be invoked with a tuple of
+  // arguments, so they are forwarded using the original function instead.
+  (@impl_variadic ()) => {};
+  (@impl_variadic ($($nm:ident : $ty:ident),+)) => {
+    impl_hookable!(@impl_function ($($nm : $ty),+) (unsafe extern "C" fn($($ty),+, ...) -> Ret) (false));
 
     #[cfg(feature = "nightly")]
-    impl_hookable!(@impl_pair ($($nm : $ty),*) (extern "thiscall" fn($($ty),*) -> Ret));
+    impl_hookable!(@impl_function ($($nm : $ty),+) (unsafe extern "C-unwind" fn($($ty),+, ...) -> Ret) (true));
   };
 
-  (@impl_pair ($($nm:ident : $ty:ident),*) ($($fn_t:tt)*)) => {
-    impl_hookable!(@impl_fun ($($nm : $ty),*) ($($fn_t)*) (unsafe $($fn_t)*));
+  (@impl_pair ($($nm:ident : $ty:ident),*) ($($fn_t:tt)*) ($unwinds:tt)) => {
+    impl_hookable!(@impl_fun ($($nm : $ty),*) ($($fn_t)*) (unsafe $($fn_t)*) ($unwinds));
   };
 
-  (@impl_fun ($($nm:ident : $ty:ident),*) ($safe_type:ty) ($unsafe_type:ty)) => {
-    impl_hookable!(@impl_core ($($nm : $ty),*) ($safe_type));
-    impl_hookable!(@impl_core ($($nm : $ty),*) ($unsafe_type));
+  (@impl_fun ($($nm:ident : $ty:ident),*) ($safe_type:ty) ($unsafe_type:ty) ($unwinds:tt)) => {
+    impl_hookable!(@impl_core ($($nm : $ty),*) ($safe_type) ($unwinds));
+    impl_hookable!(@impl_core ($($nm : $ty),*) ($unsafe_type) ($unwinds));
 
     impl_hookable!(@impl_unsafe ($($nm : $ty),*) ($unsafe_type) ($safe_type));
     impl_hookable!(@impl_safe ($($nm : $ty),*) ($safe_type));
@@ -207,7 +286,17 @@ macro_rules! impl_hookable {
     impl<Ret: 'static, $($ty: 'static),*> $crate::StaticDetour<$target> {
       #[doc(hidden)]
       pub unsafe fn call(&self, $($nm : $ty),*) -> Ret {
-        let original: $target = ::std::mem::transmute(self.trampoline().expect("calling detour trampoline"));
+        let guard = self.enter();
+        let original: $target = ::std::mem::transmute(self.trampoline(&guard).expect("calling detour trampoline"));
+        original($($nm),*)
+      }
+    }
+
+    #[cfg(feature = "nightly")]
+    impl<Ret: 'static, $($ty: 'static),*> $crate::ProbeDetour<$target> {
+      #[doc(hidden)]
+      pub unsafe fn call(&self, $($nm : $ty),*) -> Ret {
+        let original: $target = ::std::mem::transmute(self.trampoline().expect("calling probe trampoline"));
         original($($nm),*)
       }
     }
@@ -219,6 +308,22 @@ macro_rules! impl_hookable {
         original($($nm),*)
       }
     }
+
+    impl<Ret: 'static, $($ty: 'static),*> $crate::Trampoline<$target> {
+      #[doc(hidden)]
+      pub unsafe fn call(&self, $($nm : $ty),*) -> Ret {
+        let original: $target = ::std::mem::transmute(self.trampoline());
+        original($($nm),*)
+      }
+    }
+
+    impl<Ret: 'static, $($ty: 'static),*> $crate::FunctionClone<$target> {
+      #[doc(hidden)]
+      pub unsafe fn call(&self, $($nm : $ty),*) -> Ret {
+        let original: $target = ::std::mem::transmute(self.trampoline());
+        original($($nm),*)
+      }
+    }
   };
 
   (@impl_safe ($($nm:ident : $ty:ident),*) ($fn_type:ty)) => {
@@ -227,7 +332,19 @@ macro_rules! impl_hookable {
       #[doc(hidden)]
       pub fn call(&self, $($nm : $ty),*) -> Ret {
         unsafe {
-          let original: $fn_type = ::std::mem::transmute(self.trampoline().expect("calling detour trampoline"));
+          let guard = self.enter();
+          let original: $fn_type = ::std::mem::transmute(self.trampoline(&guard).expect("calling detour trampoline"));
+          original($($nm),*)
+        }
+      }
+    }
+
+    #[cfg(feature = "nightly")]
+    impl<Ret: 'static, $($ty: 'static),*> $crate::ProbeDetour<$fn_type> {
+      #[doc(hidden)]
+      pub fn call(&self, $($nm : $ty),*) -> Ret {
+        unsafe {
+          let original: $fn_type = ::std::mem::transmute(self.trampoline().expect("calling probe trampoline"));
           original($($nm),*)
         }
       }
@@ -242,13 +359,45 @@ macro_rules! impl_hookable {
         }
       }
     }
+
+    impl<Ret: 'static, $($ty: 'static),*> $crate::Trampoline<$fn_type> {
+      #[doc(hidden)]
+      pub fn call(&self, $($nm : $ty),*) -> Ret {
+        unsafe {
+          let original: $fn_type = ::std::mem::transmute(self.trampoline());
+          original($($nm),*)
+        }
+      }
+    }
+
+    impl<Ret: 'static, $($ty: 'static),*> $crate::FunctionClone<$fn_type> {
+      #[doc(hidden)]
+      pub fn call(&self, $($nm : $ty),*) -> Ret {
+        unsafe {
+          let original: $fn_type = ::std::mem::transmute(self.trampoline());
+          original($($nm),*)
+        }
+      }
+    }
   };
 
-  (@impl_core ($($nm:ident : $ty:ident),*) ($fn_type:ty)) => {
+  (@impl_core ($($nm:ident : $ty:ident),*) ($fn_type:ty) ($unwinds:tt)) => {
+    impl_hookable!(@impl_function ($($nm : $ty),*) ($fn_type) ($unwinds));
+
+    impl<Ret: 'static, $($ty: 'static),*> sealed::Invoke for $fn_type {
+      unsafe fn invoke(&self, ($($nm,)*): Self::Arguments) -> Self::Output {
+        (*self)($($nm),*)
+      }
+    }
+  };
+
+  (@impl_function ($($nm:ident : $ty:ident),*) ($fn_type:ty) ($unwinds:tt)) => {
     unsafe impl<Ret: 'static, $($ty: 'static),*> Function for $fn_type {
       type Arguments = ($($ty,)*);
       type Output = Ret;
 
+      const UNWINDS: bool = $unwinds;
+
       unsafe fn from_ptr(ptr: *const ()) -> Self {
         ::std::mem::transmute(ptr)
       }
@@ -7,11 +7,17 @@
 /// detouring.
 pub unsafe trait Function: Sized + Copy + Sync + 'static {
   /// The argument types as a tuple.
+  ///
+  /// For C-variadic functions this only includes the fixed arguments.
   type Arguments;
 
   /// The return type.
   type Output;
 
+  /// Whether a panic may unwind out of the function, i.e whether its ABI is
+  /// `Rust` or one of the `-unwind` ABIs.
+  const UNWINDS: bool = false;
+
   /// Constructs a `Function` from an untyped pointer.
   unsafe fn from_ptr(ptr: *const ()) -> Self;
 
@@ -19,6 +25,17 @@ pub unsafe trait Function: Sized + Copy + Sync + 'static {
   fn to_ptr(&self) -> *const ();
 }
 
+pub(crate) mod sealed {
+  /// Trait for functions which can be invoked with a tuple of their
+  /// arguments, i.e those implemented by this library.
+  ///
+  /// It's sealed, so `Function` can still be implemented elsewhere.
+  pub trait Invoke: super::Function {
+    /// Invokes the function with a tuple of arguments.
+    unsafe fn invoke(&self, args: Self::Arguments) -> Self::Output;
+  }
+}
+
 /// Trait indicating that `Self` can be detoured by the given function `D`.
 pub unsafe trait HookableWith<D: Function>: Function {}
 
@@ -26,5 +43,6 @@ unsafe impl<T: Function> HookableWith<T> for T {}
 
 impl_hookable! {
   __arg_0:  A, __arg_1:  B, __arg_2:  C, __arg_3:  D, __arg_4:  E, __arg_5:  F, __arg_6:  G,
-  __arg_7:  H, __arg_8:  I, __arg_9:  J, __arg_10: K, __arg_11: L, __arg_12: M, __arg_13: N
+  __arg_7:  H, __arg_8:  I, __arg_9:  J, __arg_10: K, __arg_11: L, __arg_12: M, __arg_13: N,
+  __arg_14: O, __arg_15: P, __arg_16: Q, __arg_17: R, __arg_18: S, __arg_19: T
 }
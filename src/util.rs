@@ -1,4 +1,65 @@
 use crate::error::Result;
+use lazy_static::lazy_static;
+use std::ops::Range;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::{cmp, io, mem, ptr};
+
+lazy_static! {
+  /// Locks for modifying code, indexed by page.
+  static ref PAGE_LOCKS: StripedLock = StripedLock::new(64);
+}
+
+/// A fixed set of locks, which keys (e.g addresses) are distributed across.
+///
+/// Unrelated keys may share a lock, so at most one key should be locked at a
+/// time, unless they're locked together using `lock_range`.
+pub struct StripedLock(Vec<Mutex<()>>);
+
+impl StripedLock {
+  /// Constructs a set of `count` locks.
+  pub fn new(count: usize) -> Self {
+    StripedLock((0..count).map(|_| Mutex::new(())).collect())
+  }
+
+  /// Acquires the lock associated with a key.
+  pub fn lock(&self, key: usize) -> MutexGuard<'_, ()> {
+    lock(&self.0[key % self.0.len()])
+  }
+
+  /// Acquires the locks associated with a range of keys.
+  ///
+  /// The locks are always acquired in the same order, to prevent deadlocks.
+  pub fn lock_range(&self, keys: Range<usize>) -> Vec<MutexGuard<'_, ()>> {
+    let mut indices = keys
+      .take(self.0.len())
+      .map(|key| key % self.0.len())
+      .collect::<Vec<_>>();
+    indices.sort_unstable();
+    indices
+      .into_iter()
+      .map(|index| lock(&self.0[index]))
+      .collect()
+  }
+}
+
+/// Acquires a mutex, even if a thread panicked whilst holding it.
+///
+/// A panic whilst a lock is held (e.g within a custom allocator) does not leave
+/// the guarded state inconsistent, so the poison is ignored.
+pub fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+  mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Acquires shared access to a lock, even if it's poisoned.
+pub fn read_lock<T>(lock: &RwLock<T>) -> RwLockReadGuard<'_, T> {
+  lock.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Acquires exclusive access to a lock, even if it's poisoned.
+pub fn write_lock<T>(lock: &RwLock<T>) -> RwLockWriteGuard<'_, T> {
+  lock.write().unwrap_or_else(PoisonError::into_inner)
+}
 
 /// Returns true if an address is executable.
 pub fn is_executable_address(address: *const ()) -> Result<bool> {
@@ -8,3 +69,102 @@ pub fn is_executable_address(address: *const ()) -> Result<bool> {
       .contains(region::Protection::EXECUTE),
   )
 }
+
+/// Writes code to an address, which may be executed concurrently.
+///
+/// The affected pages are made writable in addition to their current
+/// protection, which is restored afterwards. If the pages cannot be both
+/// writable and executable (e.g due to SELinux or PaX), the code is instead
+/// written through an alias of the process' memory.
+pub unsafe fn write_code(address: *const u8, code: &[u8]) -> Result<()> {
+  if code.is_empty() {
+    return Ok(());
+  }
+
+  let page_size = region::page::size();
+  let start = address as usize & !(page_size - 1);
+  let end = address as usize + code.len();
+
+  // Concurrent writes must not restore each other's pages prematurely
+  let _guards = PAGE_LOCKS.lock_range(start / page_size..(end - 1) / page_size + 1);
+
+  let regions = region::query_range(address, code.len())?.collect::<region::Result<Vec<_>>>()?;
+  let mut modified = Vec::with_capacity(regions.len());
+  let mut result = Ok(());
+
+  for region in &regions {
+    let range = region.as_range();
+    let base = cmp::max(range.start, start);
+    let size = cmp::min(range.end, end) - base;
+
+    match region::protect(
+      base as *const u8,
+      size,
+      region.protection() | region::Protection::WRITE,
+    ) {
+      Ok(()) => modified.push((base, size, region.protection())),
+      Err(error) => {
+        result = Err(error);
+        break;
+      },
+    }
+  }
+
+  if result.is_ok() {
+    copy_code(address as *mut u8, code);
+  }
+
+  // Restore exactly the protection that was queried; every region is
+  // restored, even if another one fails, and the first error is reported
+  let mut restored = Ok(());
+  for (base, size, protection) in modified {
+    let status = region::protect(base as *const u8, size, protection);
+    if restored.is_ok() {
+      restored = status;
+    }
+  }
+  restored?;
+
+  match result {
+    Ok(()) => Ok(()),
+    Err(error) => write_code_alias(address, code).map_err(|_| error.into()),
+  }
+}
+
+/// Copies code, using an atomic store for aligned, pointer sized values (e.g
+/// slots) and 32-bit values (e.g aarch64 instructions).
+pub unsafe fn copy_code(address: *mut u8, code: &[u8]) {
+  let is_aligned = address as usize % code.len().max(1) == 0;
+
+  if code.len() == mem::size_of::<usize>() && is_aligned {
+    let mut value = [0; mem::size_of::<usize>()];
+    value.copy_from_slice(code);
+    (*(address as *const AtomicUsize)).store(usize::from_ne_bytes(value), Ordering::SeqCst);
+  } else if code.len() == mem::size_of::<u32>() && is_aligned {
+    let mut value = [0; mem::size_of::<u32>()];
+    value.copy_from_slice(code);
+    (*(address as *const AtomicU32)).store(u32::from_ne_bytes(value), Ordering::SeqCst);
+  } else {
+    ptr::copy_nonoverlapping(code.as_ptr(), address, code.len());
+  }
+}
+
+/// Writes code via the process' memory file, which ignores page protection.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+unsafe fn write_code_alias(address: *const u8, code: &[u8]) -> io::Result<()> {
+  use std::os::unix::fs::FileExt;
+
+  let file = std::fs::OpenOptions::new()
+    .write(true)
+    .open("/proc/self/mem")?;
+  file.write_all_at(code, address as u64)
+}
+
+/// Writes code via an alias of the process' memory (unsupported).
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+unsafe fn write_code_alias(_address: *const u8, _code: &[u8]) -> io::Result<()> {
+  Err(io::Error::new(
+    io::ErrorKind::Other,
+    "no memory alias available",
+  ))
+}
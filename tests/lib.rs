@@ -1,3 +1,4 @@
+#![cfg_attr(feature = "nightly", feature(c_variadic))]
 use detour::Result;
 use std::mem;
 
@@ -47,6 +48,181 @@ mod raw {
     }
     Ok(())
   }
+
+  #[test]
+  fn set_detour() -> Result<()> {
+    #[inline(never)]
+    extern "C" fn max(x: i32, y: i32) -> i32 {
+      unsafe { std::ptr::read_volatile(&x as *const i32).max(y) }
+    }
+
+    #[inline(never)]
+    extern "C" fn min_detour(x: i32, y: i32) -> i32 {
+      unsafe { std::ptr::read_volatile(&x as *const i32).min(y) }
+    }
+
+    unsafe {
+      let hook = RawDetour::new(max as *const (), sub_detour as *const ())?;
+      hook.enable()?;
+      assert_eq!(max(10, 4), 6);
+
+      // The destination can be changed whilst enabled
+      hook.set_detour(min_detour as *const ())?;
+      assert_eq!(max(10, 4), 4);
+
+      let trampoline: FnAdd = mem::transmute(hook.trampoline());
+      assert_eq!(trampoline(10, 4), 10);
+
+      hook.disable()?;
+      assert_eq!(max(10, 4), 10);
+
+      // ... or whilst disabled
+      hook.set_detour(sub_detour as *const ())?;
+      hook.enable()?;
+      assert_eq!(max(10, 4), 6);
+    }
+    Ok(())
+  }
+
+  #[test]
+  fn fast_toggle() -> Result<()> {
+    #[inline(never)]
+    extern "C" fn avg(x: i32, y: i32) -> i32 {
+      unsafe { (std::ptr::read_volatile(&x as *const i32) + y) / 2 }
+    }
+
+    #[inline(never)]
+    extern "C" fn diff_detour(x: i32, y: i32) -> i32 {
+      unsafe { (std::ptr::read_volatile(&x as *const i32) - y) * 2 }
+    }
+
+    unsafe {
+      let hook = RawDetour::with_fast_toggle(avg as *const (), sub_detour as *const ())?;
+      assert!(!hook.is_enabled());
+      assert_eq!(avg(10, 4), 7);
+
+      for _ in 0..100 {
+        hook.enable()?;
+        assert!(hook.is_enabled());
+        assert_eq!(avg(10, 4), 6);
+
+        hook.disable()?;
+        assert_eq!(avg(10, 4), 7);
+      }
+
+      let trampoline: FnAdd = mem::transmute(hook.trampoline());
+      assert_eq!(trampoline(10, 4), 7);
+
+      hook.set_detour(diff_detour as *const ())?;
+      hook.enable()?;
+      assert_eq!(avg(10, 4), 12);
+
+      // Once dropped, the target is restored
+      mem::drop(hook);
+      assert_eq!(avg(10, 4), 7);
+    }
+    Ok(())
+  }
+
+  #[test]
+  fn custom_allocator() -> Result<()> {
+    use detour::{ExecutableAllocator, ExecutableBlock, ProximityAllocator};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    /// Counts the active blocks of the default allocator.
+    struct CountingAllocator {
+      inner: ProximityAllocator,
+      active: AtomicUsize,
+    }
+
+    unsafe impl ExecutableAllocator for CountingAllocator {
+      fn allocate_near(
+        &self,
+        origin: *const (),
+        size: usize,
+        max_distance: usize,
+      ) -> Result<ExecutableBlock> {
+        let block = self.inner.allocate_near(origin, size, max_distance)?;
+        self.active.fetch_add(1, Ordering::SeqCst);
+        Ok(block)
+      }
+
+      unsafe fn release(&self, block: ExecutableBlock) {
+        self.active.fetch_sub(1, Ordering::SeqCst);
+        self.inner.release(block)
+      }
+    }
+
+    #[inline(never)]
+    extern "C" fn mul(x: i32, y: i32) -> i32 {
+      unsafe { std::ptr::read_volatile(&x as *const i32) * y }
+    }
+
+    let allocator = Arc::new(CountingAllocator {
+      inner: ProximityAllocator::new(0x10000),
+      active: AtomicUsize::new(0),
+    });
+
+    unsafe {
+      let hook =
+        RawDetour::with_allocator(mul as *const (), sub_detour as *const (), allocator.clone())?;
+
+      // Both the trampoline and the relay are allocated
+      assert_eq!(allocator.active.load(Ordering::SeqCst), 2);
+
+      hook.enable()?;
+      assert_eq!(mul(10, 5), 5);
+
+      let trampoline: FnAdd = mem::transmute(hook.trampoline());
+      assert_eq!(trampoline(10, 5), 50);
+
+      mem::drop(hook);
+      assert_eq!(mul(10, 5), 50);
+    }
+
+    assert_eq!(allocator.active.load(Ordering::SeqCst), 0);
+    Ok(())
+  }
+
+  #[test]
+  fn panicking_allocator() -> Result<()> {
+    use detour::{ExecutableAllocator, ExecutableBlock};
+    use std::sync::Arc;
+
+    struct PanickingAllocator;
+
+    unsafe impl ExecutableAllocator for PanickingAllocator {
+      fn allocate_near(&self, _: *const (), _: usize, _: usize) -> Result<ExecutableBlock> {
+        panic!("allocation failure");
+      }
+
+      unsafe fn release(&self, _: ExecutableBlock) {}
+    }
+
+    #[inline(never)]
+    extern "C" fn shr(x: i32, y: i32) -> i32 {
+      unsafe { std::ptr::read_volatile(&x as *const i32) >> y }
+    }
+
+    // The panic occurs whilst the target is locked
+    let result = std::panic::catch_unwind(|| unsafe {
+      RawDetour::with_allocator(
+        shr as *const (),
+        sub_detour as *const (),
+        Arc::new(PanickingAllocator),
+      )
+    });
+    assert!(result.is_err());
+
+    unsafe {
+      let hook = RawDetour::new(shr as *const (), sub_detour as *const ())?;
+      hook.enable()?;
+      assert_eq!(shr(16, 2), 14);
+    }
+    assert_eq!(shr(16, 2), 4);
+    Ok(())
+  }
 }
 
 mod generic {
@@ -77,21 +253,272 @@ mod generic {
     }
     Ok(())
   }
+
+  #[test]
+  fn set_detour() -> Result<()> {
+    #[inline(never)]
+    extern "C" fn div(x: i32, y: i32) -> i32 {
+      unsafe { std::ptr::read_volatile(&x as *const i32) / y }
+    }
+
+    #[inline(never)]
+    extern "C" fn rem_detour(x: i32, y: i32) -> i32 {
+      unsafe { std::ptr::read_volatile(&x as *const i32) % y }
+    }
+
+    unsafe {
+      let hook = GenericDetour::<FnAdd>::new(div, sub_detour)?;
+      hook.enable()?;
+      assert_eq!(div(10, 4), 6);
+
+      hook.set_detour(rem_detour)?;
+      assert_eq!(div(10, 4), 2);
+      assert_eq!(hook.call(10, 4), 2);
+    }
+    Ok(())
+  }
+
+  #[test]
+  fn custom_function() -> Result<()> {
+    use detour::Function;
+
+    /// A function implemented outside of the library.
+    #[derive(Clone, Copy)]
+    struct Add(FnAdd);
+
+    unsafe impl Function for Add {
+      type Arguments = (i32, i32);
+      type Output = i32;
+
+      unsafe fn from_ptr(ptr: *const ()) -> Self {
+        Add(mem::transmute(ptr))
+      }
+
+      fn to_ptr(&self) -> *const () {
+        self.0 as *const ()
+      }
+    }
+
+    #[inline(never)]
+    extern "C" fn add(x: i32, y: i32) -> i32 {
+      unsafe { std::ptr::read_volatile(&x as *const i32) + y }
+    }
+
+    unsafe {
+      let hook = GenericDetour::new(Add(add), Add(sub_detour))?;
+      hook.enable()?;
+      assert_eq!(add(10, 5), 5);
+    }
+    Ok(())
+  }
+}
+
+mod trampoline {
+  use super::*;
+  use detour::{RawDetour, Trampoline};
+
+  #[test]
+  fn bypasses_hooks() -> Result<()> {
+    #[inline(never)]
+    extern "C" fn mul(x: i32, y: i32) -> i32 {
+      unsafe { std::ptr::read_volatile(&x as *const i32) * y }
+    }
+
+    unsafe {
+      let trampoline = Trampoline::<FnAdd>::create(mul)?;
+      assert_eq!(trampoline.call(3, 4), 12);
+
+      // The target is not patched by the trampoline, only by the detour
+      let hook = RawDetour::new(mul as *const (), sub_detour as *const ())?;
+      hook.enable()?;
+      assert_eq!(mul(3, 4), -1);
+      assert_eq!(trampoline.call(3, 4), 12);
+      assert_eq!((trampoline.original())(3, 4), 12);
+
+      hook.disable()?;
+      mem::drop(trampoline);
+      assert_eq!(mul(3, 4), 12);
+    }
+    Ok(())
+  }
+}
+
+#[cfg(target_os = "linux")]
+mod clone {
+  use super::*;
+  use detour::{FunctionClone, RawDetour};
+
+  #[test]
+  fn survives_modification() -> Result<()> {
+    #[inline(never)]
+    extern "C" fn sum(x: i32, y: i32) -> i32 {
+      // The loop branches within the function, which must remain intact
+      (0..y).fold(x, |acc, _| std::hint::black_box(acc + 1))
+    }
+
+    unsafe {
+      let clone = FunctionClone::<FnAdd>::create(sum)?;
+      assert_eq!(clone.call(3, 4), 7);
+
+      // The original is overwritten, without affecting the clone
+      let hook = RawDetour::new(sum as *const (), sub_detour as *const ())?;
+      hook.enable()?;
+      assert_eq!(sum(3, 4), -1);
+      assert_eq!(clone.call(3, 4), 7);
+      assert_eq!((clone.function())(3, 4), 7);
+    }
+    Ok(())
+  }
+}
+
+#[cfg(feature = "nightly")]
+mod variadic {
+  use super::*;
+  use detour::GenericDetour;
+  use std::sync::atomic::{AtomicUsize, Ordering};
+
+  type FnSum = unsafe extern "C" fn(usize, ...) -> usize;
+
+  static ORIGINAL: AtomicUsize = AtomicUsize::new(0);
+
+  #[inline(never)]
+  unsafe extern "C" fn sum(count: usize, mut args: ...) -> usize {
+    (0..count).map(|_| args.arg::<usize>()).sum()
+  }
+
+  unsafe extern "C" fn sum_detour(count: usize, mut args: ...) -> usize {
+    assert_eq!(count, 2);
+    let (x, y) = (args.arg::<usize>(), args.arg::<usize>());
+
+    let original: FnSum = mem::transmute(ORIGINAL.load(Ordering::SeqCst));
+    original(count, x * 10, y * 10)
+  }
+
+  #[test]
+  fn test() -> Result<()> {
+    unsafe {
+      let hook = GenericDetour::<FnSum>::new(sum, sum_detour)
+        .expect("target or source is not usable for detouring");
+      ORIGINAL.store(hook.original() as usize, Ordering::SeqCst);
+
+      assert_eq!(sum(2, 1, 2), 3);
+      hook.enable()?;
+      {
+        assert_eq!(sum(2, 1, 2), 30);
+        assert_eq!(hook.original()(2, 1, 2), 3);
+      }
+      hook.disable()?;
+      assert_eq!(sum(2, 1, 2), 3);
+    }
+    Ok(())
+  }
+
+  #[test]
+  fn arity() -> Result<()> {
+    type FnWide =
+      extern "C-unwind" fn(u8, u8, u8, u8, u8, u8, u8, u8, u8, u8, u8, u8, u8, u8, u8, u8) -> u8;
+
+    #[inline(never)]
+    extern "C-unwind" fn total(
+      a: u8,
+      b: u8,
+      c: u8,
+      d: u8,
+      e: u8,
+      f: u8,
+      g: u8,
+      h: u8,
+      i: u8,
+      j: u8,
+      k: u8,
+      l: u8,
+      m: u8,
+      n: u8,
+      o: u8,
+      p: u8,
+    ) -> u8 {
+      unsafe { std::ptr::read_volatile(&[a, b, c, d, e, f, g, h, i, j, k, l, m, n, o, p]) }
+        .iter()
+        .sum()
+    }
+
+    #[inline(never)]
+    extern "C-unwind" fn last(
+      _: u8,
+      _: u8,
+      _: u8,
+      _: u8,
+      _: u8,
+      _: u8,
+      _: u8,
+      _: u8,
+      _: u8,
+      _: u8,
+      _: u8,
+      _: u8,
+      _: u8,
+      _: u8,
+      _: u8,
+      p: u8,
+    ) -> u8 {
+      unsafe { std::ptr::read_volatile(&p) }
+    }
+
+    unsafe {
+      let hook = GenericDetour::<FnWide>::new(total, last)?;
+      hook.enable()?;
+      assert_eq!(
+        total(1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16),
+        16
+      );
+      assert_eq!(
+        hook.call(1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16),
+        136
+      );
+    }
+    Ok(())
+  }
 }
 
 #[cfg(feature = "nightly")]
 mod statik {
   use super::*;
   use detour::static_detour;
+  use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+  use std::sync::Arc;
+  use std::thread;
 
   #[inline(never)]
   unsafe extern "C" fn add(x: i32, y: i32) -> i32 {
     std::ptr::read_volatile(&x as *const i32) + y
   }
 
+  #[inline(never)]
+  extern "C" fn shl(x: i32, y: i32) -> i32 {
+    unsafe { std::ptr::read_volatile(&x as *const i32) << y }
+  }
+
+  #[inline(never)]
+  extern "C" fn or(x: i32, y: i32) -> i32 {
+    unsafe { std::ptr::read_volatile(&x as *const i32) | y }
+  }
+
+  #[inline(never)]
+  extern "C" fn and(x: i32, y: i32) -> i32 {
+    unsafe { std::ptr::read_volatile(&x as *const i32) & y }
+  }
+
+  #[inline(never)]
+  extern "C" fn sub(x: i32, y: i32) -> i32 {
+    unsafe { std::ptr::read_volatile(&x as *const i32) - y }
+  }
+
   static_detour! {
     #[doc="Test with attributes"]
     pub static DetourAdd: unsafe extern "C" fn(i32, i32) -> i32;
+    static DetourShl: extern "C" fn(i32, i32) -> i32;
+    static DetourBitwise: extern "C" fn(i32, i32) -> i32;
+    static DetourSub: extern "C" fn(i32, i32) -> i32;
   }
 
   #[test]
@@ -116,4 +543,631 @@ mod statik {
     }
     Ok(())
   }
+
+  #[test]
+  fn replace() -> Result<()> {
+    static DROPS: AtomicUsize = AtomicUsize::new(0);
+
+    struct Value(i32);
+
+    impl Drop for Value {
+      fn drop(&mut self) {
+        DROPS.fetch_add(1, Ordering::SeqCst);
+      }
+    }
+
+    unsafe {
+      DetourShl.initialize(shl, |_, _| -1)?;
+      DetourShl.enable()?;
+
+      // Replace the closure whilst other threads are executing it
+      let stop = Arc::new(AtomicBool::new(false));
+      let threads: Vec<_> = (0..4)
+        .map(|_| {
+          let stop = stop.clone();
+          thread::spawn(move || {
+            while !stop.load(Ordering::SeqCst) {
+              let value = shl(1, 0);
+              assert!((-1..1000).contains(&value));
+            }
+          })
+        })
+        .collect();
+
+      for index in 0..1000 {
+        let value = Value(index);
+        DetourShl.set_detour(move |_, _| value.0);
+      }
+
+      stop.store(true, Ordering::SeqCst);
+      for thread in threads {
+        thread.join().unwrap();
+      }
+
+      assert_eq!(shl(1, 0), 999);
+      DetourShl.disable()?;
+    }
+
+    // Every replaced closure has been released
+    assert_eq!(DROPS.load(Ordering::SeqCst), 999);
+    Ok(())
+  }
+
+  #[test]
+  fn reinitialize() -> Result<()> {
+    unsafe {
+      DetourBitwise.initialize(or, |_, _| 1)?.enable()?;
+      assert_eq!(or(6, 3), 1);
+
+      // The target is restored once uninitialized
+      DetourBitwise.uninitialize()?;
+      assert!(!DetourBitwise.is_enabled());
+      assert_eq!(or(6, 3), 7);
+      assert!(DetourBitwise.uninitialize().is_err());
+
+      DetourBitwise.initialize(or, |x, y| x + y)?.enable()?;
+      assert_eq!(or(6, 3), 9);
+
+      // The closure and state are retained when retargeted
+      DetourBitwise.retarget(and)?;
+      assert!(DetourBitwise.is_enabled());
+      assert_eq!(or(6, 3), 7);
+      assert_eq!(and(6, 3), 9);
+      assert_eq!(DetourBitwise.call(6, 3), 2);
+
+      // Retargeting the current target leaves it unchanged
+      DetourBitwise.retarget(and)?;
+      assert_eq!(and(6, 3), 9);
+      assert_eq!(DetourBitwise.call(6, 3), 2);
+
+      // A failed retarget leaves the current target detoured
+      static DATA: u64 = 0;
+      let data: extern "C" fn(i32, i32) -> i32 = mem::transmute(&DATA);
+      assert!(DetourBitwise.retarget(data).is_err());
+      assert_eq!(and(6, 3), 9);
+      assert_eq!(DetourBitwise.call(6, 3), 2);
+
+      DetourBitwise.uninitialize()?;
+      assert_eq!(and(6, 3), 2);
+    }
+    Ok(())
+  }
+
+  #[test]
+  fn reinitialize_concurrently() -> Result<()> {
+    static DONE: AtomicBool = AtomicBool::new(false);
+
+    // The state may be queried whilst the detour is torn down
+    let readers = (0..4)
+      .map(|_| {
+        thread::spawn(|| {
+          while !DONE.load(Ordering::SeqCst) {
+            let _ = DetourSub.is_enabled();
+          }
+        })
+      })
+      .collect::<Vec<_>>();
+
+    unsafe {
+      for _ in 0..100 {
+        DetourSub.initialize(sub, |x, y| x + y)?.enable()?;
+        assert_eq!(sub(6, 3), 9);
+        DetourSub.retarget(and)?;
+        DetourSub.uninitialize()?;
+      }
+    }
+
+    DONE.store(true, Ordering::SeqCst);
+    for reader in readers {
+      reader.join().unwrap();
+    }
+    assert_eq!(sub(6, 3), 3);
+    Ok(())
+  }
+}
+
+#[cfg(feature = "nightly")]
+mod panic {
+  use super::*;
+  use detour::{static_detour, PanicPolicy};
+
+  #[inline(never)]
+  extern "C" fn xor(x: i32, y: i32) -> i32 {
+    unsafe { std::ptr::read_volatile(&x as *const i32) ^ y }
+  }
+
+  #[inline(never)]
+  extern "C" fn mul(x: i32, y: i32) -> i32 {
+    unsafe { std::ptr::read_volatile(&x as *const i32) * y }
+  }
+
+  #[inline(never)]
+  fn neg(x: i32) -> i32 {
+    // The function must be able to unwind, or its callers may assume otherwise
+    let x = unsafe { std::ptr::read_volatile(&x as *const i32) };
+    assert_ne!(x, i32::MIN, "overflow");
+    -x
+  }
+
+  #[inline(never)]
+  fn not(x: i32) -> i32 {
+    let x = unsafe { std::ptr::read_volatile(&x as *const i32) };
+    assert_ne!(x, i32::MIN, "overflow");
+    !x
+  }
+
+  static_detour! {
+    static PanicXor: extern "C" fn(i32, i32) -> i32;
+    static PanicMul: extern "C" fn(i32, i32) -> i32;
+    static PanicNeg: fn(i32) -> i32;
+    static PanicNot: fn(i32) -> i32;
+  }
+
+  #[test]
+  fn call_original() -> Result<()> {
+    unsafe {
+      PanicXor.initialize(xor, |x, y| {
+        assert!(x > 0, "negative value");
+        x + y
+      })?;
+      PanicXor.set_panic_policy(PanicPolicy::call_original());
+      PanicXor.enable()?;
+
+      assert_eq!(xor(12, 5), 17);
+      assert_eq!(xor(-10, 3), -10 ^ 3);
+      PanicXor.disable()?;
+    }
+    Ok(())
+  }
+
+  #[test]
+  fn fallback() -> Result<()> {
+    unsafe {
+      PanicMul.initialize(mul, |_, _| panic!("unexpected call"))?;
+      PanicMul.set_panic_policy(PanicPolicy::fallback(-1));
+      PanicMul.enable()?;
+
+      assert_eq!(mul(10, 5), -1);
+      PanicMul.disable()?;
+    }
+    Ok(())
+  }
+
+  #[test]
+  fn propagate() -> Result<()> {
+    unsafe {
+      PanicNeg.initialize(neg, |_| panic!("unexpected call"))?;
+      PanicNeg.enable()?;
+
+      // Panics unwind out of Rust functions by default
+      assert!(std::panic::catch_unwind(|| neg(1)).is_err());
+      PanicNeg.disable()?;
+    }
+    Ok(())
+  }
+
+  #[test]
+  fn reinitialize() -> Result<()> {
+    unsafe {
+      PanicNot.initialize(not, |_| panic!("unexpected call"))?;
+      PanicNot.set_panic_policy(PanicPolicy::fallback(-1));
+      PanicNot.enable()?;
+      assert_eq!(not(0), -1);
+
+      // The policy is cleared along with the closure
+      PanicNot.uninitialize()?;
+      PanicNot.initialize(not, |_| panic!("unexpected call"))?;
+      PanicNot.enable()?;
+      assert!(std::panic::catch_unwind(|| not(0)).is_err());
+      PanicNot.uninitialize()?;
+    }
+    Ok(())
+  }
+}
+
+#[cfg(feature = "nightly")]
+mod probe {
+  use super::*;
+  use detour::{static_probe, PanicPolicy};
+  use std::sync::atomic::{AtomicI32, Ordering};
+
+  #[inline(never)]
+  unsafe extern "C" fn add(x: i32, y: i32) -> i32 {
+    std::ptr::read_volatile(&x as *const i32) + y
+  }
+
+  #[inline(never)]
+  extern "C" fn increment(value: &'static mut i32) -> i32 {
+    unsafe { std::ptr::write_volatile(value, *value + 1) };
+    *value
+  }
+
+  static_probe! {
+    pub static ProbeAdd: unsafe extern "C" fn(i32, i32) -> i32;
+    static ProbeIncrement: extern "C" fn(&'static mut i32) -> i32;
+  }
+
+  #[test]
+  fn test() -> Result<()> {
+    static ENTERED: AtomicI32 = AtomicI32::new(0);
+    static RETURNED: AtomicI32 = AtomicI32::new(0);
+
+    unsafe {
+      ProbeAdd.initialize(add)?;
+      ProbeAdd.on_enter(|&(x, y)| ENTERED.store(x * y, Ordering::SeqCst));
+      ProbeAdd.on_exit(|_, &output| RETURNED.store(output, Ordering::SeqCst));
+
+      assert_eq!(add(10, 5), 15);
+      assert_eq!(ENTERED.load(Ordering::SeqCst), 0);
+
+      ProbeAdd.enable()?;
+      {
+        assert!(ProbeAdd.is_enabled());
+        assert_eq!(add(10, 5), 15);
+        assert_eq!(ENTERED.load(Ordering::SeqCst), 50);
+        assert_eq!(RETURNED.load(Ordering::SeqCst), 15);
+
+        // Calling the original bypasses the probe
+        assert_eq!(ProbeAdd.call(2, 3), 5);
+        assert_eq!(ENTERED.load(Ordering::SeqCst), 50);
+      }
+      ProbeAdd.disable()?;
+
+      assert_eq!(add(2, 3), 5);
+      assert_eq!(RETURNED.load(Ordering::SeqCst), 15);
+    }
+    Ok(())
+  }
+
+  #[test]
+  fn mutable_arguments() -> Result<()> {
+    static RETURNED: AtomicI32 = AtomicI32::new(0);
+
+    unsafe {
+      ProbeIncrement.initialize(increment)?;
+      ProbeIncrement.on_enter(|(value,)| assert_eq!(**value, 1));
+      ProbeIncrement.on_return(|&output| RETURNED.store(output, Ordering::SeqCst));
+      ProbeIncrement.set_panic_policy(PanicPolicy::fallback(-1));
+      ProbeIncrement.enable()?;
+
+      assert_eq!(increment(Box::leak(Box::new(1))), 2);
+      assert_eq!(RETURNED.load(Ordering::SeqCst), 2);
+
+      // The entry callback panics, so the original function is skipped
+      let value = Box::into_raw(Box::new(5));
+      assert_eq!(increment(&mut *value), -1);
+      assert_eq!(*Box::from_raw(value), 5);
+      ProbeIncrement.disable()?;
+    }
+    Ok(())
+  }
+}
+
+#[cfg(any(all(target_arch = "x86_64", unix), target_arch = "aarch64"))]
+mod exit {
+  use super::*;
+  use detour::RawDetour;
+  use std::sync::atomic::{AtomicUsize, Ordering};
+
+  #[inline(never)]
+  extern "C" fn add(x: i32, y: i32) -> i32 {
+    unsafe { std::ptr::read_volatile(&x as *const i32) + y }
+  }
+
+  #[inline(never)]
+  extern "C" fn recurse(depth: u64) -> u64 {
+    if unsafe { std::ptr::read_volatile(&depth) } == 0 {
+      0
+    } else {
+      // Prevent the recursion from being folded into a loop
+      let recurse: extern "C" fn(u64) -> u64 = unsafe { std::ptr::read_volatile(&(recurse as _)) };
+      recurse(depth - 1) + 1
+    }
+  }
+
+  #[test]
+  fn test() -> Result<()> {
+    static RETURNS: AtomicUsize = AtomicUsize::new(0);
+
+    unsafe {
+      let hook = RawDetour::with_exit_hook(add as *const (), |context| {
+        RETURNS.fetch_add(1, Ordering::SeqCst);
+        let value = context.return_value() as i32;
+        context.set_return_value((value * 10) as u64);
+      })?;
+
+      assert_eq!(add(10, 5), 15);
+      hook.enable()?;
+      {
+        assert_eq!(add(10, 5), 150);
+        assert_eq!(RETURNS.load(Ordering::SeqCst), 1);
+
+        // The trampoline bypasses the exit hook
+        let trampoline: FnAdd = mem::transmute(hook.trampoline());
+        assert_eq!(trampoline(10, 5), 15);
+        assert_eq!(RETURNS.load(Ordering::SeqCst), 1);
+      }
+      hook.disable()?;
+      assert_eq!(add(10, 5), 15);
+    }
+    Ok(())
+  }
+
+  #[test]
+  fn recursion() -> Result<()> {
+    static RETURNS: AtomicUsize = AtomicUsize::new(0);
+
+    unsafe {
+      let hook = RawDetour::with_exit_hook(recurse as *const (), |_| {
+        RETURNS.fetch_add(1, Ordering::SeqCst);
+      })?;
+
+      hook.enable()?;
+      assert_eq!(recurse(10), 10);
+      hook.disable()?;
+    }
+
+    assert_eq!(RETURNS.load(Ordering::SeqCst), 11);
+    Ok(())
+  }
+}
+
+#[cfg(any(all(target_arch = "x86_64", unix), target_arch = "aarch64"))]
+mod context {
+  use super::*;
+  use detour::{ContextDetour, ContextPanicPolicy};
+  use std::sync::atomic::{AtomicUsize, Ordering};
+
+  #[inline(never)]
+  extern "C" fn sum(
+    a: u64,
+    b: u64,
+    c: u64,
+    d: u64,
+    e: u64,
+    f: u64,
+    g: u64,
+    h: u64,
+    i: u64,
+    j: u64,
+  ) -> u64 {
+    unsafe { std::ptr::read_volatile(&a) + b + c + d + e + f + g + h + i + j }
+  }
+
+  #[inline(never)]
+  extern "C" fn scale(value: f64, factor: f64) -> f64 {
+    unsafe { std::ptr::read_volatile(&value) * factor }
+  }
+
+  #[test]
+  fn arguments() -> Result<()> {
+    unsafe {
+      let hook = ContextDetour::new(sum as *const (), |context| {
+        // Includes arguments passed on the stack on both architectures
+        for index in 0..10 {
+          let value = context.arg(index);
+          context.set_arg(index, value * 10);
+        }
+      })?;
+
+      assert_eq!(sum(1, 2, 3, 4, 5, 6, 7, 8, 9, 10), 55);
+      hook.enable()?;
+      assert_eq!(sum(1, 2, 3, 4, 5, 6, 7, 8, 9, 10), 550);
+
+      // The trampoline bypasses the callback
+      let trampoline: extern "C" fn(u64, u64, u64, u64, u64, u64, u64, u64, u64, u64) -> u64 =
+        mem::transmute(hook.trampoline());
+      assert_eq!(trampoline(1, 2, 3, 4, 5, 6, 7, 8, 9, 10), 55);
+
+      hook.disable()?;
+      assert_eq!(sum(1, 2, 3, 4, 5, 6, 7, 8, 9, 10), 55);
+    }
+    Ok(())
+  }
+
+  #[test]
+  fn skip() -> Result<()> {
+    unsafe {
+      let hook = ContextDetour::new(scale as *const (), |context| {
+        if context.float_arg(1) == 0.0 {
+          context.set_float_return_value(-1.0);
+          context.skip();
+        } else {
+          let value = context.float_arg(0);
+          context.set_float_arg(0, value + 1.0);
+        }
+      })?;
+
+      hook.enable()?;
+      assert_eq!(scale(2.0, 3.0), 9.0);
+      assert_eq!(scale(2.0, 0.0), -1.0);
+    }
+    Ok(())
+  }
+
+  #[test]
+  fn call_original() -> Result<()> {
+    static CALLS: AtomicUsize = AtomicUsize::new(0);
+
+    unsafe {
+      let hook = ContextDetour::new(sum as *const (), |context| {
+        CALLS.fetch_add(1, Ordering::SeqCst);
+        context.set_arg(9, 100);
+        context.call_original();
+
+        let value = context.return_value();
+        context.set_return_value(value * 2);
+      })?;
+
+      hook.enable()?;
+      assert_eq!(sum(1, 2, 3, 4, 5, 6, 7, 8, 9, 10), 290);
+      assert_eq!(CALLS.load(Ordering::SeqCst), 1);
+    }
+    Ok(())
+  }
+
+  #[test]
+  fn panic_policy() -> Result<()> {
+    #[inline(never)]
+    extern "C" fn halve(value: u64) -> u64 {
+      unsafe { std::ptr::read_volatile(&value) / 2 }
+    }
+
+    unsafe {
+      let hook = ContextDetour::new(halve as *const (), |context| {
+        // The modified argument is discarded once the callback panics
+        context.set_arg(0, 0);
+        panic!("unexpected call");
+      })?;
+
+      hook.enable()?;
+      hook.set_panic_policy(ContextPanicPolicy::CallOriginal);
+      assert_eq!(halve(10), 5);
+
+      hook.set_panic_policy(ContextPanicPolicy::Return(42));
+      assert_eq!(halve(10), 42);
+    }
+    Ok(())
+  }
+}
+
+#[cfg(target_os = "linux")]
+mod pool {
+  use super::*;
+  use detour::RawDetour;
+
+  #[inline(never)]
+  extern "C" fn constant<const N: i32>() -> i32 {
+    N
+  }
+
+  #[inline(never)]
+  extern "C" fn negative() -> i32 {
+    unsafe { -std::ptr::read_volatile(&1) }
+  }
+
+  macro_rules! constants {
+    ($($row:literal)*) => {
+      [$(
+        constant::<{ $row * 16 }>, constant::<{ $row * 16 + 1 }>,
+        constant::<{ $row * 16 + 2 }>, constant::<{ $row * 16 + 3 }>,
+        constant::<{ $row * 16 + 4 }>, constant::<{ $row * 16 + 5 }>,
+        constant::<{ $row * 16 + 6 }>, constant::<{ $row * 16 + 7 }>,
+        constant::<{ $row * 16 + 8 }>, constant::<{ $row * 16 + 9 }>,
+        constant::<{ $row * 16 + 10 }>, constant::<{ $row * 16 + 11 }>,
+        constant::<{ $row * 16 + 12 }>, constant::<{ $row * 16 + 13 }>,
+        constant::<{ $row * 16 + 14 }>, constant::<{ $row * 16 + 15 }>
+      ),*]
+    };
+  }
+
+  /// Returns the start of the pool mapping containing an address.
+  fn pool_mapping(address: *const ()) -> usize {
+    std::fs::read_to_string("/proc/self/maps")
+      .unwrap()
+      .lines()
+      .filter(|line| line.contains("memfd:detour"))
+      .filter_map(|line| {
+        let (start, end) = line.split(' ').next()?.split_once('-')?;
+        let start = usize::from_str_radix(start, 16).ok()?;
+        let end = usize::from_str_radix(end, 16).ok()?;
+        Some(start..end)
+      })
+      .find(|range| range.contains(&(address as usize)))
+      .expect("pool mapping")
+      .start
+  }
+
+  #[test]
+  fn packs_many_hooks() -> Result<()> {
+    let targets: [extern "C" fn() -> i32; 256] = constants!(0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15);
+
+    let hooks = targets
+      .iter()
+      .map(|target| unsafe { RawDetour::new(*target as *const (), negative as *const ()) })
+      .collect::<Result<Vec<_>>>()?;
+
+    for (index, (target, hook)) in targets.iter().zip(&hooks).enumerate() {
+      unsafe { hook.enable()? };
+      assert_eq!(target(), -1);
+
+      let trampoline: extern "C" fn() -> i32 = unsafe { mem::transmute(hook.trampoline()) };
+      assert_eq!(trampoline(), index as i32);
+    }
+
+    // The trampolines are packed into a few pools, instead of a mapping each;
+    // other tests' pools are not counted, since they may run in parallel
+    let pools = hooks
+      .iter()
+      .map(|hook| pool_mapping(hook.trampoline()))
+      .collect::<std::collections::HashSet<_>>();
+    assert!(pools.len() <= 2);
+
+    mem::drop(hooks);
+    assert_eq!(targets[42](), 42);
+    Ok(())
+  }
+}
+
+#[cfg(all(target_os = "linux", any(target_arch = "x86", target_arch = "x86_64")))]
+mod watch {
+  use super::*;
+  use detour::{AccessKind, Error, WatchDetour};
+  use std::ptr;
+  use std::sync::atomic::{AtomicUsize, Ordering};
+
+  #[repr(align(4096))]
+  struct Page([u8; 4096]);
+
+  static mut PAGE: Page = Page([0; 4096]);
+
+  // The callback runs within a signal handler, so accesses are recorded
+  // without allocating
+  static READS: AtomicUsize = AtomicUsize::new(0);
+  static WRITES: AtomicUsize = AtomicUsize::new(0);
+  static ADDRESS: AtomicUsize = AtomicUsize::new(0);
+  static SIZE: AtomicUsize = AtomicUsize::new(0);
+
+  #[test]
+  fn reports_accesses() -> Result<()> {
+    unsafe {
+      let page = ptr::addr_of_mut!(PAGE.0) as *mut u8;
+      let hook = WatchDetour::new(page.add(64) as *const (), 16, |access| {
+        match access.kind() {
+          AccessKind::Read => READS.fetch_add(1, Ordering::SeqCst),
+          AccessKind::Write => WRITES.fetch_add(1, Ordering::SeqCst),
+        };
+        ADDRESS.store(access.address() as usize, Ordering::SeqCst);
+        SIZE.store(access.size().unwrap_or(0), Ordering::SeqCst);
+      })?;
+
+      // Pages cannot be watched twice
+      let result = WatchDetour::new(page as *const (), 1, |_| ());
+      assert!(matches!(result, Err(Error::AlreadyInitialized)));
+
+      hook.enable()?;
+      assert!(hook.is_enabled());
+
+      ptr::write_volatile(page.add(72) as *mut u64, 0x1122_3344_5566_7788);
+      assert_eq!(WRITES.load(Ordering::SeqCst), 1);
+      assert_eq!(ADDRESS.load(Ordering::SeqCst), page as usize + 72);
+      assert_eq!(SIZE.load(Ordering::SeqCst), 8);
+
+      assert_eq!(ptr::read_volatile(page.add(74) as *const u16), 0x5566);
+      assert_eq!(READS.load(Ordering::SeqCst), 1);
+      assert_eq!(ADDRESS.load(Ordering::SeqCst), page as usize + 74);
+      assert_eq!(SIZE.load(Ordering::SeqCst), 2);
+
+      // Accesses to the same page, outside of the range, are not reported
+      ptr::write_volatile(page.add(128), 1);
+      assert_eq!(ptr::read_volatile(page.add(128)), 1);
+      assert_eq!(READS.load(Ordering::SeqCst), 1);
+      assert_eq!(WRITES.load(Ordering::SeqCst), 1);
+
+      hook.disable()?;
+      ptr::write_volatile(page.add(64), 1);
+      assert_eq!(WRITES.load(Ordering::SeqCst), 1);
+    }
+    Ok(())
+  }
 }